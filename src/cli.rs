@@ -0,0 +1,264 @@
+//! Format resolved args back into the command line, so setups driven by
+//! SIP003 or optsfile can be reproduced standalone.
+
+use std::fmt::Display;
+
+use clap::ValueEnum;
+
+use crate::{
+    logging::{facility_name, LogTarget},
+    Args, Commands, Opts,
+};
+
+const MASK: &str = "***";
+
+/// Command line words, values which equal the default are left out.
+struct CliWords {
+    words: Vec<String>,
+    mask_secrets: bool,
+}
+
+impl CliWords {
+    fn word(&mut self, word: impl Into<String>) {
+        self.words.push(word.into());
+    }
+
+    fn flag(&mut self, name: &str, on: bool) {
+        if on {
+            self.word(format!("--{name}"));
+        }
+    }
+
+    fn opt(&mut self, name: &str, value: Option<impl Display>) {
+        if let Some(value) = value {
+            self.word(format!("--{name}"));
+            self.word(value.to_string());
+        }
+    }
+
+    fn secret(&mut self, name: &str, value: Option<&str>) {
+        let mask_secrets = self.mask_secrets;
+        self.opt(name, value.map(|v| if mask_secrets { MASK } else { v }));
+    }
+
+    fn value_enum<T: ValueEnum>(&mut self, name: &str, value: Option<&T>) {
+        let value = value.and_then(|v| v.to_possible_value());
+        self.opt(name, value.as_ref().map(|v| v.get_name()));
+    }
+}
+
+/// Some(value) if it is not the default.
+fn non_default<T: Default + PartialEq>(value: &T) -> Option<&T> {
+    (*value != T::default()).then_some(value)
+}
+
+impl Opts {
+    fn push_cli_words(&self, w: &mut CliWords) {
+        w.opt("threads", self.threads);
+        w.flag("cpu-affinity", self.cpu_affinity);
+        w.opt("uring-entries", self.uring_entries);
+        w.opt("user", self.user.as_ref());
+        w.opt("group", self.group.as_ref());
+        w.opt("netns", self.netns.as_ref().map(|p| p.display()));
+        w.flag("freeze-dns", self.freeze_dns);
+        w.flag("freeze-dns-lenient", self.freeze_dns_lenient);
+        w.flag("disable-nodelay", self.disable_nodelay);
+        w.opt("user-timeout", non_default(&self.user_timeout));
+        w.opt("buffer-size", self.buffer_size);
+        w.opt("recv-buffer-size", self.recv_buffer_size);
+        w.opt("send-buffer-size", self.send_buffer_size);
+        w.flag("v3", self.v3);
+        w.opt("handshake-source-cmd", self.handshake_source_cmd.as_ref());
+        w.opt("handshake-source-interval", self.handshake_source_interval);
+        w.value_enum("close-notify", non_default(&self.close_notify));
+        w.flag("random-handshake", self.random_handshake);
+        w.flag("handshake-passthrough", self.handshake_passthrough);
+        w.flag("require-handshake", self.require_handshake);
+        let ports: Vec<_> = self
+            .allowed_handshake_ports
+            .iter()
+            .map(u16::to_string)
+            .collect();
+        let ports = ports.join(",");
+        w.opt(
+            "allowed-handshake-ports",
+            (ports != crate::DEFAULT_ALLOWED_HANDSHAKE_PORTS).then_some(ports),
+        );
+        w.opt("connect-retries", non_default(&self.connect_retries));
+        w.opt("connect-timeout", self.connect_timeout);
+        w.opt("handshake-connect-timeout", self.handshake_connect_timeout);
+        w.opt("handshake-timeout", self.handshake_timeout);
+        w.value_enum("resolve-preference", non_default(&self.resolve_preference));
+        w.opt("max-bytes-per-conn", non_default(&self.max_bytes_per_conn));
+        w.value_enum("max-bytes-mode", non_default(&self.max_bytes_mode));
+        w.opt("close-jitter-ms", self.close_jitter_ms.map(|j| j.to_arg()));
+        w.opt("read-timeout", self.read_timeout);
+        w.opt("write-timeout", self.write_timeout);
+        w.opt("backend-allowlist", self.backend_allowlist.as_ref());
+        w.value_enum(
+            "proxy-protocol-version",
+            self.proxy_protocol_version.as_ref(),
+        );
+        w.secret("salt", self.salt.as_deref());
+        w.opt(
+            "password-file",
+            self.password_file.as_ref().map(|p| p.display()),
+        );
+        w.opt("rotation-grace", self.rotation_grace);
+        w.opt("replay-window", self.replay_window);
+        w.flag("tcp-info", self.tcp_info);
+        w.flag("debug-handshake", self.debug_handshake);
+        w.flag("access-log", self.access_log);
+        w.opt("cipher-suites", self.cipher_suites.as_ref());
+        w.opt("ca-file", self.ca_file.as_ref().map(|p| p.display()));
+        w.opt("ca-dir", self.ca_dir.as_ref().map(|p| p.display()));
+        w.flag("insecure-skip-verify", self.insecure_skip_verify);
+        w.flag("no-session-cache", self.no_session_cache);
+        w.opt("decoy-handshakes", non_default(&self.decoy_handshakes));
+        w.opt("max-tunnels", self.max_tunnels);
+        w.value_enum("max-tunnels-mode", non_default(&self.max_tunnels_mode));
+        w.opt("max-handshake-conns", self.max_handshake_conns);
+        w.flag("backend-down-fallback", self.backend_down_fallback);
+        w.opt("max-pending-handshakes", self.max_pending_handshakes);
+        w.opt("probe-delay-ms", self.probe_delay_ms.map(|j| j.to_arg()));
+        w.opt("metrics-listen", self.metrics_listen.as_ref());
+        w.opt("otlp-endpoint", self.otlp_endpoint.as_ref());
+        w.opt("otlp-interval", self.otlp_interval);
+        w.opt("otlp-instance", self.otlp_instance.as_ref());
+        w.opt("stats-interval", non_default(&self.stats_interval));
+        w.opt(
+            "latency-buckets",
+            self.latency_buckets.as_ref().map(|buckets| {
+                let buckets: Vec<_> = buckets.iter().map(f64::to_string).collect();
+                buckets.join(",")
+            }),
+        );
+        if self.log_target != LogTarget::Stderr {
+            w.opt("log-target", Some(&self.log_target));
+        }
+        w.opt("log-facility", self.log_facility.and_then(facility_name));
+        w.opt("log-tag", self.log_tag.as_ref());
+        w.opt("log-rate-limit", self.log_rate_limit);
+    }
+}
+
+impl Args {
+    /// The shell quoted command line which parses into the same args.
+    /// Password and salt are replaced with *** if mask_secrets.
+    pub fn to_cli_string(&self, mask_secrets: bool) -> String {
+        let mut w = CliWords {
+            words: vec![env!("CARGO_PKG_NAME").to_string()],
+            mask_secrets,
+        };
+        self.opts.push_cli_words(&mut w);
+        let Some(cmd) = &self.cmd else {
+            return shell_words::join(w.words);
+        };
+        match cmd {
+            Commands::Client {
+                listen,
+                server_addr,
+                tls_names,
+                password,
+                alpn,
+            } => {
+                w.word("client");
+                w.opt("listen", Some(listen));
+                w.opt("server", Some(server_addr));
+                w.opt("sni", Some(tls_names.to_arg()));
+                w.secret("password", password.as_deref());
+                w.opt("alpn", alpn.as_ref().map(|alpn| alpn.join(";")));
+            }
+            Commands::Server {
+                listen,
+                server_addr,
+                tls_addr,
+                password,
+            } => {
+                w.word("server");
+                w.opt("listen", Some(listen));
+                w.opt("server", Some(server_addr));
+                w.opt("tls", Some(tls_addr.to_arg()));
+                w.secret("password", password.as_deref());
+            }
+            Commands::Probe {
+                server_addr,
+                tls_names,
+                password,
+            } => {
+                w.word("probe");
+                w.opt("server", Some(server_addr));
+                w.opt("sni", Some(tls_names.to_arg()));
+                w.secret("password", password.as_deref());
+            }
+            Commands::Completions { shell } => {
+                w.word("completions");
+                w.word(shell.to_string());
+            }
+        }
+        shell_words::join(w.words)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn parse(cli: &str) -> Args {
+        Args::try_parse_from(shell_words::split(cli).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let cli = "shadow-tls --threads 2 --v3 --close-notify suppress --connect-retries 3 \
+            --allowed-handshake-ports 443,8443 \
+            --max-bytes-mode each --close-jitter-ms 20-200 --backend-allowlist 10.0.0.0/8,::1 \
+            --proxy-protocol-version v2 --salt 'my salt' --cipher-suites TLS_AES_128_GCM_SHA256 \
+            --max-tunnels 8 --max-tunnels-mode reject --probe-delay-ms 100 \
+            --otlp-endpoint http://127.0.0.1:4318 --latency-buckets 0.01,0.1,1 \
+            --log-target syslog --log-facility local3 --log-rate-limit 60 \
+            server --listen 0.0.0.0:443 --server 127.0.0.1:8080 \
+            --tls 'cloudflare.com:1.1.1.1:443;captive.apple.com;cloud.tencent.com' \
+            --password \"it's secret\"";
+        let args = parse(cli);
+        let cli = args.to_cli_string(false);
+        let parsed = parse(&cli);
+        assert_eq!(parsed.to_cli_string(false), cli);
+        assert_eq!(format!("{:?}", parsed.opts), format!("{:?}", args.opts));
+        let Some(Commands::Server { tls_addr, password, .. }) = parsed.cmd else {
+            panic!("not a server command");
+        };
+        let Some(Commands::Server { tls_addr: expected, .. }) = args.cmd else {
+            unreachable!();
+        };
+        assert_eq!(tls_addr, expected);
+        assert_eq!(password.as_deref(), Some("it's secret"));
+        assert!(cli.contains("--close-jitter-ms 20-200 "));
+        assert!(cli.contains("--log-facility local3 "));
+
+        let masked = parse(&cli).to_cli_string(true);
+        assert!(!masked.contains("secret"));
+        assert!(masked.contains("--password '***'"));
+
+        let args = parse("shadow-tls client --server 1.2.3.4:443 --sni 'a.com;b.com' --password p --alpn 'h2;http/1.1'");
+        assert_eq!(
+            args.to_cli_string(false),
+            "shadow-tls client --listen '[::1]:8080' --server 1.2.3.4:443 --sni 'a.com;b.com' --password p --alpn 'h2;http/1.1'"
+        );
+        // only port 443 is allowed by default, 0 allows any
+        assert_eq!(
+            crate::allowed_handshake_ports(args.opts.allowed_handshake_ports),
+            Some(vec![443])
+        );
+        let args = parse("shadow-tls --allowed-handshake-ports 0 server --listen 0.0.0.0:443 --server 127.0.0.1:8080 --tls a.com --password p");
+        assert_eq!(
+            crate::allowed_handshake_ports(args.opts.allowed_handshake_ports.clone()),
+            None
+        );
+        assert!(args
+            .to_cli_string(false)
+            .contains("--allowed-handshake-ports 0 "));
+    }
+}
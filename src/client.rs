@@ -1,7 +1,10 @@
 use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
     ptr::{copy, copy_nonoverlapping},
     rc::Rc,
-    sync::Arc,
+    sync::{atomic::Ordering, Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::bail;
@@ -13,25 +16,140 @@ use monoio::{
 };
 use monoio_rustls_fork_shadow_tls::TlsConnector;
 use rand::{prelude::Distribution, seq::SliceRandom, Rng};
-use rustls_fork_shadow_tls::{OwnedTrustAnchor, RootCertStore, ServerName};
+use rustls_fork_shadow_tls::{
+    client::{NoClientSessionStorage, ServerCertVerified, ServerCertVerifier},
+    Certificate, OwnedTrustAnchor, RootCertStore, ServerName, SupportedCipherSuite,
+    ALL_CIPHER_SUITES,
+};
 
 use crate::{
     helper_v2::{copy_with_application_data, copy_without_application_data, HashedReadStream},
-    util::{kdf, mod_tcp_conn, prelude::*, verified_relay, xor_slice, Hmac},
+    metrics::METRICS,
+    util::{
+        connect_with_retry, kdf, mod_tcp_conn, prelude::*, verified_relay, xor_slice, BufferSizes,
+        ByteLimit, ByteLimitMode, CloseWatch, ConnLimit, Hmac, JitterRange, JitterStream,
+        OpTimeout, PreferredAddr, ResolvePreference,
+    },
 };
 
 const FAKE_REQUEST_LENGTH_RANGE: (usize, usize) = (16, 64);
+/// Port of the cover site connected by decoy handshakes.
+const DECOY_PORT: u16 = 443;
+/// Time limit of a whole decoy handshake.
+const DECOY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(target_os = "linux")]
+use crate::util::TcpInfoProbe;
 
 /// ShadowTlsClient.
 #[derive(Clone)]
 pub struct ShadowTlsClient<LA, TA> {
     listen_addr: Arc<LA>,
     target_addr: Arc<TA>,
-    tls_connector: TlsConnector,
+    tls_config: TlsConfigHandle,
+    tls_ext_config: Arc<TlsExtConfig>,
     tls_names: Arc<TlsNames>,
     password: Arc<String>,
-    nodelay: bool,
-    v3: bool,
+    tunnel_limit: Option<Arc<ConnLimit>>,
+    decoy_port: u16,
+    opts: ClientOpts,
+}
+
+/// Options of ShadowTlsClient.
+#[derive(Clone, Debug, Default)]
+pub struct ClientOpts {
+    pub nodelay: bool,
+    pub v3: bool,
+    /// Retry times when connecting the server failed.
+    pub connect_retries: u8,
+    /// Time limit for connecting the server, including retries.
+    pub connect_timeout: Option<Duration>,
+    /// Time limit for every single read in data relay.
+    pub read_timeout: Option<Duration>,
+    /// Time limit for every single write in data relay.
+    pub write_timeout: Option<Duration>,
+    /// TCP_USER_TIMEOUT of local and server connections if set(Linux only).
+    pub user_timeout: Option<Duration>,
+    /// Socket and relay buffer sizes of each direction.
+    pub buffers: BufferSizes,
+    /// Address family to try first when connecting the server.
+    pub resolve_preference: ResolvePreference,
+    /// Log TCP_INFO of server connections when closing(Linux only).
+    pub tcp_info: bool,
+    /// Close the connection after relaying this many bytes, 0 means unlimited.
+    pub max_bytes_per_conn: u64,
+    pub max_bytes_mode: ByteLimitMode,
+    /// Random delay before shutting down the server connection.
+    pub close_jitter: Option<JitterRange>,
+    /// Log parameters negotiated with the handshake server.
+    pub debug_handshake: bool,
+    /// Log every established tunnel with the TLS version and cipher suite
+    /// negotiated with the handshake server.
+    pub access_log: bool,
+    /// CA certificates(PEM or DER) trusted instead of the builtin roots.
+    pub ca_file: Option<PathBuf>,
+    /// Directory of CA certificate files trusted instead of the builtin roots.
+    pub ca_dir: Option<PathBuf>,
+    /// Accept any handshake server certificate, for testing only.
+    pub insecure_skip_verify: bool,
+    /// Do not cache TLS sessions, so every connection does a full handshake
+    /// including certificate verification. It costs a certificate exchange
+    /// and verification per connection.
+    pub no_session_cache: bool,
+    /// Experimental: open this many real handshakes to the cover site in
+    /// background with every authenticated connection. V3 only, V2 can not
+    /// tell whether a connection is authenticated, so it never opens them.
+    pub decoy_handshakes: u8,
+    /// If set, concurrent tunnels are limited to it.
+    pub max_tunnels: Option<usize>,
+    pub max_tunnels_mode: TunnelLimitMode,
+}
+
+/// How local connections beyond max_tunnels are handled.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TunnelLimitMode {
+    /// Wait in the accept backlog until a tunnel finishes
+    #[default]
+    Wait,
+    /// Accept and close them at once
+    Reject,
+}
+
+impl std::fmt::Display for TunnelLimitMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Wait => write!(f, "wait"),
+            Self::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+/// Retry interval of accepting when tunnels are too many.
+const TUNNEL_WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Failure of probing the server.
+#[derive(Debug)]
+pub enum ProbeError {
+    /// Connecting or handshaking failed.
+    Connect(anyhow::Error),
+    /// Handshake finished but the server is not authorized.
+    Auth(&'static str),
+    /// TLS1.3 is negotiated but the server does not respond with protocol v3,
+    /// the server may be v2 or the password mismatches.
+    NotV3,
+}
+
+impl std::fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connect(e) => write!(f, "connection failed: {e}"),
+            Self::Auth(reason) => write!(f, "authentication failed: {reason}"),
+            Self::NotV3 => write!(
+                f,
+                "authentication failed: server does not respond with protocol v3(server may be v2 or password mismatch)"
+            ),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -41,6 +159,20 @@ impl TlsNames {
     pub fn random_choose(&self) -> &ServerName {
         self.0.choose(&mut rand::thread_rng()).unwrap()
     }
+
+    /// Format as the command line argument it is parsed from.
+    pub fn to_arg(&self) -> String {
+        let names: Vec<_> = self
+            .0
+            .iter()
+            .filter_map(|name| match name {
+                ServerName::DnsName(name) => Some(name.as_ref().to_string()),
+                ServerName::IpAddress(ip) => Some(ip.to_string()),
+                _ => None,
+            })
+            .collect();
+        names.join(";")
+    }
 }
 
 impl TryFrom<&str> for TlsNames {
@@ -69,15 +201,44 @@ pub fn parse_client_names(addrs: &str) -> anyhow::Result<TlsNames> {
     TlsNames::try_from(addrs)
 }
 
+/// Shared TLS config which can be replaced at runtime.
+/// Every connection takes a snapshot, so replacing only affects new connections.
+#[derive(Clone)]
+struct TlsConfigHandle(Arc<RwLock<Arc<rustls_fork_shadow_tls::ClientConfig>>>);
+
+impl TlsConfigHandle {
+    fn new(config: rustls_fork_shadow_tls::ClientConfig) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    fn load(&self) -> Arc<rustls_fork_shadow_tls::ClientConfig> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn store(&self, config: rustls_fork_shadow_tls::ClientConfig) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct TlsExtConfig {
     alpn: Option<Vec<Vec<u8>>>,
+    cipher_suites: Option<CipherSuites>,
 }
 
 impl TlsExtConfig {
     #[allow(unused)]
     pub fn new(alpn: Option<Vec<Vec<u8>>>) -> TlsExtConfig {
-        TlsExtConfig { alpn }
+        TlsExtConfig {
+            alpn,
+            cipher_suites: None,
+        }
+    }
+
+    /// Offer these cipher suites in order instead of the default list.
+    pub fn with_cipher_suites(mut self, cipher_suites: Option<CipherSuites>) -> Self {
+        self.cipher_suites = cipher_suites;
+        self
     }
 }
 
@@ -85,7 +246,68 @@ impl From<Option<Vec<String>>> for TlsExtConfig {
     fn from(maybe_alpns: Option<Vec<String>>) -> Self {
         Self {
             alpn: maybe_alpns.map(|alpns| alpns.into_iter().map(Into::into).collect()),
+            cipher_suites: None,
+        }
+    }
+}
+
+/// Cipher suites offered in ClientHello, in order.
+#[derive(Clone, Debug)]
+pub struct CipherSuites(Vec<SupportedCipherSuite>);
+
+impl TryFrom<&str> for CipherSuites {
+    type Error = anyhow::Error;
+
+    /// Parse comma separated IANA names(like TLS_AES_128_GCM_SHA256) or hex
+    /// codepoints(like 0x1301).
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut suites = Vec::new();
+        for item in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let code = match item.strip_prefix("0x").or_else(|| item.strip_prefix("0X")) {
+                Some(hex) => Some(
+                    u16::from_str_radix(hex, 16)
+                        .map_err(|_| anyhow::anyhow!("invalid cipher suite codepoint {item}"))?,
+                ),
+                None => None,
+            };
+            let suite = ALL_CIPHER_SUITES
+                .iter()
+                .find(|suite| match code {
+                    Some(code) => suite.suite().get_u16() == code,
+                    None => cipher_suite_name(suite).eq_ignore_ascii_case(item),
+                })
+                .ok_or_else(|| anyhow::anyhow!("unknown or unsupported cipher suite {item}"))?;
+            if suites.contains(suite) {
+                anyhow::bail!("duplicate cipher suite {item}");
+            }
+            suites.push(*suite);
+        }
+        if suites.is_empty() {
+            anyhow::bail!("empty cipher suite list");
+        }
+        Ok(Self(suites))
+    }
+}
+
+pub fn parse_cipher_suites(arg: &str) -> anyhow::Result<CipherSuites> {
+    CipherSuites::try_from(arg)
+}
+
+/// IANA name of the cipher suite.
+fn cipher_suite_name(suite: &SupportedCipherSuite) -> String {
+    // rustls names TLS 1.3 suites with a TLS13_ prefix
+    format!("{:?}", suite.suite()).replacen("TLS13_", "TLS_", 1)
+}
+
+impl std::fmt::Display for CipherSuites {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, suite) in self.0.iter().enumerate() {
+            if idx != 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", cipher_suite_name(suite))?;
         }
+        Ok(())
     }
 }
 
@@ -103,6 +325,9 @@ impl std::fmt::Display for TlsExtConfig {
                 write!(f, "ALPN(None)")?;
             }
         }
+        if let Some(cipher_suites) = self.cipher_suites.as_ref() {
+            write!(f, " Cipher suites({cipher_suites})")?;
+        }
         Ok(())
     }
 }
@@ -115,60 +340,95 @@ impl<LA, TA> ShadowTlsClient<LA, TA> {
         tls_names: TlsNames,
         tls_ext_config: TlsExtConfig,
         password: String,
-        nodelay: bool,
-        v3: bool,
+        opts: ClientOpts,
     ) -> anyhow::Result<Self> {
-        let mut root_store = RootCertStore::empty();
-        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-            OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject,
-                ta.spki,
-                ta.name_constraints,
-            )
-        }));
-        // TLS 1.2 and TLS 1.3 is enabled.
-        let mut tls_config = rustls_fork_shadow_tls::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-
-        // Set tls config
-        if let Some(alpn) = tls_ext_config.alpn {
-            tls_config.alpn_protocols = alpn;
-        }
-
-        let tls_connector = TlsConnector::from(tls_config);
+        let tls_config = verifying_tls_config(&tls_ext_config, &opts)?;
 
         Ok(Self {
             listen_addr: Arc::new(listen_addr),
             target_addr: Arc::new(target_addr),
-            tls_connector,
+            tls_config: TlsConfigHandle::new(tls_config),
+            tls_ext_config: Arc::new(tls_ext_config),
             tls_names: Arc::new(tls_names),
             password: Arc::new(password),
-            nodelay,
-            v3,
+            tunnel_limit: opts.max_tunnels.map(ConnLimit::new),
+            decoy_port: DECOY_PORT,
+            opts,
         })
     }
 
-    /// Serve a raw connection.
-    pub async fn serve(self) -> anyhow::Result<()>
+    /// Load CA certificates again and use the new TLS config for new
+    /// connections. The current one is kept if loading fails.
+    pub fn reload_tls_config(&self) -> anyhow::Result<()> {
+        let tls_config = verifying_tls_config(&self.tls_ext_config, &self.opts)?;
+        self.tls_config.store(tls_config);
+        Ok(())
+    }
+
+    /// Bind the listen address.
+    pub fn bind(&self) -> anyhow::Result<TcpListener>
     where
-        LA: std::net::ToSocketAddrs + 'static,
-        TA: std::net::ToSocketAddrs + 'static,
+        LA: std::net::ToSocketAddrs,
     {
         let listener = TcpListener::bind(self.listen_addr.as_ref())
             .map_err(|e| anyhow::anyhow!("bind failed, check if the port is used: {e}"))?;
+        #[cfg(unix)]
+        if let Err(e) = self
+            .opts
+            .buffers
+            .apply(std::os::unix::io::AsRawFd::as_raw_fd(&listener))
+        {
+            tracing::warn!("unable to set socket buffer sizes of listener: {e}");
+        }
+        Ok(listener)
+    }
+
+    /// Serve raw connections from the listener.
+    pub async fn serve(self, listener: TcpListener) -> anyhow::Result<()>
+    where
+        LA: 'static,
+        TA: std::net::ToSocketAddrs + 'static,
+    {
         let shared = Rc::new(self);
         loop {
+            // connections wait in the backlog if tunnels are too many
+            let slot = match (&shared.tunnel_limit, shared.opts.max_tunnels_mode) {
+                (Some(limit), TunnelLimitMode::Wait) => loop {
+                    match limit.try_acquire() {
+                        Some(slot) => break Some(slot),
+                        None => monoio::time::sleep(TUNNEL_WAIT_INTERVAL).await,
+                    }
+                },
+                _ => None,
+            };
             match listener.accept().await {
                 Ok((mut conn, addr)) => {
                     tracing::info!("Accepted a connection from {addr}");
+                    let slot = match (slot, &shared.tunnel_limit) {
+                        (None, Some(limit)) => match limit.try_acquire() {
+                            Some(slot) => Some(slot),
+                            None => {
+                                tracing::warn!("too many tunnels, connection from {addr} rejected");
+                                METRICS.tunnel_rejected.fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
+                        },
+                        (slot, _) => slot,
+                    };
                     let client = shared.clone();
-                    mod_tcp_conn(&mut conn, true, shared.nodelay);
+                    let active = METRICS.accept_conn();
+                    mod_tcp_conn(
+                        &mut conn,
+                        true,
+                        shared.opts.nodelay,
+                        shared.opts.user_timeout,
+                        shared.opts.buffers,
+                    );
                     monoio::spawn(async move {
-                        let _ = match client.v3 {
-                            false => client.relay_v2(conn).await,
-                            true => client.relay_v3(conn).await,
+                        let (_active, _slot) = (active, slot);
+                        let _ = match client.opts.v3 {
+                            false => client.relay_v2(conn, addr).await,
+                            true => client.relay_v3(conn, addr).await,
                         };
                         tracing::info!("Relay for {addr} finished");
                     });
@@ -181,76 +441,233 @@ impl<LA, TA> ShadowTlsClient<LA, TA> {
     }
 
     /// Main relay for V2 protocol.
-    async fn relay_v2(&self, mut in_stream: TcpStream) -> anyhow::Result<()>
+    async fn relay_v2(&self, in_stream: TcpStream, addr: SocketAddr) -> anyhow::Result<()>
     where
         TA: std::net::ToSocketAddrs,
     {
-        let (mut out_stream, hash, session) = self.connect_v2().await?;
+        let watch = CloseWatch::new(&in_stream);
+        let Some(connected) = watch.run(self.connect_v2(Some(&watch))).await else {
+            tracing::info!("{addr} closed during tunnel setup, server connection abandoned");
+            return Ok(());
+        };
+        let (out_stream, hash, session) = connected?;
+        if self.opts.access_log {
+            log_access(addr, &session);
+        }
+        let _tcp_info = self.tcp_info_probe(&out_stream, addr);
         let mut hash_8b = [0; 8];
         unsafe { std::ptr::copy_nonoverlapping(hash.as_ptr(), hash_8b.as_mut_ptr(), 8) };
+        let op_timeout = OpTimeout::new(self.opts.read_timeout, self.opts.write_timeout);
+        let byte_limit = ByteLimit::new(self.opts.max_bytes_per_conn, self.opts.max_bytes_mode);
+        let (mut in_stream, mut out_stream) = (
+            op_timeout.wrap(byte_limit.wrap(in_stream)),
+            op_timeout.wrap(JitterStream::new(out_stream, self.opts.close_jitter)),
+        );
         let (out_r, mut out_w) = out_stream.split();
         let (mut in_r, mut in_w) = in_stream.split();
         let mut session_filtered_out_r = crate::helper_v2::SessionFilterStream::new(session, out_r);
-        let (a, b) = monoio::join!(
-            copy_without_application_data(&mut session_filtered_out_r, &mut in_w),
-            copy_with_application_data(&mut in_r, &mut out_w, Some(hash_8b))
-        );
-        let (_, _) = (a?, b?);
+        let relay = async {
+            monoio::join!(
+                copy_without_application_data(
+                    &mut session_filtered_out_r,
+                    &mut in_w,
+                    self.opts.buffers,
+                ),
+                copy_with_application_data(&mut in_r, &mut out_w, Some(hash_8b), self.opts.buffers,)
+            )
+        };
+        match byte_limit.guard(op_timeout.guard(relay)).await {
+            Some(Some((a, b))) => {
+                let (_, _) = (a?, b?);
+            }
+            Some(None) => bail!("data relay timeout"),
+            None => (),
+        }
         Ok(())
     }
 
     /// Main relay for V3 protocol.
-    async fn relay_v3(&self, in_stream: TcpStream) -> anyhow::Result<()>
+    async fn relay_v3(&self, in_stream: TcpStream, addr: SocketAddr) -> anyhow::Result<()>
     where
         TA: std::net::ToSocketAddrs,
     {
-        let mut stream = TcpStream::connect(self.target_addr.as_ref()).await?;
-        mod_tcp_conn(&mut stream, true, self.nodelay);
-        tracing::debug!("tcp connected, start handshaking");
-
         // stage1: handshake with wrapper
-        let hamc_sr = Hmac::new(&self.password, (&[], &[]));
-        let stream = StreamWrapper::new(stream, &self.password);
-        let sni = self.tls_names.random_choose().clone();
-        let tls_stream = self
-            .tls_connector
-            .connect_with_session_id_generator(sni, stream, move |data| {
-                generate_session_id(&hamc_sr, data)
-            })
-            .await?;
-        tracing::debug!("handshake success");
-        let (stream, session) = tls_stream.into_parts();
-        let server_random = stream.authorized();
-        let stream = stream.into_inner();
+        let watch = CloseWatch::new(&in_stream);
+        let Some(connected) = watch.run(self.connect_v3(Some(&watch))).await else {
+            tracing::info!("{addr} closed during tunnel setup, server connection abandoned");
+            return Ok(());
+        };
+        let (stream, server_random, session) = connected?;
+        let _tcp_info = self.tcp_info_probe(&stream, addr);
 
         // stage2:
         match server_random {
             None => {
-                tracing::warn!("traffic hijacked or TLS1.3 is not supported");
+                let reason = unauthorized(&session);
+                tracing::warn!("{reason}");
                 let tls_stream =
                     monoio_rustls_fork_shadow_tls::ClientTlsStream::new(stream, session);
                 if let Err(e) = fake_request(tls_stream).await {
-                    bail!("traffic hijacked or TLS1.3 is not supported, fake request fail: {e}");
+                    bail!("{reason}, fake request fail: {e}");
                 }
-                bail!("traffic hijacked or TLS1.3 is not supported, but fake request success");
+                bail!("{reason}, but fake request success");
             }
             Some(sr) => {
+                if self.opts.access_log {
+                    log_access(addr, &session);
+                }
                 drop(session);
                 tracing::debug!("ServerRandom extracted: {sr:?}");
+                self.spawn_decoy_handshakes(self.decoy_port);
                 let hmac_sr_s = Hmac::new(&self.password, (&sr, b"S"));
                 let hmac_sr_c = Hmac::new(&self.password, (&sr, b"C"));
 
-                verified_relay(in_stream, stream, hmac_sr_c, hmac_sr_s).await;
+                let op_timeout = OpTimeout::new(self.opts.read_timeout, self.opts.write_timeout);
+                let byte_limit =
+                    ByteLimit::new(self.opts.max_bytes_per_conn, self.opts.max_bytes_mode);
+                let (in_stream, stream) = (
+                    op_timeout.wrap(byte_limit.wrap(in_stream)),
+                    op_timeout.wrap(JitterStream::new(stream, self.opts.close_jitter)),
+                );
+                byte_limit
+                    .guard(op_timeout.guard(verified_relay(
+                        in_stream,
+                        stream,
+                        hmac_sr_c,
+                        hmac_sr_s,
+                        self.opts.buffers,
+                    )))
+                    .await;
                 Ok(())
             }
         }
     }
 
+    /// Handshake with the server to check if the password is accepted, return
+    /// the time spent.
+    ///
+    /// V2 protocol can not tell it by handshake, so only the handshake is checked.
+    pub async fn probe(&self) -> Result<Duration, ProbeError>
+    where
+        TA: std::net::ToSocketAddrs,
+    {
+        let start = Instant::now();
+        if !self.opts.v3 {
+            self.connect_v2(None).await.map_err(ProbeError::Connect)?;
+            return Ok(start.elapsed());
+        }
+        let (_, server_random, session) =
+            self.connect_v3(None).await.map_err(ProbeError::Connect)?;
+        match server_random {
+            Some(_) => Ok(start.elapsed()),
+            None => Err(unauthorized(&session)),
+        }
+    }
+
+    /// Handshake with the cover site on port in background like a normal
+    /// client, their results are only logged.
+    fn spawn_decoy_handshakes(&self, port: u16) {
+        for _ in 0..self.opts.decoy_handshakes {
+            let sni = self.tls_names.random_choose().clone();
+            let host = match &sni {
+                ServerName::DnsName(name) => name.as_ref().to_string(),
+                ServerName::IpAddress(ip) => ip.to_string(),
+                _ => continue,
+            };
+            let connector = TlsConnector::from(self.tls_config.load());
+            monoio::spawn(async move {
+                let decoy = async {
+                    let stream = TcpStream::connect((host.as_str(), port)).await?;
+                    let mut tls_stream = connector.connect(sni, stream).await?;
+                    tls_stream.shutdown().await?;
+                    anyhow::Ok(())
+                };
+                match monoio::time::timeout(DECOY_TIMEOUT, decoy).await {
+                    Ok(Ok(_)) => tracing::debug!("decoy handshake with {host} finished"),
+                    Ok(Err(e)) => tracing::debug!("decoy handshake with {host} failed: {e}"),
+                    Err(_) => tracing::debug!("decoy handshake with {host} timeout"),
+                }
+            });
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn tcp_info_probe(&self, conn: &TcpStream, addr: SocketAddr) -> Option<TcpInfoProbe> {
+        self.opts
+            .tcp_info
+            .then(|| TcpInfoProbe::new(conn, format!("Server connection for {addr}")))
+            .flatten()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn tcp_info_probe(&self, _conn: &TcpStream, _addr: SocketAddr) -> Option<()> {
+        None
+    }
+
+    /// Connect remote with retries.
+    async fn connect(&self) -> std::io::Result<TcpStream>
+    where
+        TA: std::net::ToSocketAddrs,
+    {
+        connect_with_retry(
+            PreferredAddr(self.target_addr.as_ref(), self.opts.resolve_preference),
+            self.opts.connect_retries,
+            self.opts.connect_timeout,
+        )
+        .await
+    }
+
+    /// Connect remote and do handshaking with signed session id, the server
+    /// random is returned if the server is authorized.
+    ///
+    /// Only used by V3 protocol.
+    async fn connect_v3(
+        &self,
+        watch: Option<&CloseWatch<'_>>,
+    ) -> anyhow::Result<(
+        TcpStream,
+        Option<[u8; TLS_RANDOM_SIZE]>,
+        rustls_fork_shadow_tls::ClientConnection,
+    )>
+    where
+        TA: std::net::ToSocketAddrs,
+    {
+        let mut stream = self.connect().await?;
+        if let Some(watch) = watch {
+            watch.watch(&stream);
+        }
+        mod_tcp_conn(
+            &mut stream,
+            true,
+            self.opts.nodelay,
+            self.opts.user_timeout,
+            self.opts.buffers,
+        );
+        tracing::debug!("tcp connected, start handshaking");
+
+        let hamc_sr = Hmac::new(&self.password, (&[], &[]));
+        let stream = StreamWrapper::new(stream, &self.password);
+        let sni = self.tls_names.random_choose().clone();
+        let tls_stream = TlsConnector::from(self.tls_config.load())
+            .connect_with_session_id_generator(sni, stream, move |data| {
+                generate_session_id(&hamc_sr, data)
+            })
+            .await?;
+        tracing::debug!("handshake success");
+        let (stream, session) = tls_stream.into_parts();
+        if self.opts.debug_handshake {
+            log_handshake(&session);
+        }
+        let server_random = stream.authorized();
+        Ok((stream.into_inner(), server_random, session))
+    }
+
     /// Connect remote, do handshaking and calculate HMAC.
     ///
     /// Only used by V2 protocol.
     async fn connect_v2(
         &self,
+        watch: Option<&CloseWatch<'_>>,
     ) -> anyhow::Result<(
         TcpStream,
         [u8; 20],
@@ -259,13 +676,27 @@ impl<LA, TA> ShadowTlsClient<LA, TA> {
     where
         TA: std::net::ToSocketAddrs,
     {
-        let mut stream = TcpStream::connect(self.target_addr.as_ref()).await?;
-        mod_tcp_conn(&mut stream, true, self.nodelay);
+        let mut stream = self.connect().await?;
+        if let Some(watch) = watch {
+            watch.watch(&stream);
+        }
+        mod_tcp_conn(
+            &mut stream,
+            true,
+            self.opts.nodelay,
+            self.opts.user_timeout,
+            self.opts.buffers,
+        );
         tracing::debug!("tcp connected, start handshaking");
         let stream = HashedReadStream::new(stream, self.password.as_bytes())?;
         let sni = self.tls_names.random_choose().clone();
-        let tls_stream = self.tls_connector.connect(sni, stream).await?;
+        let tls_stream = TlsConnector::from(self.tls_config.load())
+            .connect(sni, stream)
+            .await?;
         let (io, session) = tls_stream.into_parts();
+        if self.opts.debug_handshake {
+            log_handshake(&session);
+        }
         let hash = io.hash();
         tracing::debug!("tls handshake finished, signed hmac: {:?}", hash);
         let stream = io.into_inner();
@@ -273,6 +704,171 @@ impl<LA, TA> ShadowTlsClient<LA, TA> {
     }
 }
 
+fn tls_config(
+    tls_ext_config: &TlsExtConfig,
+    root_store: RootCertStore,
+) -> anyhow::Result<rustls_fork_shadow_tls::ClientConfig> {
+    // TLS 1.2 and TLS 1.3 is enabled.
+    let builder = rustls_fork_shadow_tls::ClientConfig::builder();
+    let builder = match &tls_ext_config.cipher_suites {
+        Some(cipher_suites) => builder
+            .with_cipher_suites(&cipher_suites.0)
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .map_err(|e| anyhow::anyhow!("invalid cipher suites: {e}"))?,
+        None => builder.with_safe_defaults(),
+    };
+    let mut tls_config = builder
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    // Set tls config
+    if let Some(alpn) = &tls_ext_config.alpn {
+        tls_config.alpn_protocols = alpn.clone();
+    }
+    Ok(tls_config)
+}
+
+/// Client config verifying the handshake server certificate and caching
+/// sessions as opts specify.
+fn verifying_tls_config(
+    tls_ext_config: &TlsExtConfig,
+    opts: &ClientOpts,
+) -> anyhow::Result<rustls_fork_shadow_tls::ClientConfig> {
+    let root_store = root_store(opts.ca_file.as_deref(), opts.ca_dir.as_deref())?;
+    let mut tls_config = tls_config(tls_ext_config, root_store)?;
+    if opts.insecure_skip_verify {
+        tracing::warn!(
+            "insecure_skip_verify is set, handshake server certificate is NOT verified, never use it in production"
+        );
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoCertificateVerification));
+    }
+    if opts.no_session_cache {
+        tls_config.session_storage = Arc::new(NoClientSessionStorage {});
+    }
+    Ok(tls_config)
+}
+
+/// Trust anchors for the handshake server certificate: the given CA file and
+/// directory, or the builtin webpki roots if neither is set.
+fn root_store(ca_file: Option<&Path>, ca_dir: Option<&Path>) -> anyhow::Result<RootCertStore> {
+    let mut root_store = RootCertStore::empty();
+    if ca_file.is_none() && ca_dir.is_none() {
+        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        return Ok(root_store);
+    }
+    if let Some(path) = ca_file {
+        for cert in read_certs(path)? {
+            root_store.add(&Certificate(cert)).map_err(|e| {
+                anyhow::anyhow!("invalid CA certificate in {}: {e}", path.display())
+            })?;
+        }
+    }
+    if let Some(dir) = ca_dir {
+        let entries = std::fs::read_dir(dir)
+            .map_err(|e| anyhow::anyhow!("unable to read CA directory {}: {e}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            // directories like /etc/ssl/certs may contain other files
+            match read_certs(&path) {
+                Ok(certs) => {
+                    let (_, ignored) = root_store.add_parsable_certificates(&certs);
+                    if ignored != 0 {
+                        tracing::warn!(
+                            "ignored {ignored} invalid CA certificates in {}",
+                            path.display()
+                        );
+                    }
+                }
+                Err(e) => tracing::debug!("skip {}: {e}", path.display()),
+            }
+        }
+    }
+    if root_store.is_empty() {
+        bail!("no CA certificate loaded");
+    }
+    tracing::info!("loaded {} CA certificates", root_store.len());
+    Ok(root_store)
+}
+
+/// Read certificates from a PEM file, or a single DER certificate.
+fn read_certs(path: &Path) -> anyhow::Result<Vec<Vec<u8>>> {
+    let content = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("unable to read {}: {e}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut content.as_slice())
+        .map_err(|e| anyhow::anyhow!("invalid PEM file {}: {e}", path.display()))?;
+    if !certs.is_empty() {
+        return Ok(certs);
+    }
+    // DER encoded certificate starts with a SEQUENCE tag
+    if content.first() == Some(&0x30) {
+        return Ok(vec![content]);
+    }
+    bail!("no certificate found in {}", path.display())
+}
+
+/// Verifier accepting any server certificate.
+pub struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls_fork_shadow_tls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Why the server random is not authorized after handshake.
+fn unauthorized(session: &rustls_fork_shadow_tls::ClientConnection) -> ProbeError {
+    // With TLS1.3 negotiated, the server is reachable but does not speak v3.
+    match session.protocol_version() {
+        Some(rustls_fork_shadow_tls::ProtocolVersion::TLSv1_3) => ProbeError::NotV3,
+        _ => ProbeError::Auth("traffic hijacked or TLS1.3 is not supported"),
+    }
+}
+
+/// Log what the handshake server negotiated.
+fn log_handshake(session: &rustls_fork_shadow_tls::ClientConnection) {
+    match session.alpn_protocol() {
+        Some(alpn) => tracing::info!(
+            "handshake server selected ALPN: {}",
+            String::from_utf8_lossy(alpn)
+        ),
+        None => tracing::info!("handshake server selected no ALPN"),
+    }
+}
+
+/// Log the tunnel for addr with what the handshake server negotiated.
+fn log_access(addr: SocketAddr, session: &rustls_fork_shadow_tls::ClientConnection) {
+    // rustls names versions like TLSv1_3
+    let version = session
+        .protocol_version()
+        .map(|v| format!("{v:?}").replace('_', "."))
+        .unwrap_or_else(|| "unknown".to_string());
+    let cipher = session
+        .negotiated_cipher_suite()
+        .map(|s| cipher_suite_name(&s))
+        .unwrap_or_else(|| "unknown".to_string());
+    tracing::info!("access: {addr} tunnel established, version={version} cipher={cipher}");
+}
+
 /// A wrapper for doing data extraction and modification.
 ///
 /// Only used by V3 protocol.
@@ -547,3 +1143,652 @@ fn generate_session_id(hmac: &Hmac, buf: &[u8]) -> [u8; TLS_SESSION_ID_SIZE] {
     }
     session_id
 }
+
+#[cfg(test)]
+mod tests {
+    use rustls_fork_shadow_tls::{Certificate, ClientConnection, PrivateKey, ServerConnection};
+
+    use super::*;
+    use crate::util::test_util::capture_logs;
+
+    const CERT: &[u8] = include_bytes!("testdata/localhost.crt.der");
+    const KEY: &[u8] = include_bytes!("testdata/localhost.key.der");
+
+    /// Handshake in memory with a server selecting ALPN from its own list.
+    fn handshake(client_alpn: &[&str], server_alpn: &[&str]) -> ClientConnection {
+        let mut root_store = RootCertStore::empty();
+        root_store.add(&Certificate(CERT.to_vec())).unwrap();
+        let mut client_config = rustls_fork_shadow_tls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+        client_config.alpn_protocols = client_alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        handshake_with(Arc::new(client_config), server_alpn).unwrap()
+    }
+
+    fn handshake_with(
+        client_config: Arc<rustls_fork_shadow_tls::ClientConfig>,
+        server_alpn: &[&str],
+    ) -> Result<ClientConnection, rustls_fork_shadow_tls::Error> {
+        let mut server_config = rustls_fork_shadow_tls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![Certificate(CERT.to_vec())], PrivateKey(KEY.to_vec()))
+            .unwrap();
+        server_config.alpn_protocols = server_alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+
+        let mut client =
+            ClientConnection::new(client_config, "localhost".try_into().unwrap()).unwrap();
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+        let mut buf = Vec::new();
+        while client.is_handshaking() || server.is_handshaking() {
+            buf.clear();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut buf.as_slice()).unwrap();
+            server.process_new_packets().unwrap();
+            buf.clear();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut buf.as_slice()).unwrap();
+            client.process_new_packets()?;
+        }
+        Ok(client)
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn probe_password() {
+        use crate::server::{ServerOpts, ShadowTlsServer, TlsAddrs};
+
+        let server_config = rustls_fork_shadow_tls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![Certificate(CERT.to_vec())], PrivateKey(KEY.to_vec()))
+            .unwrap();
+        let acceptor = monoio_rustls_fork_shadow_tls::TlsAcceptor::from(server_config);
+        let handshake_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let handshake_addr = handshake_listener.local_addr().unwrap();
+        monoio::spawn(async move {
+            while let Ok((conn, _)) = handshake_listener.accept().await {
+                let acceptor = acceptor.clone();
+                monoio::spawn(async move {
+                    if let Ok(mut tls) = acceptor.accept(conn).await {
+                        let _ = tls.read(vec![0; 1024]).await;
+                    }
+                });
+            }
+        });
+        let server = ShadowTlsServer::new(
+            "127.0.0.1:0",
+            "127.0.0.1:1",
+            TlsAddrs::try_from(handshake_addr.to_string().as_str()).unwrap(),
+            "pwd".to_string(),
+            ServerOpts {
+                v3: true,
+                ..Default::default()
+            },
+        );
+        let listener = server.bind().unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        monoio::spawn(server.serve(listener));
+
+        let probe = |password: &str| {
+            ShadowTlsClient::new(
+                (),
+                server_addr,
+                TlsNames::try_from("localhost").unwrap(),
+                TlsExtConfig::default(),
+                password.to_string(),
+                ClientOpts {
+                    v3: true,
+                    ca_file: Some(
+                        Path::new(env!("CARGO_MANIFEST_DIR"))
+                            .join("src/testdata/localhost.crt.der"),
+                    ),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        assert!(probe("pwd").probe().await.is_ok());
+        assert!(matches!(
+            probe("wrong").probe().await,
+            Err(ProbeError::NotV3)
+        ));
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn backend_down_fallback() {
+        use std::sync::atomic::Ordering;
+
+        use crate::{
+            metrics::METRICS,
+            server::{ServerOpts, ShadowTlsServer, TlsAddrs},
+        };
+
+        /// Wait a while for the condition to hold.
+        async fn eventually(done: impl Fn() -> bool) -> bool {
+            let wait = async {
+                while !done() {
+                    monoio::time::sleep(Duration::from_millis(10)).await;
+                }
+            };
+            monoio::time::timeout(Duration::from_secs(2), wait)
+                .await
+                .is_ok()
+        }
+
+        let server_config = rustls_fork_shadow_tls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![Certificate(CERT.to_vec())], PrivateKey(KEY.to_vec()))
+            .unwrap();
+        let acceptor = monoio_rustls_fork_shadow_tls::TlsAcceptor::from(server_config);
+        let handshake_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let handshake_addr = handshake_listener.local_addr().unwrap();
+        // records the handshake server could not decrypt
+        let rejected = Rc::new(std::cell::Cell::new(0));
+        let rejected_by_server = rejected.clone();
+        monoio::spawn(async move {
+            while let Ok((conn, _)) = handshake_listener.accept().await {
+                let (acceptor, rejected) = (acceptor.clone(), rejected_by_server.clone());
+                monoio::spawn(async move {
+                    if let Ok(mut tls) = acceptor.accept(conn).await {
+                        if tls.read(vec![0; 1024]).await.0.is_err() {
+                            rejected.set(rejected.get() + 1);
+                        }
+                    }
+                });
+            }
+        });
+        let unreachable = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let tunnel = |max_handshake_conns| {
+            let server = ShadowTlsServer::new(
+                "127.0.0.1:0",
+                unreachable,
+                TlsAddrs::try_from(handshake_addr.to_string().as_str()).unwrap(),
+                "pwd".to_string(),
+                ServerOpts {
+                    v3: true,
+                    backend_down_fallback: true,
+                    max_handshake_conns,
+                    ..Default::default()
+                },
+            );
+            let listener = server.bind().unwrap();
+            let server_addr = listener.local_addr().unwrap();
+            monoio::spawn(server.serve(listener));
+            let client = ShadowTlsClient::new(
+                (),
+                server_addr,
+                TlsNames::try_from("localhost").unwrap(),
+                TlsExtConfig::default(),
+                "pwd".to_string(),
+                ClientOpts {
+                    v3: true,
+                    ca_file: Some(
+                        Path::new(env!("CARGO_MANIFEST_DIR"))
+                            .join("src/testdata/localhost.crt.der"),
+                    ),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            async move {
+                let (mut app, in_stream) = crate::util::test_util::tcp_pair().await;
+                let (res, _) = app.write_all(b"data").await;
+                res.unwrap();
+                let addr = in_stream.peer_addr().unwrap();
+                let relay = client.relay_v3(in_stream, addr);
+                let _ = monoio::time::timeout(Duration::from_secs(5), relay).await;
+            }
+        };
+
+        // the authenticated client falls back after the handshake, its data
+        // goes to the handshake server instead
+        let before = METRICS.backend_down.load(Ordering::Relaxed);
+        tunnel(Some(1)).await;
+        assert!(
+            eventually(|| METRICS.backend_down.load(Ordering::Relaxed) > before).await,
+            "not classified as backend_down"
+        );
+        assert!(
+            eventually(|| rejected.get() > 0).await,
+            "data not forwarded to handshake server"
+        );
+
+        // and takes a probe slot, dropped when no slot is left
+        let before = METRICS.probe_dropped.load(Ordering::Relaxed);
+        tunnel(Some(0)).await;
+        assert!(
+            eventually(|| METRICS.probe_dropped.load(Ordering::Relaxed) > before).await,
+            "not dropped without probe slot"
+        );
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn cancel_setup_on_local_close() {
+        // the server never answers, so the handshake never finishes
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = ShadowTlsClient::new(
+            (),
+            upstream.local_addr().unwrap(),
+            TlsNames::try_from("localhost").unwrap(),
+            TlsExtConfig::default(),
+            "pwd".to_string(),
+            ClientOpts {
+                v3: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let (app, in_stream) = crate::util::test_util::tcp_pair().await;
+        let addr = in_stream.peer_addr().unwrap();
+        let upstream = async move {
+            let (mut conn, _) = upstream.accept().await.unwrap();
+            let (res, _) = conn.read(vec![0; 1024]).await;
+            assert!(res.unwrap() > 0);
+            drop(app);
+            let closed = monoio::time::timeout(Duration::from_secs(2), async {
+                loop {
+                    match conn.read(vec![0; 1024]).await.0 {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => (),
+                    }
+                }
+            });
+            assert!(closed.await.is_ok(), "upstream connection not released");
+        };
+        let (res, _) = monoio::join!(client.relay_v3(in_stream, addr), upstream);
+        res.unwrap();
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn decoy_handshakes() {
+        use crate::server::{ServerOpts, ShadowTlsServer, TlsAddrs};
+
+        let server_config = rustls_fork_shadow_tls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![Certificate(CERT.to_vec())], PrivateKey(KEY.to_vec()))
+            .unwrap();
+        let acceptor = monoio_rustls_fork_shadow_tls::TlsAcceptor::from(server_config);
+        // connect only tries the first resolved address
+        let addr = std::net::ToSocketAddrs::to_socket_addrs(&("localhost", 0))
+            .unwrap()
+            .next()
+            .unwrap();
+        // the cover site is the handshake server too, so it sees tunnels and decoys
+        let cover_site = TcpListener::bind(addr).unwrap();
+        let cover_addr = cover_site.local_addr().unwrap();
+        let cover_conns = Rc::new(std::cell::Cell::new(0));
+        let conns = cover_conns.clone();
+        monoio::spawn(async move {
+            while let Ok((conn, _)) = cover_site.accept().await {
+                conns.set(conns.get() + 1);
+                let acceptor = acceptor.clone();
+                monoio::spawn(async move {
+                    if let Ok(mut tls) = acceptor.accept(conn).await {
+                        let _ = tls.read(vec![0; 1024]).await;
+                    }
+                });
+            }
+        });
+        let data_server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let data_addr = data_server.local_addr().unwrap();
+        monoio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((conn, _)) = data_server.accept().await {
+                held.push(conn);
+            }
+        });
+        let server = ShadowTlsServer::new(
+            "127.0.0.1:0",
+            data_addr,
+            TlsAddrs::try_from(cover_addr.to_string().as_str()).unwrap(),
+            "pwd".to_string(),
+            ServerOpts {
+                v3: true,
+                ..Default::default()
+            },
+        );
+        let listener = server.bind().unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        monoio::spawn(server.serve(listener));
+
+        let relay = |password: &str| {
+            let mut client = ShadowTlsClient::new(
+                (),
+                server_addr,
+                TlsNames::try_from("localhost").unwrap(),
+                TlsExtConfig::default(),
+                password.to_string(),
+                ClientOpts {
+                    v3: true,
+                    ca_file: Some(
+                        Path::new(env!("CARGO_MANIFEST_DIR"))
+                            .join("src/testdata/localhost.crt.der"),
+                    ),
+                    decoy_handshakes: 3,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            client.decoy_port = cover_addr.port();
+            async move {
+                let (mut app, in_stream) = crate::util::test_util::tcp_pair().await;
+                let (res, _) = app.write_all(b"data").await;
+                res.unwrap();
+                let addr = in_stream.peer_addr().unwrap();
+                let relay = client.relay_v3(in_stream, addr);
+                let _ = monoio::time::timeout(Duration::from_millis(500), relay).await;
+            }
+        };
+        let settled = |expected| {
+            let cover_conns = cover_conns.clone();
+            async move {
+                let reached = monoio::time::timeout(Duration::from_secs(5), async {
+                    while cover_conns.get() < expected {
+                        monoio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                });
+                assert!(
+                    reached.await.is_ok(),
+                    "{} cover site connections",
+                    cover_conns.get()
+                );
+                monoio::time::sleep(Duration::from_millis(100)).await;
+                assert_eq!(cover_conns.get(), expected, "unexpected decoys");
+            }
+        };
+
+        // the tunnel handshake and 3 decoys
+        relay("pwd").await;
+        settled(4).await;
+        // no decoys for a connection the server did not authenticate
+        relay("wrong").await;
+        settled(5).await;
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn access_log() {
+        let server_config = rustls_fork_shadow_tls::ServerConfig::builder()
+            .with_cipher_suites(&[
+                rustls_fork_shadow_tls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
+            ])
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls_fork_shadow_tls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![Certificate(CERT.to_vec())], PrivateKey(KEY.to_vec()))
+            .unwrap();
+        let acceptor = monoio_rustls_fork_shadow_tls::TlsAcceptor::from(server_config);
+        let handshake_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let handshake_addr = handshake_listener.local_addr().unwrap();
+        monoio::spawn(async move {
+            let (conn, _) = handshake_listener.accept().await.unwrap();
+            if let Ok(mut tls) = acceptor.accept(conn).await {
+                let _ = tls.read(vec![0; 1024]).await;
+            }
+        });
+        let client = ShadowTlsClient::new(
+            (),
+            handshake_addr,
+            TlsNames::try_from("localhost").unwrap(),
+            TlsExtConfig::default(),
+            "pwd".to_string(),
+            ClientOpts {
+                ca_file: Some(
+                    Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testdata/localhost.crt.der"),
+                ),
+                access_log: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let (logs, guard) = capture_logs();
+        let (_app, in_stream) = crate::util::test_util::tcp_pair().await;
+        let addr = in_stream.peer_addr().unwrap();
+        // the log is written once the handshake finishes, before relaying
+        monoio::spawn(async move { client.relay_v2(in_stream, addr).await });
+        let logged = monoio::time::timeout(Duration::from_secs(5), async {
+            while !logs.contents().contains("access:") {
+                monoio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        assert!(logged.await.is_ok(), "no access log");
+        drop(guard);
+        assert!(logs.contents().contains(&format!(
+            "access: {addr} tunnel established, version=TLSv1.3 cipher=TLS_CHACHA20_POLY1305_SHA256"
+        )));
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn max_tunnels() {
+        // the server never answers, so tunnels stay in setup
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let upstream_conns = Rc::new(std::cell::Cell::new(0));
+        let conns = upstream_conns.clone();
+        monoio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((conn, _)) = upstream.accept().await {
+                conns.set(conns.get() + 1);
+                held.push(conn);
+            }
+        });
+        let client = |mode| {
+            ShadowTlsClient::new(
+                "127.0.0.1:0",
+                upstream_addr,
+                TlsNames::try_from("localhost").unwrap(),
+                TlsExtConfig::default(),
+                "pwd".to_string(),
+                ClientOpts {
+                    v3: true,
+                    max_tunnels: Some(2),
+                    max_tunnels_mode: mode,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        let client_wait = client(TunnelLimitMode::Wait);
+        let limit = client_wait.tunnel_limit.clone().unwrap();
+        let listener = client_wait.bind().unwrap();
+        let addr = listener.local_addr().unwrap();
+        monoio::spawn(client_wait.serve(listener));
+        let mut locals = Vec::new();
+        for _ in 0..8 {
+            // locals send data, so their tunnels are never abandoned
+            let mut local = TcpStream::connect(addr).await.unwrap();
+            let (res, _) = local.write_all(b"data").await;
+            res.unwrap();
+            locals.push(local);
+            monoio::time::sleep(Duration::from_millis(5)).await;
+            assert!(limit.current() <= 2);
+        }
+        monoio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(limit.current(), 2);
+        assert_eq!(upstream_conns.get(), 2);
+
+        let client_reject = client(TunnelLimitMode::Reject);
+        let limit = client_reject.tunnel_limit.clone().unwrap();
+        let listener = client_reject.bind().unwrap();
+        let addr = listener.local_addr().unwrap();
+        monoio::spawn(client_reject.serve(listener));
+        let mut rejected = 0;
+        for _ in 0..5 {
+            let mut local = TcpStream::connect(addr).await.unwrap();
+            let (res, _) = local.write_all(b"data").await;
+            res.unwrap();
+            let read = monoio::time::timeout(Duration::from_millis(100), local.read(vec![0; 1]));
+            if let Ok((Ok(0), _)) = read.await {
+                rejected += 1;
+            }
+            locals.push(local);
+            assert!(limit.current() <= 2);
+        }
+        assert_eq!(rejected, 3);
+        assert_eq!(upstream_conns.get(), 4);
+    }
+
+    #[test]
+    fn no_session_cache() {
+        /// Check if ClientHello offers pre_shared_key for resumption.
+        fn offers_psk(config: &Arc<rustls_fork_shadow_tls::ClientConfig>) -> bool {
+            let mut conn =
+                ClientConnection::new(config.clone(), "localhost".try_into().unwrap()).unwrap();
+            let mut hello = Vec::new();
+            conn.write_tls(&mut hello).unwrap();
+            // skip record header, handshake header, version and random
+            let mut idx = TLS_HEADER_SIZE + 4 + 2 + TLS_RANDOM_SIZE;
+            idx += 1 + hello[idx] as usize;
+            idx += 2 + u16::from_be_bytes([hello[idx], hello[idx + 1]]) as usize;
+            idx += 1 + hello[idx] as usize;
+            idx += 2;
+            while idx + 4 <= hello.len() {
+                let ext = u16::from_be_bytes([hello[idx], hello[idx + 1]]);
+                if ext == 41 {
+                    return true;
+                }
+                idx += 4 + u16::from_be_bytes([hello[idx + 2], hello[idx + 3]]) as usize;
+            }
+            false
+        }
+
+        let ca_file = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testdata/localhost.crt.der");
+        for no_session_cache in [false, true] {
+            let opts = ClientOpts {
+                ca_file: Some(ca_file.clone()),
+                no_session_cache,
+                ..Default::default()
+            };
+            let config = Arc::new(verifying_tls_config(&TlsExtConfig::default(), &opts).unwrap());
+            assert!(!offers_psk(&config));
+            handshake_with(config.clone(), &[]).unwrap();
+            assert_eq!(offers_psk(&config), !no_session_cache);
+        }
+    }
+
+    #[test]
+    fn reload_ca_file() {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testdata");
+        let ca_file = std::env::temp_dir().join(format!("shadow-tls-ca-{}", std::process::id()));
+        std::fs::copy(testdata.join("localhost.crt.der"), &ca_file).unwrap();
+        let client = ShadowTlsClient::new(
+            (),
+            (),
+            TlsNames::try_from("localhost").unwrap(),
+            TlsExtConfig::default(),
+            "pwd".to_string(),
+            ClientOpts {
+                ca_file: Some(ca_file.clone()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let before = client.tls_config.load();
+        assert!(handshake_with(before.clone(), &[]).is_ok());
+
+        // the new CA takes effect for new connections only
+        std::fs::copy(testdata.join("other-ca.crt.der"), &ca_file).unwrap();
+        client.reload_tls_config().unwrap();
+        assert!(handshake_with(client.tls_config.load(), &[]).is_err());
+        assert!(handshake_with(before, &[]).is_ok());
+
+        // invalid file is rejected and the current config is kept
+        let current = client.tls_config.load();
+        std::fs::write(&ca_file, "not a certificate").unwrap();
+        assert!(client.reload_tls_config().is_err());
+        assert!(Arc::ptr_eq(&current, &client.tls_config.load()));
+        std::fs::remove_file(&ca_file).unwrap();
+    }
+
+    #[test]
+    fn verify_with_custom_ca() {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testdata");
+        let connect = |opts: ClientOpts| {
+            handshake_with(
+                Arc::new(verifying_tls_config(&TlsExtConfig::default(), &opts).unwrap()),
+                &[],
+            )
+        };
+        assert!(connect(ClientOpts {
+            ca_file: Some(testdata.join("localhost.crt.der")),
+            ..Default::default()
+        })
+        .is_ok());
+        assert!(connect(ClientOpts {
+            ca_dir: Some(testdata.clone()),
+            ..Default::default()
+        })
+        .is_ok());
+        assert!(connect(ClientOpts {
+            ca_file: Some(testdata.join("other-ca.crt.der")),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(connect(ClientOpts {
+            ca_file: Some(testdata.join("other-ca.crt.der")),
+            insecure_skip_verify: true,
+            ..Default::default()
+        })
+        .is_ok());
+        assert!(root_store(Some(&testdata.join("localhost.key.der")), None).is_err());
+    }
+
+    #[test]
+    fn client_hello_cipher_suites() {
+        assert!(parse_cipher_suites("TLS_AES_128_GCM_SHA256,TLS_FAKE").is_err());
+        assert!(parse_cipher_suites("0x1301,TLS_AES_128_GCM_SHA256").is_err());
+        let suites =
+            parse_cipher_suites("TLS_CHACHA20_POLY1305_SHA256, 0x1301,tls_aes_256_gcm_sha384")
+                .unwrap();
+        assert_eq!(
+            suites.to_string(),
+            "TLS_CHACHA20_POLY1305_SHA256,TLS_AES_128_GCM_SHA256,TLS_AES_256_GCM_SHA384"
+        );
+        let config = tls_config(
+            &TlsExtConfig::default().with_cipher_suites(Some(suites)),
+            RootCertStore::empty(),
+        )
+        .unwrap();
+        let mut conn =
+            ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+        let mut hello = Vec::new();
+        conn.write_tls(&mut hello).unwrap();
+
+        // skip record header, handshake header, version and random
+        let mut idx = TLS_HEADER_SIZE + 4 + 2 + TLS_RANDOM_SIZE;
+        idx += 1 + hello[idx] as usize;
+        let len = u16::from_be_bytes([hello[idx], hello[idx + 1]]) as usize;
+        let offered: Vec<_> = hello[idx + 2..idx + 2 + len]
+            .chunks(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .collect();
+        // rustls always appends TLS_EMPTY_RENEGOTIATION_INFO_SCSV
+        assert_eq!(offered, vec![0x1303, 0x1301, 0x1302, 0x00ff]);
+    }
+
+    #[test]
+    fn log_selected_alpn() {
+        let (logs, guard) = capture_logs();
+        log_handshake(&handshake(&["h2", "http/1.1"], &["http/1.1"]));
+        log_handshake(&handshake(&["h2"], &[]));
+        drop(guard);
+        let logs = logs.contents();
+        let mut lines = logs.lines();
+        assert!(lines
+            .next()
+            .unwrap()
+            .ends_with("handshake server selected ALPN: http/1.1"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .ends_with("handshake server selected no ALPN"));
+    }
+}
@@ -21,7 +21,7 @@ use monoio::{
     },
 };
 
-use crate::util::prelude::*;
+use crate::util::{prelude::*, BufferSizes};
 
 pub const HMAC_SIZE_V2: usize = 8;
 
@@ -69,7 +69,8 @@ impl<S> HashedStream for HashedReadStream<S> {
 
 pub struct HashedWriteStream<S> {
     raw: S,
-    hmac: Rc<RefCell<(bool, hmac::Hmac<sha1::Sha1>)>>,
+    /// One HMAC per accepted password, the first is the current one.
+    hmac: Rc<RefCell<(bool, Vec<hmac::Hmac<sha1::Sha1>>)>>,
 }
 
 // # Safety
@@ -91,9 +92,22 @@ impl<S: AsWriteFd> AsWriteFd for HashedWriteStream<S> {
 
 impl<S> HashedWriteStream<S> {
     pub fn new(raw: S, password: &[u8]) -> Result<Self, hmac::digest::InvalidLength> {
+        Self::with_passwords(raw, &[password])
+    }
+
+    /// Hash written data with every password, so a client using any of them
+    /// can be recognized.
+    pub fn with_passwords(
+        raw: S,
+        passwords: &[&[u8]],
+    ) -> Result<Self, hmac::digest::InvalidLength> {
+        let hmacs = passwords
+            .iter()
+            .map(|password| hmac::Hmac::new_from_slice(password))
+            .collect::<Result<_, _>>()?;
         Ok(Self {
             raw,
-            hmac: Rc::new(RefCell::new((true, hmac::Hmac::new_from_slice(password)?))),
+            hmac: Rc::new(RefCell::new((true, hmacs))),
         })
     }
 
@@ -102,15 +116,7 @@ impl<S> HashedWriteStream<S> {
     }
 
     pub fn hash(&self) -> [u8; 20] {
-        self.hmac
-            .borrow()
-            .clone()
-            .1
-            .finalize()
-            .into_bytes()
-            .as_slice()
-            .try_into()
-            .expect("unexpected digest length")
+        self.hmac_handler().hash()
     }
 
     pub fn hmac_handler(&self) -> HmacHandler {
@@ -118,19 +124,29 @@ impl<S> HashedWriteStream<S> {
     }
 }
 
-pub struct HmacHandler(Rc<RefCell<(bool, hmac::Hmac<sha1::Sha1>)>>);
+pub struct HmacHandler(Rc<RefCell<(bool, Vec<hmac::Hmac<sha1::Sha1>>)>>);
 
 impl HmacHandler {
+    /// Hash with the current password.
     pub fn hash(&self) -> [u8; 20] {
+        self.hashes()[0]
+    }
+
+    /// Hash with every password, in the order they are given.
+    pub fn hashes(&self) -> Vec<[u8; 20]> {
         self.0
             .borrow()
-            .clone()
             .1
-            .finalize()
-            .into_bytes()
-            .as_slice()
-            .try_into()
-            .expect("unexpected digest length")
+            .iter()
+            .map(|hmac| {
+                hmac.clone()
+                    .finalize()
+                    .into_bytes()
+                    .as_slice()
+                    .try_into()
+                    .expect("unexpected digest length")
+            })
+            .collect()
     }
 
     pub fn disable(&mut self) {
@@ -242,7 +258,8 @@ impl<S: AsyncWriteRent> AsyncWriteRent for HashedWriteStream<S> {
                 let mut eh = self.hmac.borrow_mut();
                 if eh.0 {
                     // Safety: we can make sure the ptr and n are valid.
-                    eh.1.update(unsafe { std::slice::from_raw_parts(ptr, n) });
+                    let data = unsafe { std::slice::from_raw_parts(ptr, n) };
+                    eh.1.iter_mut().for_each(|hmac| hmac.update(data));
                 }
             }
             (result, buf)
@@ -530,19 +547,21 @@ pub async fn copy_with_application_data<'a, const N: usize, R, W>(
     reader: &'a mut R,
     writer: &'a mut W,
     write_prefix: Option<[u8; N]>,
+    buffers: BufferSizes,
 ) -> std::io::Result<u64>
 where
     R: monoio::io::AsyncReadRent + ?Sized,
     W: monoio::io::AsyncWriteRent + ?Sized,
 {
-    let mut buf: Vec<u8> = vec![0; COPY_BUF_SIZE];
+    let buf_size = buffers.relay_send();
+    let mut buf: Vec<u8> = vec![0; buf_size];
     buf[0] = APPLICATION_DATA;
     // 0x03, 0x03: tls 1.2
     buf[1] = TLS_MAJOR;
     buf[2] = TLS_MINOR.0;
     // prefix
     let mut buf = if let Some(prefix) = write_prefix {
-        assert!(N + TLS_HEADER_SIZE <= COPY_BUF_SIZE);
+        assert!(N + TLS_HEADER_SIZE <= buf_size);
         unsafe {
             std::ptr::copy_nonoverlapping(prefix.as_ptr(), buf.as_mut_ptr().add(TLS_HEADER_SIZE), N)
         };
@@ -604,12 +623,13 @@ where
 pub async fn copy_without_application_data<'a, R, W>(
     reader: &'a mut R,
     writer: &'a mut W,
+    buffers: BufferSizes,
 ) -> std::io::Result<u64>
 where
     R: monoio::io::AsyncReadRent + ?Sized,
     W: monoio::io::AsyncWriteRent + ?Sized,
 {
-    let mut buf: Vec<u8> = vec![0; COPY_BUF_SIZE];
+    let mut buf: Vec<u8> = vec![0; buffers.relay_recv()];
     let mut to_copy = 0;
     let mut transfered: u64 = 0;
 
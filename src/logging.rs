@@ -0,0 +1,578 @@
+//! Log target setup: stderr, syslog(local or remote) or file.
+
+use std::{
+    any::TypeId,
+    fmt::Write as _,
+    io::Write,
+    net::UdpSocket,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use rustc_hash::FxHashMap;
+use tracing::{
+    callsite::Identifier,
+    field::{display, Field, Value, Visit},
+    span, Event, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{fmt, fmt::MakeWriter, layer::Context, registry::LookupSpan, Layer};
+
+/// Where to write logs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum LogTarget {
+    #[default]
+    Stderr,
+    /// Local syslog(/dev/log) when None, otherwise remote syslog over UDP.
+    Syslog(Option<String>),
+    File(PathBuf),
+}
+
+impl FromStr for LogTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => bail!("empty log target"),
+            "stderr" => Ok(Self::Stderr),
+            "syslog" => Ok(Self::Syslog(None)),
+            s => match s.strip_prefix("syslog:") {
+                Some(remote) if remote.contains(':') => Ok(Self::Syslog(Some(remote.to_string()))),
+                Some(_) => bail!("remote syslog must be like syslog:127.0.0.1:514"),
+                None => Ok(Self::File(PathBuf::from(s))),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for LogTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stderr => write!(f, "stderr"),
+            Self::Syslog(None) => write!(f, "syslog"),
+            Self::Syslog(Some(remote)) => write!(f, "syslog:{remote}"),
+            Self::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+pub fn parse_log_target(s: &str) -> anyhow::Result<LogTarget> {
+    LogTarget::from_str(s)
+}
+
+/// Syslog facility names indexed by code.
+const FACILITIES: [&str; 12] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv",
+    "ftp",
+];
+
+/// Parse syslog facility name into its code.
+pub fn parse_facility(s: &str) -> anyhow::Result<u8> {
+    if let Some(idx) = FACILITIES.iter().position(|&f| f == s) {
+        return Ok(idx as u8);
+    }
+    match s.strip_prefix("local").and_then(|n| n.parse::<u8>().ok()) {
+        Some(n) if n < 8 => Ok(16 + n),
+        _ => Err(anyhow!("unknown syslog facility: {s}")),
+    }
+}
+
+/// Name of the syslog facility code, the reverse of parse_facility.
+pub fn facility_name(code: u8) -> Option<String> {
+    match code {
+        16..=23 => Some(format!("local{}", code - 16)),
+        code => FACILITIES.get(code as usize).map(|f| f.to_string()),
+    }
+}
+
+/// Build fmt layer for the given target, warnings and errors from the same
+/// statement within rate_limit are collapsed if given.
+pub fn build_layer<S>(
+    target: &LogTarget,
+    facility: u8,
+    tag: &str,
+    rate_limit: Option<Duration>,
+) -> anyhow::Result<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    build_layer_with(target, facility, tag, rate_limit, std::io::stderr)
+}
+
+/// Like build_layer, with the stderr target written to the given writer.
+fn build_layer_with<S, E>(
+    target: &LogTarget,
+    facility: u8,
+    tag: &str,
+    rate_limit: Option<Duration>,
+    stderr: E,
+) -> anyhow::Result<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    E: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = match target {
+        LogTarget::Stderr => fmt::layer().with_writer(stderr).boxed(),
+        LogTarget::Syslog(remote) => {
+            // syslog records time itself.
+            let writer = SyslogWriter::new(remote.as_deref(), facility, tag)?;
+            fmt::layer()
+                .with_writer(writer)
+                .with_ansi(false)
+                .without_time()
+                .boxed()
+        }
+        LogTarget::File(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| anyhow!("unable to open log file {}: {e}", path.display()))?;
+            fmt::layer()
+                .with_writer(Mutex::new(file))
+                .with_ansi(false)
+                .boxed()
+        }
+    };
+    Ok(match rate_limit {
+        Some(window) => {
+            let limited = RateLimited::new(layer, window);
+            limited.spawn_flusher();
+            limited.boxed()
+        }
+        None => layer,
+    })
+}
+
+/// Target of the suppression summaries, which are never limited.
+const RATE_LIMIT_TARGET: &str = "shadow_tls::rate_limit";
+
+/// Collapse identical warnings and errors from the same log statement: the
+/// first one in a window is logged, the rest are counted. The count is
+/// appended to the first one logged after the window, or logged by the
+/// flusher if none comes.
+pub struct RateLimited<L> {
+    inner: L,
+    limiter: Arc<RateLimiter>,
+}
+
+/// Distinct messages tracked at most, messages beyond it are not limited.
+const MAX_RATE_LIMITED: usize = 1024;
+
+struct RateLimiter {
+    window: Duration,
+    seen: Mutex<FxHashMap<(Identifier, String), Seen>>,
+}
+
+struct Seen {
+    since: Instant,
+    suppressed: u64,
+    level: Level,
+}
+
+impl<L> RateLimited<L> {
+    pub fn new(inner: L, window: Duration) -> Self {
+        Self {
+            inner,
+            limiter: Arc::new(RateLimiter {
+                window,
+                seen: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Spawn a thread logging counts of suppressed messages whose window
+    /// expired without another identical message.
+    pub fn spawn_flusher(&self) -> std::thread::JoinHandle<()> {
+        let limiter = self.limiter.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(limiter.window);
+            for (level, summary) in limiter.flush_expired(Instant::now()) {
+                match level {
+                    Level::ERROR => tracing::error!(target: RATE_LIMIT_TARGET, "{summary}"),
+                    _ => tracing::warn!(target: RATE_LIMIT_TARGET, "{summary}"),
+                }
+            }
+        })
+    }
+}
+
+impl RateLimiter {
+    /// None if the message is suppressed, otherwise the number of messages
+    /// suppressed before it.
+    fn check(&self, callsite: Identifier, level: Level, message: String) -> Option<u64> {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        let key = (callsite, message);
+        match seen.get_mut(&key) {
+            Some(s) if now.duration_since(s.since) < self.window => {
+                s.suppressed += 1;
+                None
+            }
+            Some(s) => {
+                let suppressed = std::mem::take(&mut s.suppressed);
+                s.since = now;
+                Some(suppressed)
+            }
+            None => {
+                if seen.len() >= MAX_RATE_LIMITED {
+                    // expired ones without suppressed messages are useless
+                    seen.retain(|_, s| {
+                        s.suppressed != 0 || now.duration_since(s.since) < self.window
+                    });
+                }
+                if seen.len() < MAX_RATE_LIMITED {
+                    let seen_now = Seen {
+                        since: now,
+                        suppressed: 0,
+                        level,
+                    };
+                    seen.insert(key, seen_now);
+                }
+                Some(0)
+            }
+        }
+    }
+
+    /// Take counts of suppressed messages whose window expired by now, with
+    /// the summary to log. Expired messages are forgotten.
+    fn flush_expired(&self, now: Instant) -> Vec<(Level, String)> {
+        let mut summaries = Vec::new();
+        self.seen.lock().unwrap().retain(|(_, message), s| {
+            if now.duration_since(s.since) < self.window {
+                return true;
+            }
+            if s.suppressed != 0 {
+                summaries.push((
+                    s.level,
+                    format!(
+                        "{} identical messages suppressed in {:?}: {message}",
+                        s.suppressed, self.window
+                    ),
+                ));
+            }
+            false
+        });
+        summaries
+    }
+}
+
+/// Message and other fields of an event formatted as one line.
+#[derive(Default)]
+struct EventText(String);
+
+impl Visit for EventText {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.0.insert_str(0, value),
+            name => {
+                let _ = write!(self.0, " {name}={value:?}");
+            }
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "message" => self.0.insert_str(0, &format!("{value:?}")),
+            name => {
+                let _ = write!(self.0, " {name}={value:?}");
+            }
+        }
+    }
+}
+
+impl<S: Subscriber, L: Layer<S>> Layer<S> for RateLimited<L> {
+    fn on_layer(&mut self, subscriber: &mut S) {
+        self.inner.on_layer(subscriber);
+    }
+
+    fn register_callsite(
+        &self,
+        metadata: &'static Metadata<'static>,
+    ) -> tracing::subscriber::Interest {
+        self.inner.register_callsite(metadata)
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        self.inner.enabled(metadata, ctx)
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_new_span(attrs, id, ctx);
+    }
+
+    fn on_record(&self, span: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        self.inner.on_record(span, values, ctx);
+    }
+
+    fn on_follows_from(&self, span: &span::Id, follows: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_follows_from(span, follows, ctx);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() > Level::WARN || metadata.target() == RATE_LIMIT_TARGET {
+            return self.inner.on_event(event, ctx);
+        }
+        let mut text = EventText::default();
+        event.record(&mut text);
+        let text = text.0;
+        match self
+            .limiter
+            .check(metadata.callsite(), *metadata.level(), text.clone())
+        {
+            None => {}
+            Some(0) => self.inner.on_event(event, ctx),
+            Some(suppressed) => match metadata.fields().field("message") {
+                Some(field) => {
+                    let text = display(format!(
+                        "{text} ({suppressed} identical messages suppressed)"
+                    ));
+                    let values = [(&field, Some(&text as &dyn Value))];
+                    let values = metadata.fields().value_set(&values);
+                    self.inner.on_event(&Event::new(metadata, &values), ctx);
+                }
+                None => self.inner.on_event(event, ctx),
+            },
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_enter(id, ctx);
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_exit(id, ctx);
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        self.inner.on_close(id, ctx);
+    }
+
+    fn on_id_change(&self, old: &span::Id, new: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_id_change(old, new, ctx);
+    }
+
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        match id {
+            id if id == TypeId::of::<Self>() => Some(self as *const _ as *const ()),
+            id => self.inner.downcast_raw(id),
+        }
+    }
+}
+
+enum SyslogSocket {
+    #[cfg(unix)]
+    Local(std::os::unix::net::UnixDatagram),
+    Remote(UdpSocket),
+}
+
+/// Send every log event as a syslog message.
+pub struct SyslogWriter {
+    socket: SyslogSocket,
+    facility: u8,
+    tag: String,
+    pid: u32,
+}
+
+impl SyslogWriter {
+    pub fn new(remote: Option<&str>, facility: u8, tag: &str) -> anyhow::Result<Self> {
+        let socket = match remote {
+            Some(remote) => {
+                let socket = UdpSocket::bind("[::]:0").or_else(|_| UdpSocket::bind("0.0.0.0:0"))?;
+                socket
+                    .connect(remote)
+                    .map_err(|e| anyhow!("unable to connect remote syslog {remote}: {e}"))?;
+                SyslogSocket::Remote(socket)
+            }
+            #[cfg(unix)]
+            None => {
+                const SYSLOG_PATH: &str = "/dev/log";
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket
+                    .connect(SYSLOG_PATH)
+                    .map_err(|e| anyhow!("unable to connect local syslog {SYSLOG_PATH}: {e}"))?;
+                SyslogSocket::Local(socket)
+            }
+            #[cfg(not(unix))]
+            None => bail!("local syslog is not supported on this platform"),
+        };
+        Ok(Self {
+            socket,
+            facility,
+            tag: tag.to_string(),
+            pid: std::process::id(),
+        })
+    }
+
+    fn message(&self, level: &Level) -> SyslogMessage<'_> {
+        let severity = match *level {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            _ => 7,
+        };
+        let mut buf = Vec::with_capacity(256);
+        let _ = write!(
+            buf,
+            "<{}>{}[{}]: ",
+            self.facility * 8 + severity,
+            self.tag,
+            self.pid
+        );
+        SyslogMessage { writer: self, buf }
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogWriter {
+    type Writer = SyslogMessage<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.message(&Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.message(meta.level())
+    }
+}
+
+/// One syslog message, sent when dropped.
+pub struct SyslogMessage<'a> {
+    writer: &'a SyslogWriter,
+    buf: Vec<u8>,
+}
+
+impl Write for SyslogMessage<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogMessage<'_> {
+    fn drop(&mut self) {
+        while self.buf.last() == Some(&b'\n') {
+            self.buf.pop();
+        }
+        let _ = match &self.writer.socket {
+            #[cfg(unix)]
+            SyslogSocket::Local(s) => s.send(&self.buf),
+            SyslogSocket::Remote(s) => s.send(&self.buf),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn parse_targets() {
+        assert_eq!(parse_log_target("stderr").unwrap(), LogTarget::Stderr);
+        assert_eq!(parse_log_target("syslog").unwrap(), LogTarget::Syslog(None));
+        assert_eq!(
+            parse_log_target("syslog:127.0.0.1:514").unwrap(),
+            LogTarget::Syslog(Some("127.0.0.1:514".to_string()))
+        );
+        assert!(parse_log_target("syslog:127.0.0.1").is_err());
+        assert_eq!(
+            parse_log_target("/var/log/shadow-tls.log").unwrap(),
+            LogTarget::File(PathBuf::from("/var/log/shadow-tls.log"))
+        );
+        assert_eq!(parse_facility("daemon").unwrap(), 3);
+        assert_eq!(parse_facility("local7").unwrap(), 23);
+        assert!(parse_facility("local8").is_err());
+    }
+
+    #[test]
+    fn init_each_target() {
+        let file = std::env::temp_dir().join(format!("shadow-tls-log-{}", std::process::id()));
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let remote = receiver.local_addr().unwrap().to_string();
+        let stderr = crate::util::test_util::LogBuf::default();
+        let writer = stderr.clone();
+        let writer = move || writer.clone();
+
+        let mut targets = vec![
+            LogTarget::Stderr,
+            LogTarget::File(file.clone()),
+            LogTarget::Syslog(Some(remote)),
+        ];
+        if std::path::Path::new("/dev/log").exists() {
+            targets.push(LogTarget::Syslog(None));
+        }
+        for target in targets {
+            let layer =
+                build_layer_with(&target, 1, "shadow-tls-test", None, writer.clone()).unwrap();
+            let subscriber = tracing_subscriber::registry().with(layer);
+            tracing::subscriber::with_default(subscriber, || tracing::warn!("hello {target}"));
+        }
+
+        let stderr = stderr.contents();
+        assert!(stderr.contains("WARN") && stderr.contains("hello stderr"));
+        assert_eq!(stderr.lines().count(), 1);
+        assert!(std::fs::read_to_string(&file).unwrap().contains("hello"));
+        let mut buf = [0; 1024];
+        let n = receiver.recv(&mut buf).unwrap();
+        let msg = String::from_utf8_lossy(&buf[..n]);
+        assert!(msg.starts_with(&format!("<12>shadow-tls-test[{}]: ", std::process::id())));
+        assert!(msg.contains("hello syslog:"));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn rate_limit_similar_warnings() {
+        let logs = crate::util::test_util::LogBuf::default();
+        let writer = logs.clone();
+        let layer = fmt::layer()
+            .with_writer(move || writer.clone())
+            .with_ansi(false);
+        let limited = RateLimited::new(layer, Duration::from_millis(300));
+        let limiter = limited.limiter.clone();
+        let subscriber = tracing_subscriber::registry().with(limited);
+        let probe = |ip| tracing::warn!("probe from {ip} failed");
+        let down = |reason| tracing::error!("handshake server down: {reason}");
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..100 {
+                probe("10.0.0.1");
+            }
+            // other messages of the same statement are not collapsed
+            probe("10.0.0.2");
+            down("connection refused");
+            down("connection refused");
+            down("timeout");
+            tracing::info!("not limited");
+            tracing::info!("not limited");
+            assert!(limiter.flush_expired(Instant::now()).is_empty());
+            std::thread::sleep(Duration::from_millis(400));
+            down("connection refused");
+        });
+        let contents = logs.contents();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 7, "{contents}");
+        assert!(lines[0].ends_with("probe from 10.0.0.1 failed"));
+        assert!(lines[1].ends_with("probe from 10.0.0.2 failed"));
+        assert!(lines[2].ends_with("handshake server down: connection refused"));
+        assert!(lines[3].ends_with("handshake server down: timeout"));
+        assert!(lines[6].ends_with(
+            "handshake server down: connection refused (1 identical messages suppressed)"
+        ));
+
+        // no later probe, the flusher logs the count
+        let flushed = limiter.flush_expired(Instant::now());
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(flushed[0].0, Level::WARN);
+        assert_eq!(
+            flushed[0].1,
+            "99 identical messages suppressed in 300ms: probe from 10.0.0.1 failed"
+        );
+        assert!(limiter.flush_expired(Instant::now()).is_empty());
+    }
+}
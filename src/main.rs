@@ -2,22 +2,57 @@
 #![feature(generic_associated_types)]
 #![feature(type_alias_impl_trait)]
 
+mod cli;
 mod client;
 mod helper_v2;
+mod logging;
+mod metrics;
+mod proxy_protocol;
 mod server;
 mod sip003;
 mod util;
 
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    path::PathBuf,
+    sync::{Arc, Barrier},
+    time::Duration,
+};
 
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, EnvFilter};
 
 use crate::{
-    client::{parse_client_names, ShadowTlsClient, TlsExtConfig, TlsNames},
-    server::{parse_server_addrs, ShadowTlsServer, TlsAddrs},
+    client::{
+        parse_cipher_suites, parse_client_names, CipherSuites, ClientOpts, ProbeError,
+        ShadowTlsClient, TlsExtConfig, TlsNames, TunnelLimitMode,
+    },
+    logging::{parse_facility, parse_log_target, LogTarget},
+    metrics::{parse_otlp_endpoint, OtlpEndpoint},
+    proxy_protocol::ProxyProtocolVersion,
+    server::{
+        parse_allowlist, parse_server_addrs, spawn_password_file_watcher,
+        spawn_tls_addrs_refresher, AddrAllowlist, CloseNotifyMode, PasswordFile, ServerOpts,
+        ShadowTlsServer, TlsAddrs,
+    },
+    util::{
+        parse_buffer_size, parse_jitter_range, parse_uring_entries, read_password_file,
+        salted_password, BufferSizes, ByteLimitMode, FrozenAddr, FrozenDns, JitterRange,
+        ResolvePreference,
+    },
 };
 
+const DEFAULT_HANDSHAKE_SOURCE_INTERVAL: u64 = 300;
+const DEFAULT_ROTATION_GRACE: u64 = 300;
+const DEFAULT_RETRY_CONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_LOG_FACILITY: u8 = 1;
+const DEFAULT_LOG_TAG: &str = "shadow-tls";
+const DEFAULT_OTLP_INTERVAL: u64 = 60;
+const DEFAULT_ALLOWED_HANDSHAKE_PORTS: &str = "443";
+const PROBE_CONNECT_FAILED: i32 = 1;
+const PROBE_AUTH_FAILED: i32 = 2;
+const PROBE_NOT_V3: i32 = 3;
+
 #[derive(Parser, Debug)]
 #[clap(
     author,
@@ -26,8 +61,9 @@ use crate::{
     long_about = "A proxy to expose real tls handshake to the firewall.\nGithub: github.com/ihciah/shadow-tls"
 )]
 struct Args {
+    /// None only with --features, checked in main.
     #[clap(subcommand)]
-    cmd: Commands,
+    cmd: Option<Commands>,
     #[clap(flatten)]
     opts: Opts,
 }
@@ -36,10 +72,337 @@ struct Args {
 struct Opts {
     #[clap(short, long, help = "Set parallelism manually")]
     threads: Option<u8>,
+    #[clap(
+        long,
+        help = "Pin each worker thread to a distinct CPU(round-robin over allowed CPUs)"
+    )]
+    cpu_affinity: bool,
+    #[clap(
+        long,
+        value_parser = parse_uring_entries,
+        help = "io_uring queue depth of each worker, a power of two in 256..=32768(default 1024, ignored with epoll)"
+    )]
+    uring_entries: Option<u32>,
+    #[clap(
+        long,
+        help = "Switch to this user(name or uid) after listeners are bound, Unix only"
+    )]
+    user: Option<String>,
+    #[clap(
+        long,
+        help = "Switch to this group(name or gid, default primary group of user) after listeners are bound, Unix only"
+    )]
+    group: Option<String>,
+    #[clap(
+        long,
+        help = "Enter this network namespace(like \"/var/run/netns/proxy\") on startup, all connections(remote syslog included) are made inside it, Linux only"
+    )]
+    netns: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Resolve the server address and handshake server addresses once at startup and never again, exit if one can not be resolved"
+    )]
+    freeze_dns: bool,
+    #[clap(
+        long,
+        help = "With freeze_dns, warn instead of exiting if a name can not be resolved at startup, it is then resolved on every connect"
+    )]
+    freeze_dns_lenient: bool,
     #[clap(short, long, help = "Disable TCP_NODELAY")]
     disable_nodelay: bool,
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "TCP_USER_TIMEOUT in milliseconds of relayed connections: unacknowledged data longer than this drops the connection, 0 means system default, Linux only. It also limits keepalive probing(every 90s), so keep it above 90000 or idle connections may be dropped by one lost probe"
+    )]
+    user_timeout: u64,
+    #[clap(
+        long,
+        value_parser = parse_buffer_size,
+        help = "Buffer size in bytes of sockets(SO_RCVBUF and SO_SNDBUF) and relay buffers, in 1024..=67108864. Relay buffers are capped at 16384(a TLS record), larger values only apply to sockets"
+    )]
+    buffer_size: Option<usize>,
+    #[clap(
+        long,
+        value_parser = parse_buffer_size,
+        help = "Receive buffer size in bytes, overrides buffer_size for SO_RCVBUF and the relay buffer of data from the TLS peer(capped at 16384)"
+    )]
+    recv_buffer_size: Option<usize>,
+    #[clap(
+        long,
+        value_parser = parse_buffer_size,
+        help = "Send buffer size in bytes, overrides buffer_size for SO_SNDBUF and the relay buffer of data to the TLS peer(capped at 16384)"
+    )]
+    send_buffer_size: Option<usize>,
     #[clap(long, help = "Use v3 protocol")]
     v3: bool,
+    #[clap(
+        long,
+        help = "Server only: command whose stdout lists TLS handshake server addresses(same format as --tls), run periodically to refresh the list"
+    )]
+    handshake_source_cmd: Option<String>,
+    #[clap(
+        long,
+        help = "Server only: interval in seconds to run handshake source command(default 300)"
+    )]
+    handshake_source_interval: Option<u64>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Server only(v3): how to relay TLS alerts(like close_notify) from handshake server during handshake"
+    )]
+    close_notify: CloseNotifyMode,
+    #[clap(
+        long,
+        help = "Server only: choose handshake server uniformly at random when SNI matches none of them"
+    )]
+    random_handshake: bool,
+    #[clap(
+        long,
+        value_delimiter = ',',
+        default_value = DEFAULT_ALLOWED_HANDSHAKE_PORTS,
+        help = "Server only: comma separated ports handshake servers may be on, 0 allows any port. Others are replaced by the fallback(last) one of --tls, checked at startup and every handshake source refresh"
+    )]
+    allowed_handshake_ports: Vec<u16>,
+    #[clap(
+        long,
+        hide = true,
+        help = "Server only: forward all connections verbatim to the handshake server, no authentication and no data server, for observing the cover site through shadow-tls"
+    )]
+    handshake_passthrough: bool,
+    #[clap(
+        long,
+        help = "Server only: refuse to start unless at least one handshake server completes a TLS handshake"
+    )]
+    require_handshake: bool,
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "Client only: retry times with exponential backoff when connecting server failed"
+    )]
+    connect_retries: u8,
+    #[clap(
+        long,
+        help = "Client only: time limit in seconds for connecting server including retries(default 10 when retrying)"
+    )]
+    connect_timeout: Option<u64>,
+    #[clap(
+        long,
+        help = "Server only: time limit in seconds for connecting the handshake server"
+    )]
+    handshake_connect_timeout: Option<u64>,
+    #[clap(
+        long,
+        help = "Server only: time limit in seconds for the TLS handshake after the handshake server connected, probes are not limited once handshake frames are finished"
+    )]
+    handshake_timeout: Option<u64>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Client only: address family to try first when the server address resolves to both"
+    )]
+    resolve_preference: ResolvePreference,
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "Close a connection after relaying this many bytes of data, 0 means unlimited"
+    )]
+    max_bytes_per_conn: u64,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Count max_bytes_per_conn on the sum of both directions or each direction"
+    )]
+    max_bytes_mode: ByteLimitMode,
+    #[clap(
+        long,
+        value_parser = parse_jitter_range,
+        help = "Random delay in milliseconds(like \"20-200\") before shutting down relayed connections, data is not delayed"
+    )]
+    close_jitter_ms: Option<JitterRange>,
+    #[clap(
+        long,
+        help = "Time limit in seconds for a single relay read(no separate idle timeout: a connection idle longer than this is closed)"
+    )]
+    read_timeout: Option<u64>,
+    #[clap(
+        long,
+        help = "Time limit in seconds for a single relay write(a peer not reading longer than this gets the connection closed)"
+    )]
+    write_timeout: Option<u64>,
+    #[clap(
+        long,
+        value_parser = parse_allowlist,
+        help = "Server only: comma separated IPs or CIDRs(like \"127.0.0.1,10.0.0.0/8\") the data server is allowed to resolve to"
+    )]
+    backend_allowlist: Option<AddrAllowlist>,
+    #[clap(
+        long,
+        value_enum,
+        help = "Server only: send PROXY protocol header of this version to the data server"
+    )]
+    proxy_protocol_version: Option<ProxyProtocolVersion>,
+    #[clap(
+        long,
+        help = "Salt mixed into key derivation to separate deployments sharing a password(must be the same on both sides)"
+    )]
+    salt: Option<String>,
+    #[clap(
+        long,
+        help = "Read password from this file instead of --password, the server polls it and rotates password on change"
+    )]
+    password_file: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Server only: seconds the previous password is still accepted after rotation(default 300)"
+    )]
+    rotation_grace: Option<u64>,
+    #[clap(
+        long,
+        help = "Server only(v3): window in seconds to remember authenticated ClientHello, replayed ones are handled like probes"
+    )]
+    replay_window: Option<u64>,
+    #[clap(
+        long,
+        help = "Log TCP_INFO(RTT, retransmits, delivery rate) of data server or server connections when closing, Linux only"
+    )]
+    tcp_info: bool,
+    #[clap(
+        long,
+        help = "Client only: log parameters negotiated with the handshake server, like the selected ALPN"
+    )]
+    debug_handshake: bool,
+    #[clap(
+        long,
+        help = "Client only: log every established tunnel with the TLS version and cipher suite negotiated with the handshake server"
+    )]
+    access_log: bool,
+    #[clap(
+        long,
+        value_parser = parse_cipher_suites,
+        help = "Client only: cipher suites offered in ClientHello in order, comma separated IANA names or hex codepoints(like \"TLS_AES_128_GCM_SHA256,0x1302\")"
+    )]
+    cipher_suites: Option<CipherSuites>,
+    #[clap(
+        long,
+        help = "Client only: CA certificates file(PEM or DER) to verify the handshake server with, instead of the builtin roots(reloaded on SIGHUP)"
+    )]
+    ca_file: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Client only: directory of CA certificate files to verify the handshake server with, instead of the builtin roots(reloaded on SIGHUP)"
+    )]
+    ca_dir: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Client only: DANGEROUS, accept any handshake server certificate, for testing only"
+    )]
+    insecure_skip_verify: bool,
+    #[clap(
+        long,
+        help = "Client only: do not resume TLS sessions, every connection does a full handshake(costs more RTT and CPU)"
+    )]
+    no_session_cache: bool,
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "Client only(v3, experimental): open this many real handshakes to the cover site(SNI on port 443) in background with every authenticated connection"
+    )]
+    decoy_handshakes: u8,
+    #[clap(long, help = "Client only: limit of concurrent tunnels to the server")]
+    max_tunnels: Option<usize>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Client only: local connections beyond max_tunnels wait in the accept backlog or are rejected"
+    )]
+    max_tunnels_mode: TunnelLimitMode,
+    #[clap(
+        long,
+        help = "Server only(v3): limit of concurrent probes relayed to handshake server, probes beyond it are dropped, authenticated clients are not counted"
+    )]
+    max_handshake_conns: Option<usize>,
+    #[clap(
+        long,
+        help = "Server only: forward authenticated clients to handshake server like probes when the data server is unreachable"
+    )]
+    backend_down_fallback: bool,
+    #[clap(
+        long,
+        help = "Server only: limit of connections not yet authenticated or classified as probe, accepting is deferred beyond it"
+    )]
+    max_pending_handshakes: Option<usize>,
+    #[clap(
+        long,
+        value_parser = parse_jitter_range,
+        help = "Server only(v3): random delay in milliseconds(like \"20-200\") before forwarding probes to the handshake server, authenticated clients are not delayed"
+    )]
+    probe_delay_ms: Option<JitterRange>,
+    #[clap(
+        long,
+        help = "Serve Prometheus metrics over HTTP on this address(like \"127.0.0.1:9100\")"
+    )]
+    metrics_listen: Option<String>,
+    #[clap(
+        long,
+        value_parser = parse_otlp_endpoint,
+        help = "Push metrics to this OpenTelemetry collector with OTLP/HTTP JSON(like \"http://127.0.0.1:4318\")"
+    )]
+    otlp_endpoint: Option<OtlpEndpoint>,
+    #[clap(long, help = "Seconds between OTLP metrics pushes(default 60)")]
+    otlp_interval: Option<u64>,
+    #[clap(
+        long,
+        help = "Instance name reported to OTLP collector(default hostname)"
+    )]
+    otlp_instance: Option<String>,
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "Log a summary of connections and relayed bytes every this many seconds, 0 means off"
+    )]
+    stats_interval: u64,
+    #[clap(
+        long,
+        value_delimiter = ',',
+        help = "Server only: buckets in seconds of the first byte latency histogram(like \"0.01,0.1,1\")"
+    )]
+    latency_buckets: Option<Vec<f64>>,
+    #[clap(
+        long,
+        default_value = "stderr",
+        value_parser = parse_log_target,
+        help = "Log target: stderr, syslog(local), syslog:host:port(remote over UDP) or a file path"
+    )]
+    log_target: LogTarget,
+    #[clap(
+        long,
+        value_parser = parse_facility,
+        help = "Syslog facility(like \"daemon\", \"local0\", default user)"
+    )]
+    log_facility: Option<u8>,
+    #[clap(long, help = "Syslog tag(default shadow-tls)")]
+    log_tag: Option<String>,
+    #[clap(
+        long,
+        help = "Window in seconds to collapse identical warnings and errors into one line with the suppressed count"
+    )]
+    log_rate_limit: Option<u64>,
+    #[clap(
+        long,
+        help = "Print the equivalent command line and exit, as SIP003 plugin it is logged at debug level on startup instead"
+    )]
+    print_cli: bool,
+    #[clap(
+        long,
+        exclusive = true,
+        help = "Print cargo features and platform capabilities compiled into this binary as JSON and exit"
+    )]
+    features: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -63,8 +426,8 @@ enum Commands {
             value_parser = parse_client_names
         )]
         tls_names: TlsNames,
-        #[clap(long = "password", help = "Password")]
-        password: String,
+        #[clap(long = "password", help = "Password(or use --password-file)")]
+        password: Option<String>,
         #[clap(
             long = "alpn",
             help = "Application-Layer Protocol Negotiation list(like \"http/1.1\", \"http/1.1;h2\")",
@@ -91,8 +454,29 @@ enum Commands {
             value_parser = parse_server_addrs
         )]
         tls_addr: TlsAddrs,
-        #[clap(long = "password", help = "Password")]
-        password: String,
+        #[clap(long = "password", help = "Password(or use --password-file)")]
+        password: Option<String>,
+    },
+    #[clap(about = "Check if the password is accepted by a running server")]
+    Probe {
+        #[clap(
+            long = "server",
+            help = "Your shadow-tls server address(like \"1.2.3.4:443\")"
+        )]
+        server_addr: String,
+        #[clap(
+            long = "sni",
+            help = "TLS handshake SNIs(like \"cloud.tencent.com\", \"captive.apple.com;cloud.tencent.com\")",
+            value_parser = parse_client_names
+        )]
+        tls_names: TlsNames,
+        #[clap(long = "password", help = "Password(or use --password-file)")]
+        password: Option<String>,
+    },
+    #[clap(hide = true, about = "Print shell completion script to stdout")]
+    Completions {
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
     },
 }
 
@@ -103,22 +487,23 @@ enum RunningArgs {
         tls_names: TlsNames,
         tls_ext: TlsExtConfig,
         password: String,
-        nodelay: bool,
-        v3: bool,
+        opts: ClientOpts,
     },
     Server {
         listen_addr: String,
         target_addr: String,
         tls_addr: TlsAddrs,
+        handshake_source: Option<(String, Duration)>,
         password: String,
-        nodelay: bool,
-        v3: bool,
+        password_file: Option<PasswordFile>,
+        opts: ServerOpts,
     },
 }
 
 impl From<Args> for RunningArgs {
     fn from(args: Args) -> Self {
-        match args.cmd {
+        let buffers = buffer_sizes(&args.opts);
+        match args.cmd.expect("subcommand is checked in main") {
             Commands::Client {
                 listen,
                 server_addr,
@@ -129,10 +514,10 @@ impl From<Args> for RunningArgs {
                 listen_addr: listen,
                 target_addr: server_addr,
                 tls_names,
-                tls_ext: TlsExtConfig::from(alpn),
-                password,
-                nodelay: !args.opts.disable_nodelay,
-                v3: args.opts.v3,
+                tls_ext: TlsExtConfig::from(alpn)
+                    .with_cipher_suites(args.opts.cipher_suites.clone()),
+                password: load_password(password, &args.opts),
+                opts: client_opts(&args.opts),
             },
             Commands::Server {
                 listen,
@@ -143,16 +528,140 @@ impl From<Args> for RunningArgs {
                 listen_addr: listen,
                 target_addr: server_addr,
                 tls_addr,
-                password,
-                nodelay: !args.opts.disable_nodelay,
-                v3: args.opts.v3,
+                password: load_password(password, &args.opts),
+                password_file: args.opts.password_file.clone().map(|path| PasswordFile {
+                    path,
+                    salt: args.opts.salt.clone(),
+                    grace: Duration::from_secs(
+                        args.opts.rotation_grace.unwrap_or(DEFAULT_ROTATION_GRACE),
+                    ),
+                }),
+                handshake_source: args.opts.handshake_source_cmd.map(|cmd| {
+                    let interval = args
+                        .opts
+                        .handshake_source_interval
+                        .unwrap_or(DEFAULT_HANDSHAKE_SOURCE_INTERVAL);
+                    (cmd, Duration::from_secs(interval))
+                }),
+                opts: ServerOpts {
+                    nodelay: !args.opts.disable_nodelay,
+                    v3: args.opts.v3,
+                    close_notify: args.opts.close_notify,
+                    random_handshake: args.opts.random_handshake,
+                    read_timeout: args.opts.read_timeout.map(Duration::from_secs),
+                    write_timeout: args.opts.write_timeout.map(Duration::from_secs),
+                    user_timeout: user_timeout(args.opts.user_timeout),
+                    buffers,
+                    backend_allowlist: args.opts.backend_allowlist,
+                    proxy_protocol: args.opts.proxy_protocol_version,
+                    replay_window: args.opts.replay_window.map(Duration::from_secs),
+                    tcp_info: args.opts.tcp_info,
+                    max_bytes_per_conn: args.opts.max_bytes_per_conn,
+                    max_bytes_mode: args.opts.max_bytes_mode,
+                    close_jitter: args.opts.close_jitter_ms,
+                    max_handshake_conns: args.opts.max_handshake_conns,
+                    backend_down_fallback: args.opts.backend_down_fallback,
+                    handshake_connect_timeout: args
+                        .opts
+                        .handshake_connect_timeout
+                        .map(Duration::from_secs),
+                    handshake_timeout: args.opts.handshake_timeout.map(Duration::from_secs),
+                    max_pending_handshakes: args.opts.max_pending_handshakes,
+                    probe_delay: args.opts.probe_delay_ms,
+                    // resolved in build if DNS is frozen
+                    frozen_dns: Arc::default(),
+                    allowed_handshake_ports: allowed_handshake_ports(
+                        args.opts.allowed_handshake_ports,
+                    ),
+                    handshake_passthrough: args.opts.handshake_passthrough,
+                },
             },
+            Commands::Completions { .. } => unreachable!("completions are printed in main"),
+            Commands::Probe { .. } => unreachable!("probe is run in main"),
         }
     }
 }
 
+fn client_opts(opts: &Opts) -> ClientOpts {
+    ClientOpts {
+        nodelay: !opts.disable_nodelay,
+        v3: opts.v3,
+        connect_retries: opts.connect_retries,
+        connect_timeout: opts
+            .connect_timeout
+            .or_else(|| (opts.connect_retries > 0).then_some(DEFAULT_RETRY_CONNECT_TIMEOUT))
+            .map(Duration::from_secs),
+        read_timeout: opts.read_timeout.map(Duration::from_secs),
+        write_timeout: opts.write_timeout.map(Duration::from_secs),
+        user_timeout: user_timeout(opts.user_timeout),
+        buffers: buffer_sizes(opts),
+        resolve_preference: opts.resolve_preference,
+        tcp_info: opts.tcp_info,
+        max_bytes_per_conn: opts.max_bytes_per_conn,
+        max_bytes_mode: opts.max_bytes_mode,
+        close_jitter: opts.close_jitter_ms,
+        debug_handshake: opts.debug_handshake,
+        access_log: opts.access_log,
+        ca_file: opts.ca_file.clone(),
+        ca_dir: opts.ca_dir.clone(),
+        insecure_skip_verify: opts.insecure_skip_verify,
+        no_session_cache: opts.no_session_cache,
+        decoy_handshakes: opts.decoy_handshakes,
+        max_tunnels: opts.max_tunnels,
+        max_tunnels_mode: opts.max_tunnels_mode,
+    }
+}
+
+/// TCP_USER_TIMEOUT in milliseconds, 0 means not set.
+fn user_timeout(ms: u64) -> Option<Duration> {
+    (ms != 0).then(|| Duration::from_millis(ms))
+}
+
+/// Ports handshake servers may be on, None if any is allowed.
+fn allowed_handshake_ports(ports: Vec<u16>) -> Option<Vec<u16>> {
+    (!ports.contains(&0)).then_some(ports)
+}
+
+/// Buffer sizes of each direction, falling back to buffer_size.
+fn buffer_sizes(opts: &Opts) -> BufferSizes {
+    BufferSizes {
+        recv: opts.recv_buffer_size.or(opts.buffer_size),
+        send: opts.send_buffer_size.or(opts.buffer_size),
+    }
+}
+
+/// Take password from the file if given, or from the command line.
+fn load_password(password: Option<String>, opts: &Opts) -> String {
+    let password = match (&opts.password_file, password) {
+        (Some(path), _) => read_password_file(path).unwrap_or_else(|e| {
+            tracing::error!("{e}");
+            std::process::exit(1);
+        }),
+        (None, Some(password)) => password,
+        (None, None) => {
+            tracing::error!("password is required, use --password or --password-file");
+            std::process::exit(1);
+        }
+    };
+    salted_password(password, opts.salt.as_deref())
+}
+
 impl RunningArgs {
-    fn build(self) -> anyhow::Result<Runnable<String, String>> {
+    /// Names resolved at startup if DNS is frozen.
+    fn dns_names(&self) -> Vec<&str> {
+        match self {
+            Self::Client { target_addr, .. } => vec![target_addr],
+            Self::Server {
+                target_addr,
+                tls_addr,
+                ..
+            } => std::iter::once(target_addr.as_str())
+                .chain(tls_addr.addrs())
+                .collect(),
+        }
+    }
+
+    fn build(self, dns: FrozenDns) -> anyhow::Result<Runnable<String, FrozenAddr>> {
         match self {
             RunningArgs::Client {
                 listen_addr,
@@ -160,32 +669,36 @@ impl RunningArgs {
                 tls_names,
                 tls_ext,
                 password,
-                nodelay,
-                v3,
+                opts,
             } => Ok(Runnable::Client(ShadowTlsClient::new(
                 listen_addr,
-                target_addr,
+                FrozenAddr::new(target_addr, &dns),
                 tls_names,
                 tls_ext,
                 password,
-                nodelay,
-                v3,
+                opts,
             )?)),
             RunningArgs::Server {
                 listen_addr,
                 target_addr,
                 tls_addr,
+                handshake_source,
                 password,
-                nodelay,
-                v3,
-            } => Ok(Runnable::Server(ShadowTlsServer::new(
-                listen_addr,
-                target_addr,
-                tls_addr,
-                password,
-                nodelay,
-                v3,
-            ))),
+                password_file,
+                mut opts,
+            } => {
+                let target_addr = FrozenAddr::new(target_addr, &dns);
+                opts.frozen_dns = Arc::new(dns);
+                let server =
+                    ShadowTlsServer::new(listen_addr, target_addr, tls_addr, password, opts);
+                if let Some((cmd, interval)) = handshake_source {
+                    spawn_tls_addrs_refresher(cmd, interval, server.tls_addrs_handle());
+                }
+                if let Some(file) = password_file {
+                    spawn_password_file_watcher(file, server.passwords_handle());
+                }
+                Ok(Runnable::Server(Box::new(server)))
+            }
         }
     }
 }
@@ -198,30 +711,166 @@ impl Display for RunningArgs {
                 target_addr,
                 tls_names,
                 tls_ext,
-                nodelay,
-                v3,
+                opts,
                 ..
             } => {
-                write!(f, "Client with:\nListen address: {listen_addr}\nTarget address: {target_addr}\nTLS server names: {tls_names}\nTLS Extension: {tls_ext}\nTCP_NODELAY: {nodelay}\nV3 Protocol: {v3}")
+                write!(f, "Client with:\nListen address: {listen_addr}\nTarget address: {target_addr}\nTLS server names: {tls_names}\nTLS Extension: {tls_ext}\nTCP_NODELAY: {}\nV3 Protocol: {}\nConnect retries: {}", opts.nodelay, opts.v3, opts.connect_retries)?;
+                if let Some(timeout) = opts.connect_timeout {
+                    write!(f, "\nConnect timeout: {}s", timeout.as_secs())?;
+                }
+                write!(f, "\nResolve preference: {}", opts.resolve_preference)?;
+                write_op_timeouts(f, opts.read_timeout, opts.write_timeout)?;
+                if let Some(timeout) = opts.user_timeout {
+                    write!(f, "\nTCP_USER_TIMEOUT: {}ms", timeout.as_millis())?;
+                }
+                write_buffer_sizes(f, opts.buffers)?;
+                write_byte_limit(f, opts.max_bytes_per_conn, opts.max_bytes_mode)?;
+                if let Some(jitter) = opts.close_jitter {
+                    write!(f, "\nClose jitter: {jitter}")?;
+                }
+                if let Some(max) = opts.max_tunnels {
+                    write!(f, "\nMax tunnels: {max}({})", opts.max_tunnels_mode)?;
+                }
+                if opts.decoy_handshakes != 0 {
+                    write!(f, "\nDecoy handshakes: {}", opts.decoy_handshakes)?;
+                }
+                Ok(())
             }
             Self::Server {
                 listen_addr,
                 target_addr,
                 tls_addr,
-                nodelay,
-                v3,
+                handshake_source,
+                password_file,
+                opts,
                 ..
             } => {
-                write!(f, "Server with:\nListen address: {listen_addr}\nTarget address: {target_addr}\nTLS server address: {tls_addr}\nTCP_NODELAY: {nodelay}\nV3 Protocol: {v3}")
+                write!(f, "Server with:\nListen address: {listen_addr}\nTarget address: {target_addr}\nTLS server address: {tls_addr}\nTCP_NODELAY: {}\nV3 Protocol: {}\nClose notify: {}\nRandom handshake: {}", opts.nodelay, opts.v3, opts.close_notify, opts.random_handshake)?;
+                if let Some(file) = password_file {
+                    write!(
+                        f,
+                        "\nPassword file: {}(grace {}s)",
+                        file.path.display(),
+                        file.grace.as_secs()
+                    )?;
+                }
+                if let Some((cmd, interval)) = handshake_source {
+                    write!(
+                        f,
+                        "\nHandshake source command: {cmd}(every {}s)",
+                        interval.as_secs()
+                    )?;
+                }
+                if let Some(ports) = &opts.allowed_handshake_ports {
+                    let ports: Vec<_> = ports.iter().map(u16::to_string).collect();
+                    write!(f, "\nAllowed handshake ports: {}", ports.join(","))?;
+                }
+                if opts.handshake_passthrough {
+                    write!(
+                        f,
+                        "\nHandshake passthrough: all connections go to the handshake server"
+                    )?;
+                }
+                if let Some(allowlist) = &opts.backend_allowlist {
+                    write!(f, "\nBackend allowlist: {allowlist}")?;
+                }
+                if let Some(version) = opts.proxy_protocol {
+                    write!(f, "\nPROXY protocol: {version}")?;
+                }
+                if let Some(window) = opts.replay_window {
+                    write!(f, "\nReplay window: {}s", window.as_secs())?;
+                }
+                if let Some(max) = opts.max_handshake_conns {
+                    write!(f, "\nMax probes relayed to handshake server: {max}")?;
+                }
+                if let Some(max) = opts.max_pending_handshakes {
+                    write!(f, "\nMax pending handshakes: {max}")?;
+                }
+                if let Some(timeout) = opts.handshake_connect_timeout {
+                    write!(f, "\nHandshake connect timeout: {}s", timeout.as_secs())?;
+                }
+                if let Some(timeout) = opts.handshake_timeout {
+                    write!(f, "\nHandshake timeout: {}s", timeout.as_secs())?;
+                }
+                if let Some(delay) = opts.probe_delay {
+                    write!(f, "\nProbe delay: {delay}")?;
+                }
+                write_op_timeouts(f, opts.read_timeout, opts.write_timeout)?;
+                if let Some(timeout) = opts.user_timeout {
+                    write!(f, "\nTCP_USER_TIMEOUT: {}ms", timeout.as_millis())?;
+                }
+                write_buffer_sizes(f, opts.buffers)?;
+                write_byte_limit(f, opts.max_bytes_per_conn, opts.max_bytes_mode)?;
+                if let Some(jitter) = opts.close_jitter {
+                    write!(f, "\nClose jitter: {jitter}")?;
+                }
+                Ok(())
             }
         }
     }
 }
 
+fn write_op_timeouts(
+    f: &mut std::fmt::Formatter<'_>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+) -> std::fmt::Result {
+    if let Some(timeout) = read_timeout {
+        write!(f, "\nRead timeout: {}s", timeout.as_secs())?;
+    }
+    if let Some(timeout) = write_timeout {
+        write!(f, "\nWrite timeout: {}s", timeout.as_secs())?;
+    }
+    Ok(())
+}
+
+fn write_buffer_sizes(f: &mut std::fmt::Formatter<'_>, buffers: BufferSizes) -> std::fmt::Result {
+    if let Some(size) = buffers.recv {
+        write!(
+            f,
+            "\nReceive buffer: {size} bytes(relay reads {} bytes)",
+            buffers.relay_recv()
+        )?;
+    }
+    if let Some(size) = buffers.send {
+        write!(
+            f,
+            "\nSend buffer: {size} bytes(relay reads {} bytes)",
+            buffers.relay_send()
+        )?;
+    }
+    Ok(())
+}
+
+/// Warn if a buffer size is larger than the relay buffer uses.
+fn warn_relay_buffer_cap(buffers: BufferSizes) {
+    for (name, size, relay) in [
+        ("receive", buffers.recv, buffers.relay_recv()),
+        ("send", buffers.send, buffers.relay_send()),
+    ] {
+        if let Some(size) = size.filter(|&size| size > relay) {
+            tracing::warn!(
+                "{name} buffer of {size} bytes is only set on sockets, relay buffer is capped at {relay} bytes"
+            );
+        }
+    }
+}
+
+fn write_byte_limit(
+    f: &mut std::fmt::Formatter<'_>,
+    max: u64,
+    mode: ByteLimitMode,
+) -> std::fmt::Result {
+    if max != 0 {
+        write!(f, "\nMax bytes per connection: {max}({mode})")?;
+    }
+    Ok(())
+}
+
 #[derive(Clone)]
 enum Runnable<A, B> {
     Client(ShadowTlsClient<A, B>),
-    Server(ShadowTlsServer<A, B>),
+    Server(Box<ShadowTlsServer<A, B>>),
 }
 
 impl<A, B> Runnable<A, B>
@@ -229,43 +878,235 @@ where
     A: std::net::ToSocketAddrs + 'static,
     B: std::net::ToSocketAddrs + 'static,
 {
-    async fn serve(self) -> anyhow::Result<()> {
+    fn bind(&self) -> anyhow::Result<monoio::net::TcpListener> {
+        match self {
+            Runnable::Client(c) => c.bind(),
+            Runnable::Server(s) => s.bind(),
+        }
+    }
+
+    async fn serve(self, listener: monoio::net::TcpListener) -> anyhow::Result<()> {
         match self {
-            Runnable::Client(c) => c.serve().await,
-            Runnable::Server(s) => s.serve().await,
+            Runnable::Client(c) => c.serve(listener).await,
+            Runnable::Server(s) => s.serve(listener).await,
         }
     }
 }
 
+fn env_filter() -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy()
+        .add_directive("rustls=off".parse().unwrap())
+}
+
 fn main() {
+    // Log target is unknown until args are parsed, so log to stderr before that.
+    let mut args = tracing::subscriber::with_default(
+        tracing_subscriber::registry()
+            .with(fmt::layer())
+            .with(env_filter()),
+        sip003::get_sip003_arg,
+    )
+    .unwrap_or_else(Args::parse);
+    if args.opts.features {
+        println!("{}", features_json(monoio::utils::detect_uring()));
+        return;
+    }
+    let Some(cmd) = &args.cmd else {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingSubcommand,
+                "a subcommand is required",
+            )
+            .exit();
+    };
+    if let Commands::Completions { shell } = cmd {
+        write_completions(*shell, &mut std::io::stdout());
+        return;
+    }
+    if args.opts.print_cli {
+        println!("{}", args.to_cli_string(false));
+        return;
+    }
+    // CA certificates are reloaded on SIGHUP, which must be blocked before
+    // any thread is spawned.
+    #[cfg(unix)]
+    let reload_on_sighup = matches!(args.cmd, Some(Commands::Client { .. }))
+        && (args.opts.ca_file.is_some() || args.opts.ca_dir.is_some())
+        && util::block_sighup().is_ok();
+    // log targets like remote syslog create sockets, which belong to netns
+    if let Some(path) = &args.opts.netns {
+        if let Err(e) = util::enter_netns(path) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+    let log_layer = logging::build_layer(
+        &args.opts.log_target,
+        args.opts.log_facility.unwrap_or(DEFAULT_LOG_FACILITY),
+        args.opts.log_tag.as_deref().unwrap_or(DEFAULT_LOG_TAG),
+        args.opts.log_rate_limit.map(Duration::from_secs),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("unable to init log target {}: {e}", args.opts.log_target);
+        std::process::exit(1);
+    });
     tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy()
-                .add_directive("rustls=off".parse().unwrap()),
-        )
+        .with(log_layer)
+        .with(env_filter())
         .init();
-    let args = sip003::get_sip003_arg().unwrap_or_else(Args::parse);
+    tracing::debug!("Command line: {}", args.to_cli_string(true));
+    if let Some(path) = &args.opts.netns {
+        tracing::info!("entered network namespace {}", path.display());
+    }
+    if let Some(Commands::Probe { .. }) = args.cmd {
+        std::process::exit(probe(args));
+    }
+    if args.opts.tcp_info && !cfg!(target_os = "linux") {
+        tracing::warn!("TCP_INFO is only supported on Linux, tcp_info ignored");
+    }
+    if args.opts.user_timeout != 0 && !cfg!(target_os = "linux") {
+        tracing::warn!("TCP_USER_TIMEOUT is only supported on Linux, user_timeout ignored");
+    }
+    if args.opts.decoy_handshakes != 0 && !args.opts.v3 {
+        tracing::warn!("decoy handshakes are only opened with v3, decoy_handshakes ignored");
+    }
+    warn_relay_buffer_cap(buffer_sizes(&args.opts));
     let parallelism = get_parallelism(&args);
+    let (user, group) = (args.opts.user.clone(), args.opts.group.clone());
+    if let Some(buckets) = args.opts.latency_buckets.clone() {
+        metrics::METRICS.first_byte_latency.set_buckets(buckets);
+    }
+    if let Some(addr) = &args.opts.metrics_listen {
+        if let Err(e) = metrics::spawn_metrics_server(addr) {
+            tracing::error!("{e}");
+            std::process::exit(1);
+        }
+    }
+    if let Some(endpoint) = args.opts.otlp_endpoint.take() {
+        let resource = metrics::OtlpResource {
+            instance: args.opts.otlp_instance.clone().unwrap_or_else(hostname),
+            version: env!("CARGO_PKG_VERSION"),
+        };
+        let interval = args.opts.otlp_interval.unwrap_or(DEFAULT_OTLP_INTERVAL);
+        tracing::info!("OTLP metrics pushed to {endpoint} every {interval}s");
+        metrics::spawn_otlp_exporter(endpoint, resource, Duration::from_secs(interval.max(1)));
+    }
+    if args.opts.stats_interval != 0 {
+        metrics::spawn_stats_heartbeat(Duration::from_secs(args.opts.stats_interval));
+    }
+    let cpus = match args.opts.cpu_affinity {
+        true => util::allowed_cpus().unwrap_or_else(|e| {
+            tracing::warn!("CPU affinity is not available, ignored: {e}");
+            Vec::new()
+        }),
+        false => Vec::new(),
+    };
+    let uring_entries = args.opts.uring_entries;
+    if let Some(entries) = uring_entries {
+        match monoio::utils::detect_uring() {
+            true => tracing::info!("io_uring entries: {entries}"),
+            false => tracing::warn!("io_uring is not available, uring_entries ignored with epoll"),
+        }
+    }
+    let (freeze_dns, freeze_dns_lenient) = (args.opts.freeze_dns, args.opts.freeze_dns_lenient);
+    let require_handshake = args.opts.require_handshake;
     let running_args = RunningArgs::from(args);
     tracing::info!("Start {parallelism}-thread {running_args}");
 
-    let runnable = running_args.build().expect("unable to build runnable");
+    let dns = match freeze_dns {
+        true => {
+            FrozenDns::resolve(running_args.dns_names(), freeze_dns_lenient).unwrap_or_else(|e| {
+                tracing::error!("{e}");
+                std::process::exit(1);
+            })
+        }
+        false => FrozenDns::default(),
+    };
+    let runnable = running_args.build(dns).expect("unable to build runnable");
+    if let (true, Runnable::Server(server)) = (require_handshake, &runnable) {
+        let checked = runtime_builder(uring_entries)
+            .enable_timer()
+            .build()
+            .expect("unable to build monoio runtime")
+            .block_on(server.check_handshake_servers());
+        if let Err(e) = checked {
+            tracing::error!("{e}");
+            std::process::exit(1);
+        }
+    }
+    #[cfg(unix)]
+    if let (true, Runnable::Client(client)) = (reload_on_sighup, &runnable) {
+        let client = client.clone();
+        util::spawn_sighup_handler(move || match client.reload_tls_config() {
+            Ok(_) => tracing::info!("CA certificates reloaded"),
+            Err(e) => tracing::error!("reload CA certificates failed, keep the current ones: {e}"),
+        });
+    }
     let mut threads = Vec::new();
-    for _ in 0..parallelism {
+    // Workers report whether they are bound, then wait for privileges dropped
+    // before accepting.
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+    let barrier = Arc::new(Barrier::new(parallelism + 1));
+    for idx in 0..parallelism {
         let runnable_clone = runnable.clone();
+        let ready_tx = ready_tx.clone();
+        let barrier = barrier.clone();
+        let cpu = (!cpus.is_empty()).then(|| cpus[idx % cpus.len()]);
         let t = std::thread::spawn(move || {
-            let mut rt = monoio::RuntimeBuilder::<monoio::FusionDriver>::new()
-                .enable_timer()
-                .build()
-                .expect("unable to build monoio runtime(please refer to: https://github.com/ihciah/shadow-tls/wiki/How-to-Run#common-issues)");
-            let _ = rt.block_on(runnable_clone.serve());
+            if let Some(cpu) = cpu {
+                match util::pin_to_cpu(cpu) {
+                    Ok(_) => tracing::debug!("worker {idx} pinned to CPU {cpu}"),
+                    Err(e) => tracing::warn!("unable to pin worker {idx} to CPU {cpu}: {e}"),
+                }
+            }
+            let mut rt = match runtime_builder(uring_entries).enable_timer().build() {
+                Ok(rt) => rt,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(anyhow::anyhow!("unable to build monoio runtime(please refer to: https://github.com/ihciah/shadow-tls/wiki/How-to-Run#common-issues): {e}")));
+                    return;
+                }
+            };
+            rt.block_on(async move {
+                let listener = match runnable_clone.bind() {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = ready_tx.send(Ok(()));
+                barrier.wait();
+                let _ = runnable_clone.serve(listener).await;
+            });
         });
         threads.push(t);
     }
-    if let Err(e) = ctrlc::set_handler(|| std::process::exit(0)) {
+    // Only workers hold senders now, so a worker panicking early ends recv.
+    drop(ready_tx);
+    for _ in 0..parallelism {
+        match ready_rx.recv() {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => {
+                tracing::error!("{e}");
+                std::process::exit(1);
+            }
+            Err(_) => {
+                tracing::error!("worker exited before binding");
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Err(e) = util::drop_privileges(user.as_deref(), group.as_deref()) {
+        tracing::error!("unable to drop privileges: {e}");
+        std::process::exit(1);
+    }
+    barrier.wait();
+    if let Err(e) = ctrlc::set_handler(|| {
+        tracing::info!("Exiting, metrics: {}", metrics::METRICS);
+        std::process::exit(0)
+    }) {
         tracing::error!("Unable to register signal handler: {e}");
     }
     threads.into_iter().for_each(|t| {
@@ -273,6 +1114,112 @@ fn main() {
     });
 }
 
+/// Run probe subcommand and return the exit code.
+fn probe(args: Args) -> i32 {
+    let Some(Commands::Probe { server_addr, tls_names, password }) = args.cmd else {
+        unreachable!("not a probe command");
+    };
+    let client = ShadowTlsClient::new(
+        (),
+        server_addr,
+        tls_names,
+        TlsExtConfig::default().with_cipher_suites(args.opts.cipher_suites.clone()),
+        load_password(password, &args.opts),
+        client_opts(&args.opts),
+    );
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("unable to build client: {e}");
+            return PROBE_CONNECT_FAILED;
+        }
+    };
+    let mut rt = runtime_builder(args.opts.uring_entries)
+        .enable_timer()
+        .build()
+        .expect("unable to build monoio runtime");
+    match rt.block_on(client.probe()) {
+        Ok(elapsed) if args.opts.v3 => {
+            println!("authentication success in {}ms", elapsed.as_millis());
+            0
+        }
+        Ok(elapsed) => {
+            println!(
+                "handshake success in {}ms(protocol v2 can not check the password by handshake)",
+                elapsed.as_millis()
+            );
+            0
+        }
+        Err(e) => {
+            println!("{e}");
+            match e {
+                ProbeError::Connect(_) => PROBE_CONNECT_FAILED,
+                ProbeError::Auth(_) => PROBE_AUTH_FAILED,
+                ProbeError::NotV3 => PROBE_NOT_V3,
+            }
+        }
+    }
+}
+
+fn write_completions(shell: clap_complete::Shell, out: &mut dyn std::io::Write) {
+    let mut cmd = Args::command();
+    let name = cmd.get_name().to_string();
+    clap_complete::generate(shell, &mut cmd, name, out);
+}
+
+/// Optional cargo features and whether each is enabled in this build. There
+/// is none yet, the field keeps the output shape stable once they are added.
+const CARGO_FEATURES: [(&str, bool); 0] = [];
+
+/// Enabled cargo features, platform dependent capabilities compiled in, and
+/// whether io_uring is usable on this host.
+fn features_json(uring_available: bool) -> String {
+    let capabilities = [
+        ("io_uring", cfg!(target_os = "linux")),
+        ("tcp_info", cfg!(target_os = "linux")),
+        ("tcp_user_timeout", cfg!(target_os = "linux")),
+        ("netns", cfg!(target_os = "linux")),
+        ("cpu_affinity", cfg!(target_os = "linux")),
+        ("drop_privileges", cfg!(unix)),
+        ("sighup_reload", cfg!(unix)),
+        ("local_syslog", cfg!(unix)),
+    ];
+    let enabled = |list: &[(&str, bool)]| {
+        let names: Vec<_> = list
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| format!("\"{name}\""))
+            .collect();
+        names.join(",")
+    };
+    format!(
+        r#"{{"version":"{}","os":"{}","arch":"{}","cargo_features":[{}],"capabilities":[{}],"io_uring_available":{uring_available}}}"#,
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        enabled(&CARGO_FEATURES),
+        enabled(&capabilities)
+    )
+}
+
+fn runtime_builder(uring_entries: Option<u32>) -> monoio::RuntimeBuilder<monoio::FusionDriver> {
+    let builder = monoio::RuntimeBuilder::<monoio::FusionDriver>::new();
+    match uring_entries {
+        Some(entries) => builder.with_entries(entries),
+        None => builder,
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
 fn get_parallelism(args: &Args) -> usize {
     if let Some(n) = args.opts.threads {
         return n as usize;
@@ -281,3 +1228,59 @@ fn get_parallelism(args: &Args) -> usize {
         .map(|n| n.get())
         .unwrap_or(1)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_completions() {
+        use clap_complete::Shell;
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let mut out = Vec::new();
+            write_completions(shell, &mut out);
+            let script = String::from_utf8(out).unwrap();
+            assert!(script.contains("shadow-tls"), "{shell}");
+            assert!(script.contains("connect-retries"), "{shell}");
+        }
+    }
+
+    #[test]
+    fn list_features() {
+        // the flag needs no subcommand and takes no other option
+        let args = Args::try_parse_from(["shadow-tls", "--features"]).unwrap();
+        assert!(args.opts.features && args.cmd.is_none());
+        assert!(Args::try_parse_from(["shadow-tls", "--features", "--v3"]).is_err());
+
+        let json = features_json(false);
+        assert!(json.starts_with(&format!(
+            r#"{{"version":"{}","os":"{}","#,
+            env!("CARGO_PKG_VERSION"),
+            std::env::consts::OS
+        )));
+        assert!(json.ends_with(r#""io_uring_available":false}"#), "{json}");
+        // no optional cargo feature yet, so every build has none enabled
+        assert!(json.contains(r#""cargo_features":[],"#), "{json}");
+        assert_eq!(
+            json.contains(r#""tcp_info""#),
+            cfg!(target_os = "linux"),
+            "{json}"
+        );
+        assert_eq!(json.contains(r#""sighup_reload""#), cfg!(unix), "{json}");
+    }
+
+    #[test]
+    fn build_runtime_with_uring_entries() {
+        for entries in [None, Some(256), Some(1024), Some(4096), Some(32768)] {
+            let mut rt = runtime_builder(entries)
+                .enable_timer()
+                .build()
+                .unwrap_or_else(|e| panic!("{entries:?}: {e}"));
+            let slept = rt.block_on(async {
+                monoio::time::sleep(std::time::Duration::from_millis(1)).await;
+                true
+            });
+            assert!(slept);
+        }
+    }
+}
@@ -0,0 +1,748 @@
+use std::{
+    fmt::Write as _,
+    io::{Read, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Process wide counters, shared by all worker threads.
+pub static METRICS: Metrics = Metrics::new();
+
+/// At most this many probe logs are emitted per second.
+const PROBE_LOG_PER_SEC: u64 = 10;
+
+/// Default histogram buckets in seconds.
+pub const DEFAULT_LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+pub struct Metrics {
+    pub conn_authed: AtomicU64,
+    pub conn_probe: AtomicU64,
+    pub conn_fallback: AtomicU64,
+    pub replay_detected: AtomicU64,
+    /// Authenticated but handled like probe because the data server is down.
+    pub backend_down: AtomicU64,
+    /// Probes dropped because relayed probes reach max_handshake_conns.
+    pub probe_dropped: AtomicU64,
+    /// Connections currently not authenticated or classified as probe yet.
+    pub pending_handshakes: AtomicU64,
+    pub conn_accepted: AtomicU64,
+    /// Local connections closed by the client because of max_tunnels.
+    pub tunnel_rejected: AtomicU64,
+    pub conn_active: AtomicU64,
+    /// Bytes of data relay in both directions, handshake traffic excluded.
+    /// Added when the relay is closed.
+    pub bytes_relayed: AtomicU64,
+    /// Time from accept to the first byte sent to client, by fallback or not.
+    /// Fallback means it is not relayed to the data server, like probes.
+    pub first_byte_latency: Histogram,
+    probe_log_limit: RateLimit,
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Self {
+            conn_authed: AtomicU64::new(0),
+            conn_probe: AtomicU64::new(0),
+            conn_fallback: AtomicU64::new(0),
+            replay_detected: AtomicU64::new(0),
+            backend_down: AtomicU64::new(0),
+            probe_dropped: AtomicU64::new(0),
+            pending_handshakes: AtomicU64::new(0),
+            conn_accepted: AtomicU64::new(0),
+            tunnel_rejected: AtomicU64::new(0),
+            conn_active: AtomicU64::new(0),
+            bytes_relayed: AtomicU64::new(0),
+            first_byte_latency: Histogram::new(),
+            probe_log_limit: RateLimit::new(PROBE_LOG_PER_SEC),
+        }
+    }
+
+    /// Count and log how the connection is handled.
+    pub fn record_conn(&self, class: ConnClass, addr: SocketAddr) {
+        let counter = match class {
+            ConnClass::Authed => &self.conn_authed,
+            ConnClass::Probe => &self.conn_probe,
+            ConnClass::Fallback => &self.conn_fallback,
+            ConnClass::Replay => &self.replay_detected,
+            ConnClass::BackendDown => &self.backend_down,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        match class {
+            ConnClass::Authed => tracing::info!("connection from {addr} classified as {class}"),
+            ConnClass::Probe => {
+                if let Some(suppressed) = self.probe_log_limit.check(now_secs()) {
+                    tracing::warn!(
+                        "connection from {addr} classified as {class}, forwarded to handshake server({suppressed} probe logs suppressed)"
+                    );
+                }
+            }
+            ConnClass::Fallback => tracing::warn!(
+                "connection from {addr} classified as {class}, handshake server response unusable"
+            ),
+            ConnClass::Replay | ConnClass::BackendDown => tracing::warn!(
+                "connection from {addr} classified as {class}, forwarded to handshake server"
+            ),
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("conn_authed", self.conn_authed.load(Ordering::Relaxed)),
+            ("conn_probe", self.conn_probe.load(Ordering::Relaxed)),
+            ("conn_fallback", self.conn_fallback.load(Ordering::Relaxed)),
+            (
+                "replay_detected",
+                self.replay_detected.load(Ordering::Relaxed),
+            ),
+            ("backend_down", self.backend_down.load(Ordering::Relaxed)),
+            ("probe_dropped", self.probe_dropped.load(Ordering::Relaxed)),
+            ("conn_accepted", self.conn_accepted.load(Ordering::Relaxed)),
+            (
+                "tunnel_rejected",
+                self.tunnel_rejected.load(Ordering::Relaxed),
+            ),
+            ("bytes_relayed", self.bytes_relayed.load(Ordering::Relaxed)),
+        ]
+    }
+
+    pub fn gauges(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            (
+                "pending_handshakes",
+                self.pending_handshakes.load(Ordering::Relaxed),
+            ),
+            ("conn_active", self.conn_active.load(Ordering::Relaxed)),
+        ]
+    }
+
+    /// Count an accepted connection, which is active until the guard is dropped.
+    pub fn accept_conn(&'static self) -> ActiveConn {
+        self.conn_accepted.fetch_add(1, Ordering::Relaxed);
+        self.conn_active.fetch_add(1, Ordering::Relaxed);
+        ActiveConn(&self.conn_active)
+    }
+
+    /// One line summary of connections and traffic.
+    pub fn heartbeat(&self) -> String {
+        format!(
+            "stats: {} active connections, {} accepted, {} bytes relayed",
+            self.conn_active.load(Ordering::Relaxed),
+            self.conn_accepted.load(Ordering::Relaxed),
+            self.bytes_relayed.load(Ordering::Relaxed)
+        )
+    }
+}
+
+pub struct ActiveConn(&'static AtomicU64);
+
+impl Drop for ActiveConn {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Render in Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        for (name, value) in self.snapshot() {
+            let _ = writeln!(out, "# TYPE shadow_tls_{name}_total counter");
+            let _ = writeln!(out, "shadow_tls_{name}_total {value}");
+        }
+        for (name, value) in self.gauges() {
+            let _ = writeln!(out, "# TYPE shadow_tls_{name} gauge");
+            let _ = writeln!(out, "shadow_tls_{name} {value}");
+        }
+        self.first_byte_latency
+            .render("shadow_tls_first_byte_seconds", &mut out);
+        out
+    }
+}
+
+impl Metrics {
+    /// Render in OTLP JSON encoding as cumulative sums and histograms since
+    /// start_nanos.
+    pub fn render_otlp(&self, resource: &OtlpResource, start_nanos: u64, now_nanos: u64) -> String {
+        let times = format!(r#""startTimeUnixNano":"{start_nanos}","timeUnixNano":"{now_nanos}""#);
+        let mut metrics = Vec::new();
+        for (name, value) in self.snapshot() {
+            metrics.push(format!(
+                r#"{{"name":"shadow_tls_{name}","sum":{{"dataPoints":[{{"asInt":"{value}",{times}}}],"aggregationTemporality":2,"isMonotonic":true}}}}"#
+            ));
+        }
+        for (name, value) in self.gauges() {
+            metrics.push(format!(
+                r#"{{"name":"shadow_tls_{name}","gauge":{{"dataPoints":[{{"asInt":"{value}",{times}}}]}}}}"#
+            ));
+        }
+        metrics.push(
+            self.first_byte_latency
+                .render_otlp("shadow_tls_first_byte_seconds", &times),
+        );
+        format!(
+            r#"{{"resourceMetrics":[{{"resource":{{"attributes":[{}]}},"scopeMetrics":[{{"scope":{{"name":"shadow-tls"}},"metrics":[{}]}}]}}]}}"#,
+            resource.attributes(),
+            metrics.join(",")
+        )
+    }
+}
+
+impl std::fmt::Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, (name, value)) in self.snapshot().into_iter().enumerate() {
+            if idx != 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// How an incoming connection is handled by the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnClass {
+    /// Authenticated and relayed to the data server.
+    Authed,
+    /// Not authenticated, all traffic relayed to the handshake server.
+    Probe,
+    /// Authenticated, but the handshake server response can not be used, so
+    /// all traffic relayed to the handshake server.
+    Fallback,
+    /// Authenticated ClientHello seen before, handled like probe.
+    Replay,
+    /// Authenticated, but the data server is down, handled like probe.
+    BackendDown,
+}
+
+impl std::fmt::Display for ConnClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Authed => write!(f, "authed"),
+            Self::Probe => write!(f, "probe"),
+            Self::Fallback => write!(f, "fallback"),
+            Self::Replay => write!(f, "replay"),
+            Self::BackendDown => write!(f, "backend_down"),
+        }
+    }
+}
+
+/// Histogram labeled by fallback or not.
+pub struct Histogram(Mutex<HistogramInner>);
+
+struct HistogramInner {
+    bounds: Vec<f64>,
+    // indexed by fallback
+    series: [Series; 2],
+}
+
+impl HistogramInner {
+    fn reset(&mut self, mut bounds: Vec<f64>) {
+        bounds.sort_by(|a, b| a.total_cmp(b));
+        bounds.dedup();
+        self.series = [
+            Series {
+                counts: vec![0; bounds.len()],
+                ..Default::default()
+            },
+            Series {
+                counts: vec![0; bounds.len()],
+                ..Default::default()
+            },
+        ];
+        self.bounds = bounds;
+    }
+}
+
+#[derive(Default, Clone)]
+struct Series {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self(Mutex::new(HistogramInner {
+            bounds: Vec::new(),
+            series: [
+                Series {
+                    counts: Vec::new(),
+                    sum: 0.0,
+                    count: 0,
+                },
+                Series {
+                    counts: Vec::new(),
+                    sum: 0.0,
+                    count: 0,
+                },
+            ],
+        }))
+    }
+
+    /// Replace bucket upper bounds(in seconds), observations are reset.
+    pub fn set_buckets(&self, bounds: Vec<f64>) {
+        self.0.lock().unwrap().reset(bounds);
+    }
+
+    pub fn observe(&self, value: Duration, fallback: bool) {
+        let mut inner = self.0.lock().unwrap();
+        if inner.bounds.is_empty() {
+            inner.reset(DEFAULT_LATENCY_BUCKETS.to_vec());
+        }
+        let value = value.as_secs_f64();
+        let idx = inner.bounds.partition_point(|&b| b < value);
+        let series = &mut inner.series[fallback as usize];
+        if let Some(c) = series.counts.get_mut(idx) {
+            *c += 1;
+        }
+        series.sum += value;
+        series.count += 1;
+    }
+
+    fn render(&self, name: &str, out: &mut String) {
+        let inner = self.0.lock().unwrap();
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (fallback, series) in [false, true].into_iter().zip(inner.series.iter()) {
+            let mut cumulative = 0;
+            for (bound, count) in inner.bounds.iter().zip(series.counts.iter()) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "{name}_bucket{{fallback=\"{fallback}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{fallback=\"{fallback}\",le=\"+Inf\"}} {}",
+                series.count
+            );
+            let _ = writeln!(out, "{name}_sum{{fallback=\"{fallback}\"}} {}", series.sum);
+            let _ = writeln!(
+                out,
+                "{name}_count{{fallback=\"{fallback}\"}} {}",
+                series.count
+            );
+        }
+    }
+}
+
+impl Histogram {
+    fn render_otlp(&self, name: &str, times: &str) -> String {
+        let inner = self.0.lock().unwrap();
+        let bounds: Vec<_> = inner.bounds.iter().map(f64::to_string).collect();
+        let points: Vec<_> = [false, true]
+            .into_iter()
+            .zip(inner.series.iter())
+            .map(|(fallback, series)| {
+                // OTLP bucket counts are not cumulative and end with the +Inf one
+                let mut counts: Vec<_> = series.counts.iter().map(|c| format!(r#""{c}""#)).collect();
+                let overflow = series.count - series.counts.iter().sum::<u64>();
+                counts.push(format!(r#""{overflow}""#));
+                format!(
+                    r#"{{"attributes":[{{"key":"fallback","value":{{"boolValue":{fallback}}}}}],{times},"count":"{}","sum":{},"bucketCounts":[{}],"explicitBounds":[{}]}}"#,
+                    series.count,
+                    series.sum,
+                    counts.join(","),
+                    bounds.join(",")
+                )
+            })
+            .collect();
+        format!(
+            r#"{{"name":"{name}","unit":"s","histogram":{{"dataPoints":[{}],"aggregationTemporality":2}}}}"#,
+            points.join(",")
+        )
+    }
+}
+
+/// Resource attributes identifying this process in OTLP.
+pub struct OtlpResource {
+    pub instance: String,
+    pub version: &'static str,
+}
+
+impl OtlpResource {
+    fn attributes(&self) -> String {
+        [
+            ("service.name", "shadow-tls"),
+            ("service.instance.id", self.instance.as_str()),
+            ("service.version", self.version),
+        ]
+        .iter()
+        .map(|(key, value)| {
+            format!(
+                r#"{{"key":"{key}","value":{{"stringValue":"{}"}}}}"#,
+                escape_json(value)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+    }
+}
+
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Collector endpoint of OTLP/HTTP, only plain HTTP is supported.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OtlpEndpoint {
+    host: String,
+    path: String,
+}
+
+impl TryFrom<&str> for OtlpEndpoint {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let rest = value
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow::anyhow!("OTLP endpoint must start with http://: {value}"))?;
+        let (host, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/v1/metrics"),
+        };
+        if host.is_empty() {
+            anyhow::bail!("empty host in OTLP endpoint {value}");
+        }
+        let host = match host.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => host.to_string(),
+            _ => format!("{host}:4318"),
+        };
+        Ok(Self {
+            host,
+            path: path.to_string(),
+        })
+    }
+}
+
+pub fn parse_otlp_endpoint(arg: &str) -> anyhow::Result<OtlpEndpoint> {
+    OtlpEndpoint::try_from(arg)
+}
+
+impl std::fmt::Display for OtlpEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}{}", self.host, self.path)
+    }
+}
+
+impl OtlpEndpoint {
+    /// POST the body and check the response status.
+    fn post(&self, body: &str) -> anyhow::Result<()> {
+        let addr = std::net::ToSocketAddrs::to_socket_addrs(&self.host)?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("unable to resolve {}", self.host))?;
+        let mut conn = std::net::TcpStream::connect_timeout(&addr, OTLP_TIMEOUT)?;
+        conn.set_read_timeout(Some(OTLP_TIMEOUT))?;
+        conn.set_write_timeout(Some(OTLP_TIMEOUT))?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.path,
+            self.host,
+            body.len()
+        );
+        conn.write_all(request.as_bytes())?;
+        let mut status = [0; 12];
+        conn.read_exact(&mut status)?;
+        // like "HTTP/1.1 200"
+        match status.get(9) {
+            Some(b'2') => Ok(()),
+            _ => anyhow::bail!(
+                "collector responded {}",
+                String::from_utf8_lossy(&status[9..])
+            ),
+        }
+    }
+}
+
+const OTLP_TIMEOUT: Duration = Duration::from_secs(10);
+const OTLP_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Push metrics to the OTLP collector every interval on a separate thread.
+/// A failed export is retried with exponential backoff, the relay is never
+/// blocked by it.
+pub fn spawn_otlp_exporter(
+    endpoint: OtlpEndpoint,
+    resource: OtlpResource,
+    interval: Duration,
+) -> std::thread::JoinHandle<()> {
+    let start_nanos = now_nanos();
+    std::thread::spawn(move || {
+        let mut delay = interval;
+        loop {
+            std::thread::sleep(delay);
+            let body = METRICS.render_otlp(&resource, start_nanos, now_nanos());
+            delay = match endpoint.post(&body) {
+                Ok(_) => interval,
+                Err(e) => {
+                    let backoff = match delay == interval {
+                        true => Duration::from_secs(1),
+                        false => (delay * 2).min(OTLP_MAX_BACKOFF),
+                    };
+                    tracing::warn!(
+                        "export metrics to {endpoint} failed: {e}, retry after {backoff:?}"
+                    );
+                    backoff
+                }
+            };
+        }
+    })
+}
+
+/// Log the stats heartbeat every interval on a separate thread, with the
+/// subscriber of the calling thread.
+pub fn spawn_stats_heartbeat(interval: Duration) -> std::thread::JoinHandle<()> {
+    let dispatch = tracing::dispatcher::get_default(|d| d.clone());
+    std::thread::spawn(move || {
+        tracing::dispatcher::with_default(&dispatch, || loop {
+            std::thread::sleep(interval);
+            tracing::info!("{}", METRICS.heartbeat());
+        })
+    })
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// Serve metrics in Prometheus format over HTTP on a separate thread.
+pub fn spawn_metrics_server(addr: &str) -> anyhow::Result<std::thread::JoinHandle<()>> {
+    let listener = std::net::TcpListener::bind(addr)
+        .map_err(|e| anyhow::anyhow!("bind metrics address {addr} failed: {e}"))?;
+    Ok(std::thread::spawn(move || {
+        for conn in listener.incoming() {
+            let Ok(mut conn) = conn else {
+                continue;
+            };
+            // The request is not inspected, every path returns metrics.
+            let _ = conn.set_read_timeout(Some(Duration::from_secs(1)));
+            let _ = conn.read(&mut [0; 1024]);
+            let body = METRICS.render_prometheus();
+            let _ = write!(
+                conn,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+        }
+    }))
+}
+
+/// Allow `limit` events per second.
+pub struct RateLimit {
+    limit: u64,
+    window: AtomicU64,
+    count: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+impl RateLimit {
+    pub const fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            window: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Return the number of suppressed events since last allowed one if allowed.
+    pub fn check(&self, now: u64) -> Option<u64> {
+        if self.window.swap(now, Ordering::Relaxed) != now {
+            self.count.store(0, Ordering::Relaxed);
+        }
+        if self.count.fetch_add(1, Ordering::Relaxed) < self.limit {
+            Some(self.suppressed.swap(0, Ordering::Relaxed))
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_each_class() {
+        let metrics = Metrics::new();
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        metrics.record_conn(ConnClass::Authed, addr);
+        for _ in 0..2 {
+            metrics.record_conn(ConnClass::Fallback, addr);
+        }
+        for _ in 0..3 {
+            metrics.record_conn(ConnClass::Probe, addr);
+        }
+        metrics.record_conn(ConnClass::Replay, addr);
+        metrics.record_conn(ConnClass::BackendDown, addr);
+        assert_eq!(
+            metrics.snapshot(),
+            vec![
+                ("conn_authed", 1),
+                ("conn_probe", 3),
+                ("conn_fallback", 2),
+                ("replay_detected", 1),
+                ("backend_down", 1),
+                ("probe_dropped", 0),
+                ("conn_accepted", 0),
+                ("tunnel_rejected", 0),
+                ("bytes_relayed", 0),
+            ]
+        );
+        assert_eq!(
+            metrics.to_string(),
+            "conn_authed=1 conn_probe=3 conn_fallback=2 replay_detected=1 backend_down=1 probe_dropped=0 conn_accepted=0 tunnel_rejected=0 bytes_relayed=0"
+        );
+    }
+
+    #[test]
+    fn first_byte_histogram() {
+        let metrics = Metrics::new();
+        metrics.first_byte_latency.set_buckets(vec![0.1, 0.01]);
+        metrics
+            .first_byte_latency
+            .observe(Duration::from_millis(5), false);
+        metrics
+            .first_byte_latency
+            .observe(Duration::from_millis(50), true);
+        metrics
+            .first_byte_latency
+            .observe(Duration::from_millis(500), true);
+        let rendered = metrics.render_prometheus();
+        for line in [
+            "shadow_tls_conn_authed_total 0",
+            "shadow_tls_pending_handshakes 0",
+            r#"shadow_tls_first_byte_seconds_bucket{fallback="false",le="0.01"} 1"#,
+            r#"shadow_tls_first_byte_seconds_bucket{fallback="false",le="+Inf"} 1"#,
+            r#"shadow_tls_first_byte_seconds_bucket{fallback="true",le="0.01"} 0"#,
+            r#"shadow_tls_first_byte_seconds_bucket{fallback="true",le="0.1"} 1"#,
+            r#"shadow_tls_first_byte_seconds_bucket{fallback="true",le="+Inf"} 2"#,
+            r#"shadow_tls_first_byte_seconds_count{fallback="true"} 2"#,
+        ] {
+            assert!(
+                rendered.lines().any(|l| l == line),
+                "{line} not in {rendered}"
+            );
+        }
+    }
+
+    #[test]
+    fn otlp_export() {
+        assert!(parse_otlp_endpoint("https://collector:4318").is_err());
+        assert_eq!(
+            parse_otlp_endpoint("http://collector").unwrap().to_string(),
+            "http://collector:4318/v1/metrics"
+        );
+        let metrics = Metrics::new();
+        metrics.conn_probe.fetch_add(3, Ordering::Relaxed);
+        metrics.first_byte_latency.set_buckets(vec![0.01, 0.1]);
+        for ms in [5, 50, 500] {
+            metrics
+                .first_byte_latency
+                .observe(Duration::from_millis(ms), false);
+        }
+        let resource = OtlpResource {
+            instance: "node \"1\"".to_string(),
+            version: "1.0",
+        };
+        let body = metrics.render_otlp(&resource, 1, 2);
+        for part in [
+            r#"{"key":"service.instance.id","value":{"stringValue":"node \"1\""}}"#,
+            r#"{"key":"service.version","value":{"stringValue":"1.0"}}"#,
+            r#"{"name":"shadow_tls_conn_probe","sum":{"dataPoints":[{"asInt":"3","startTimeUnixNano":"1","timeUnixNano":"2"}],"aggregationTemporality":2,"isMonotonic":true}}"#,
+            r#"{"attributes":[{"key":"fallback","value":{"boolValue":false}}],"startTimeUnixNano":"1","timeUnixNano":"2","count":"3","sum":0.555,"bucketCounts":["1","1","1"],"explicitBounds":[0.01,0.1]}"#,
+        ] {
+            assert!(body.contains(part), "{part} not in {body}");
+        }
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint =
+            parse_otlp_endpoint(&format!("http://{}/otlp", listener.local_addr().unwrap()))
+                .unwrap();
+        let collector = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n{}") {
+                let mut buf = [0; 1024];
+                let n = conn.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        endpoint.post("{}").unwrap();
+        let request = collector.join().unwrap();
+        assert!(request.starts_with("POST /otlp HTTP/1.1\r\n"), "{request}");
+        assert!(request.contains("Content-Type: application/json\r\n"));
+    }
+
+    #[test]
+    fn stats_heartbeat() {
+        static METRICS: Metrics = Metrics::new();
+        let metrics = &METRICS;
+        let _active = [metrics.accept_conn(), metrics.accept_conn()];
+        drop(metrics.accept_conn());
+        metrics.bytes_relayed.fetch_add(1024, Ordering::Relaxed);
+        assert_eq!(
+            metrics.heartbeat(),
+            "stats: 2 active connections, 3 accepted, 1024 bytes relayed"
+        );
+    }
+
+    #[test]
+    fn spawn_heartbeat() {
+        let (logs, _guard) = crate::util::test_util::capture_logs();
+        // the thread lives until the test process exits
+        drop(spawn_stats_heartbeat(Duration::from_millis(50)));
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        while !logs.contents().contains("INFO") && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let contents = logs.contents();
+        assert!(
+            contents
+                .lines()
+                .any(|line| line.contains("INFO") && line.contains("stats: ")),
+            "{contents}"
+        );
+    }
+
+    #[test]
+    fn rate_limit() {
+        let limit = RateLimit::new(2);
+        assert_eq!(limit.check(1), Some(0));
+        assert_eq!(limit.check(1), Some(0));
+        assert_eq!(limit.check(1), None);
+        assert_eq!(limit.check(1), None);
+        assert_eq!(limit.check(2), Some(2));
+    }
+}
@@ -0,0 +1,128 @@
+use std::net::{IpAddr, SocketAddr};
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+const V2_SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+// version 2, PROXY command
+const V2_VERSION_COMMAND: u8 = 0x21;
+// AF_INET/AF_INET6 with STREAM
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// PROXY protocol header format sent to the data server.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyProtocolVersion {
+    /// Human-readable text header
+    V1,
+    /// Binary header
+    V2,
+}
+
+impl std::fmt::Display for ProxyProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::V1 => write!(f, "v1"),
+            Self::V2 => write!(f, "v2"),
+        }
+    }
+}
+
+/// Encode PROXY protocol header of a TCP connection from src to dst.
+/// If families of the two addresses differ, the IPv4 one is mapped to IPv6.
+pub fn encode_header(version: ProxyProtocolVersion, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V6(d)) => (IpAddr::V6(s.to_ipv6_mapped()), IpAddr::V6(d)),
+        (IpAddr::V6(s), IpAddr::V4(d)) => (IpAddr::V6(s), IpAddr::V6(d.to_ipv6_mapped())),
+        ips => ips,
+    };
+    match version {
+        ProxyProtocolVersion::V1 => {
+            let proto = if src_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {proto} {src_ip} {dst_ip} {} {}\r\n",
+                src.port(),
+                dst.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocolVersion::V2 => {
+            let mut buf = Vec::with_capacity(16 + 36);
+            buf.extend_from_slice(&V2_SIGNATURE);
+            buf.push(V2_VERSION_COMMAND);
+            match (src_ip, dst_ip) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => {
+                    buf.push(V2_TCP4);
+                    buf.write_u16::<BigEndian>(12).unwrap();
+                    buf.extend_from_slice(&s.octets());
+                    buf.extend_from_slice(&d.octets());
+                }
+                (IpAddr::V6(s), IpAddr::V6(d)) => {
+                    buf.push(V2_TCP6);
+                    buf.write_u16::<BigEndian>(36).unwrap();
+                    buf.extend_from_slice(&s.octets());
+                    buf.extend_from_slice(&d.octets());
+                }
+                _ => unreachable!("address families are unified"),
+            }
+            buf.write_u16::<BigEndian>(src.port()).unwrap();
+            buf.write_u16::<BigEndian>(dst.port()).unwrap();
+            buf
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1_header() {
+        assert_eq!(
+            encode_header(
+                ProxyProtocolVersion::V1,
+                "192.168.0.1:56324".parse().unwrap(),
+                "10.0.0.2:443".parse().unwrap()
+            ),
+            b"PROXY TCP4 192.168.0.1 10.0.0.2 56324 443\r\n"
+        );
+        assert_eq!(
+            encode_header(
+                ProxyProtocolVersion::V1,
+                "[2001:db8::1]:56324".parse().unwrap(),
+                "[::1]:443".parse().unwrap()
+            ),
+            b"PROXY TCP6 2001:db8::1 ::1 56324 443\r\n"
+        );
+        assert_eq!(
+            encode_header(
+                ProxyProtocolVersion::V1,
+                "192.168.0.1:56324".parse().unwrap(),
+                "[::1]:443".parse().unwrap()
+            ),
+            b"PROXY TCP6 ::ffff:192.168.0.1 ::1 56324 443\r\n"
+        );
+    }
+
+    #[test]
+    fn v2_header() {
+        let header = encode_header(
+            ProxyProtocolVersion::V2,
+            "192.168.0.1:56324".parse().unwrap(),
+            "10.0.0.2:443".parse().unwrap(),
+        );
+        assert_eq!(&header[..12], &V2_SIGNATURE);
+        assert_eq!(
+            &header[12..],
+            &[0x21, 0x11, 0, 12, 192, 168, 0, 1, 10, 0, 0, 2, 0xdc, 0x04, 0x01, 0xbb]
+        );
+        let header = encode_header(
+            ProxyProtocolVersion::V2,
+            "[2001:db8::1]:56324".parse().unwrap(),
+            "[::1]:443".parse().unwrap(),
+        );
+        assert_eq!(header.len(), 16 + 36);
+        assert_eq!(&header[12..16], &[0x21, 0x21, 0, 36]);
+    }
+}
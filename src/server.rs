@@ -1,10 +1,14 @@
 use std::{
     borrow::Cow,
+    cell::Cell,
     collections::VecDeque,
     io::Read,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
     ptr::{copy, copy_nonoverlapping},
     rc::Rc,
-    sync::Arc,
+    sync::{atomic::Ordering, Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
 
 use anyhow::bail;
@@ -13,32 +17,212 @@ use local_sync::oneshot::Sender;
 use monoio::{
     buf::{IoBuf, IoBufMut, Slice, SliceMut},
     io::{
-        AsyncReadRent, AsyncReadRentExt, AsyncWriteRent, AsyncWriteRentExt, PrefixedReadIo,
+        AsyncReadRent, AsyncReadRentExt, AsyncWriteRent, AsyncWriteRentExt, PrefixedReadIo, Split,
         Splitable,
     },
     net::{TcpListener, TcpStream},
 };
+use monoio_rustls_fork_shadow_tls::TlsConnector;
+use rand::Rng;
+use rustls_fork_shadow_tls::{ClientConfig, RootCertStore, ServerName};
 
 use crate::{
+    client::NoCertificateVerification,
     helper_v2::{
         copy_with_application_data, copy_without_application_data, ErrGroup, FirstRetGroup,
         FutureOrOutput, HashedWriteStream, HmacHandler, HMAC_SIZE_V2,
     },
+    metrics::{now_secs, ConnClass, RateLimit, METRICS},
+    proxy_protocol::{encode_header, ProxyProtocolVersion},
     util::{
-        copy_bidirectional, copy_until_eof, kdf, mod_tcp_conn, prelude::*, verified_relay,
-        xor_slice, Hmac,
+        copy_bidirectional, copy_until_eof, kdf, mod_tcp_conn, prelude::*, read_password_file,
+        salted_password, verified_relay, xor_slice, BufferSizes, ByteLimit, ByteLimitMode,
+        ConnLimit, ConnLimitGuard, FirstByteStream, FrozenDns, Hmac, JitterRange, JitterStream,
+        OpTimeout,
     },
 };
 
+#[cfg(target_os = "linux")]
+use crate::util::TcpInfoProbe;
+
 /// ShadowTlsServer.
 #[derive(Clone)]
 pub struct ShadowTlsServer<LA, TA> {
     listen_addr: Arc<LA>,
     target_addr: Arc<TA>,
-    tls_addr: Arc<TlsAddrs>,
-    password: Arc<String>,
-    nodelay: bool,
-    v3: bool,
+    tls_addr: TlsAddrsHandle,
+    passwords: PasswordsHandle,
+    replay_cache: Option<Arc<ReplayCache>>,
+    handshake_limit: Option<Arc<ConnLimit>>,
+    pending_limit: Option<Arc<ConnLimit>>,
+    opts: ServerOpts,
+}
+
+/// Options of ShadowTlsServer.
+#[derive(Clone, Debug, Default)]
+pub struct ServerOpts {
+    pub nodelay: bool,
+    pub v3: bool,
+    pub close_notify: CloseNotifyMode,
+    /// Choose handshake server randomly when SNI does not match any.
+    pub random_handshake: bool,
+    /// Time limit for every single read in data relay.
+    pub read_timeout: Option<Duration>,
+    /// Time limit for every single write in data relay.
+    pub write_timeout: Option<Duration>,
+    /// If set, data server connections are only allowed to these addresses.
+    pub backend_allowlist: Option<AddrAllowlist>,
+    /// If set, send PROXY protocol header to the data server.
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    /// If set, authenticated ClientHello seen again within this window is
+    /// treated as replay(V3 only).
+    pub replay_window: Option<Duration>,
+    /// Log TCP_INFO of data server connections when closing(Linux only).
+    pub tcp_info: bool,
+    /// Close the connection after relaying this many bytes, 0 means unlimited.
+    pub max_bytes_per_conn: u64,
+    pub max_bytes_mode: ByteLimitMode,
+    /// Handle authenticated clients like probes if the data server is down.
+    pub backend_down_fallback: bool,
+    /// Random delay before shutting down the client connection.
+    pub close_jitter: Option<JitterRange>,
+    /// If set, probes are dropped when concurrent probes relayed to the
+    /// handshake server reach it. Authenticated clients are neither counted
+    /// nor dropped(V3 only).
+    pub max_handshake_conns: Option<usize>,
+    /// Time limit for connecting the handshake server.
+    pub handshake_connect_timeout: Option<Duration>,
+    /// Time limit for the TLS handshake after the handshake server connected,
+    /// the relayed session of a probe is not limited.
+    pub handshake_timeout: Option<Duration>,
+    /// If set, accepting is deferred when connections not yet authenticated
+    /// or classified as probe reach it.
+    pub max_pending_handshakes: Option<usize>,
+    /// Random delay before forwarding probes to the handshake server, like
+    /// the latency of a real server(V3 only).
+    pub probe_delay: Option<JitterRange>,
+    /// Handshake server names resolved at startup, names not in it are
+    /// resolved on every connect.
+    pub frozen_dns: Arc<FrozenDns>,
+    /// If set, ports handshake servers other than the fallback may listen on.
+    pub allowed_handshake_ports: Option<Vec<u16>>,
+    /// TCP_USER_TIMEOUT of client, handshake server and data server
+    /// connections if set(Linux only).
+    pub user_timeout: Option<Duration>,
+    /// Forward all connections to the handshake server without
+    /// authentication, for observing the cover site through this proxy.
+    pub handshake_passthrough: bool,
+    /// Socket and relay buffer sizes of each direction.
+    pub buffers: BufferSizes,
+}
+
+/// Time limit of checking each handshake server at startup, if
+/// handshake_timeout is not set.
+const HANDSHAKE_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Probes checked per second for a v2 client, the rest are relayed directly.
+static MISMATCH_CHECK_LIMIT: RateLimit = RateLimit::new(1);
+
+/// Record time from accept to the first byte sent to the client, if any is
+/// sent.
+fn observe_first_byte(accepted_at: Instant, sent_at: &Cell<Option<Instant>>, fallback: bool) {
+    if let Some(sent_at) = sent_at.get() {
+        METRICS
+            .first_byte_latency
+            .observe(sent_at.saturating_duration_since(accepted_at), fallback);
+    }
+}
+
+/// Retry interval of accepting when pending handshakes are too many.
+const PENDING_DEFER_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Mark the connection in handshake phase until dropped.
+struct PendingHandshake(Option<ConnLimitGuard>);
+
+impl PendingHandshake {
+    fn new(slot: Option<ConnLimitGuard>) -> Self {
+        METRICS.pending_handshakes.fetch_add(1, Ordering::Relaxed);
+        Self(slot)
+    }
+}
+
+impl Drop for PendingHandshake {
+    fn drop(&mut self) {
+        METRICS.pending_handshakes.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Deadline of the TLS handshake phase, started when the handshake server is
+/// connected.
+#[derive(Clone, Copy)]
+struct HandshakeDeadline(Option<(monoio::time::Instant, Duration)>);
+
+impl HandshakeDeadline {
+    fn start(timeout: Option<Duration>) -> Self {
+        Self(timeout.map(|t| (monoio::time::Instant::now() + t, t)))
+    }
+
+    async fn run<F, T, E>(self, f: F) -> anyhow::Result<T>
+    where
+        F: std::future::Future<Output = Result<T, E>>,
+        E: Into<anyhow::Error>,
+    {
+        match self.0 {
+            Some((deadline, timeout)) => monoio::time::timeout_at(deadline, f)
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!("handshake timeout: TLS handshake not finished in {timeout:?}")
+                })?
+                .map_err(Into::into),
+            None => f.await.map_err(Into::into),
+        }
+    }
+
+    /// Like run, but once finished is set the deadline no longer applies and
+    /// f runs to the end.
+    async fn run_until<F, T, E>(self, f: F, finished: &Cell<bool>) -> anyhow::Result<T>
+    where
+        F: std::future::Future<Output = Result<T, E>>,
+        E: Into<anyhow::Error>,
+    {
+        let mut f = std::pin::pin!(f);
+        match self.run(async { Ok::<_, E>(f.as_mut().await) }).await {
+            Ok(res) => res.map_err(Into::into),
+            Err(_) if finished.get() => f.await.map_err(Into::into),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// How to relay TLS alerts(like close_notify) sent by the handshake server
+/// during the handshake phase.
+///
+/// Only used by V3 protocol. Note that in TLS1.3 alerts after ServerHello are
+/// encrypted and look like application data, so only plaintext alert records
+/// are affected.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CloseNotifyMode {
+    /// Relay alerts as is. It is exactly what the handshake server does,
+    /// so it is the most consistent with a real session.
+    #[default]
+    Forward,
+    /// Drop alerts. The client's tls library never sees them, but the
+    /// connection is closed without any alert which a real server rarely does.
+    Suppress,
+    /// Replace alerts with a warning level close_notify. The client's tls library
+    /// treats it as a clean shutdown, but the alert differs from what the
+    /// handshake server really sent.
+    Synthesize,
+}
+
+impl std::fmt::Display for CloseNotifyMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Forward => write!(f, "forward"),
+            Self::Suppress => write!(f, "suppress"),
+            Self::Synthesize => write!(f, "synthesize"),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -48,6 +232,27 @@ pub struct TlsAddrs {
 }
 
 impl TlsAddrs {
+    /// All handshake server addresses.
+    pub fn addrs(&self) -> impl Iterator<Item = &str> {
+        self.dispatch
+            .values()
+            .chain(std::iter::once(&self.fallback))
+            .map(AsRef::as_ref)
+    }
+
+    /// SNI and address of all handshake servers, the SNI of fallback is its
+    /// host.
+    fn targets(&self) -> impl Iterator<Item = (&str, &str)> {
+        let fallback_host = self
+            .fallback
+            .rsplit_once(':')
+            .map_or(self.fallback.as_str(), |(host, _)| host);
+        self.dispatch
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .chain(std::iter::once((fallback_host, self.fallback.as_str())))
+    }
+
     fn find(&self, key: Option<&str>) -> &str {
         match key {
             Some(k) => self.dispatch.get(k).unwrap_or(&self.fallback),
@@ -55,8 +260,67 @@ impl TlsAddrs {
         }
     }
 
-    fn is_empty(&self) -> bool {
-        self.dispatch.is_empty()
+    /// Find by key, or choose one randomly from all addrs if not found.
+    fn find_or_random(&self, key: Option<&str>) -> &str {
+        match key.and_then(|k| self.dispatch.get(k)) {
+            Some(addr) => addr,
+            None => self.random_choose(),
+        }
+    }
+
+    fn random_choose(&self) -> &str {
+        let idx = rand::thread_rng().gen_range(0..=self.dispatch.len());
+        self.dispatch
+            .values()
+            .nth(idx)
+            .map(AsRef::as_ref)
+            .unwrap_or(&self.fallback)
+    }
+
+    /// Format as the command line argument it is parsed from.
+    pub fn to_arg(&self) -> String {
+        let mut parts: Vec<_> = self
+            .dispatch
+            .iter()
+            .map(|(k, v)| format!("{k}:{v}"))
+            .collect();
+        parts.sort_unstable();
+        parts.push(self.fallback.clone());
+        parts.join(";")
+    }
+
+    /// Remove dispatch entries whose port is not allowed, so they use the
+    /// fallback. The fallback is kept since there is nothing to replace it,
+    /// but warned if its port is not allowed.
+    fn retain_allowed_ports(&mut self, ports: &[u16]) {
+        let allowed = |addr: &str| {
+            addr.rsplit_once(':')
+                .and_then(|(_, p)| p.parse::<u16>().ok())
+                .map_or(false, |port| ports.contains(&port))
+        };
+        let fallback = &self.fallback;
+        if !allowed(fallback) {
+            tracing::warn!(
+                "fallback handshake server {fallback} is not on an allowed port, it is still used"
+            );
+        }
+        self.dispatch.retain(|key, addr| {
+            let allowed = allowed(addr);
+            if !allowed {
+                tracing::warn!(
+                    "handshake server {addr} is not on an allowed port, {key} uses {fallback} instead"
+                );
+            }
+            allowed
+        });
+    }
+
+    /// Choose handshake server addr for the given SNI.
+    fn choose(&self, key: Option<&str>, random: bool) -> &str {
+        match random {
+            true => self.find_or_random(key),
+            false => self.find(key),
+        }
     }
 }
 
@@ -121,46 +385,488 @@ impl std::fmt::Display for TlsAddrs {
     }
 }
 
+/// IP networks which are allowed to connect to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AddrAllowlist(Vec<(IpAddr, u8)>);
+
+impl AddrAllowlist {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|&(net, prefix)| match (net, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        })
+    }
+}
+
+impl TryFrom<&str> for AddrAllowlist {
+    type Error = anyhow::Error;
+
+    fn try_from(arg: &str) -> Result<Self, Self::Error> {
+        let mut nets = Vec::new();
+        for p in arg.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (ip, prefix) = match p.split_once('/') {
+                Some((ip, prefix)) => (ip, Some(prefix)),
+                None => (p, None),
+            };
+            let ip: IpAddr = ip
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid ip in allowlist: {p}"))?;
+            let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                Some(prefix) => match prefix.parse::<u8>() {
+                    Ok(prefix) if prefix <= max_prefix => prefix,
+                    _ => bail!("invalid prefix length in allowlist: {p}"),
+                },
+                None => max_prefix,
+            };
+            nets.push((ip, prefix));
+        }
+        if nets.is_empty() {
+            bail!("empty allowlist");
+        }
+        Ok(AddrAllowlist(nets))
+    }
+}
+
+pub fn parse_allowlist(arg: &str) -> anyhow::Result<AddrAllowlist> {
+    AddrAllowlist::try_from(arg)
+}
+
+impl std::fmt::Display for AddrAllowlist {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, (ip, prefix)) in self.0.iter().enumerate() {
+            if idx != 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{ip}/{prefix}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Recently seen ClientHello randoms, shared by all worker threads.
+pub struct ReplayCache {
+    window: Duration,
+    seen: Mutex<SeenRandoms>,
+}
+
+#[derive(Default)]
+struct SeenRandoms {
+    set: rustc_hash::FxHashSet<[u8; TLS_RANDOM_SIZE]>,
+    // in insertion order, for expiring
+    queue: VecDeque<(Instant, [u8; TLS_RANDOM_SIZE])>,
+}
+
+impl ReplayCache {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Default::default(),
+        }
+    }
+
+    /// Record the random, return true if it has been seen within the window.
+    pub fn check_and_insert(&self, random: [u8; TLS_RANDOM_SIZE], now: Instant) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        while let Some((t, r)) = seen.queue.front().copied() {
+            if now.duration_since(t) < self.window {
+                break;
+            }
+            seen.set.remove(&r);
+            seen.queue.pop_front();
+        }
+        if !seen.set.insert(random) {
+            return true;
+        }
+        seen.queue.push_back((now, random));
+        false
+    }
+}
+
+/// Shared TlsAddrs which can be replaced at runtime.
+/// Every connection takes a snapshot, so replacing only affects new connections.
+///
+/// If allowed ports are set, dispatch entries on other ports are removed
+/// whenever the list is stored.
+#[derive(Clone)]
+pub struct TlsAddrsHandle {
+    tls_addr: Arc<RwLock<Arc<TlsAddrs>>>,
+    allowed_ports: Option<Arc<[u16]>>,
+}
+
+impl TlsAddrsHandle {
+    pub fn new(mut tls_addr: TlsAddrs, allowed_ports: Option<Vec<u16>>) -> Self {
+        let allowed_ports: Option<Arc<[u16]>> = allowed_ports.map(Into::into);
+        if let Some(ports) = &allowed_ports {
+            tls_addr.retain_allowed_ports(ports);
+        }
+        Self {
+            tls_addr: Arc::new(RwLock::new(Arc::new(tls_addr))),
+            allowed_ports,
+        }
+    }
+
+    pub fn load(&self) -> Arc<TlsAddrs> {
+        self.tls_addr.read().unwrap().clone()
+    }
+
+    pub fn store(&self, mut tls_addr: TlsAddrs) {
+        if let Some(ports) = &self.allowed_ports {
+            tls_addr.retain_allowed_ports(ports);
+        }
+        *self.tls_addr.write().unwrap() = Arc::new(tls_addr);
+    }
+}
+
+/// Accepted passwords.
+#[derive(Debug, PartialEq)]
+pub struct Passwords {
+    current: String,
+    /// The password before rotation, valid until the instant.
+    previous: Option<(String, Instant)>,
+}
+
+impl Passwords {
+    fn current(&self) -> &str {
+        &self.current
+    }
+
+    /// The previous password if it is still in grace window.
+    fn previous(&self, now: Instant) -> Option<&str> {
+        match &self.previous {
+            Some((password, until)) if now < *until => Some(password),
+            _ => None,
+        }
+    }
+}
+
+/// Shared Passwords which can be rotated at runtime.
+#[derive(Clone)]
+pub struct PasswordsHandle(Arc<RwLock<Arc<Passwords>>>);
+
+impl PasswordsHandle {
+    pub fn new(password: String) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(Passwords {
+            current: password,
+            previous: None,
+        }))))
+    }
+
+    pub fn load(&self) -> Arc<Passwords> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Switch to the new password and keep the current one valid for grace.
+    /// Return false if the password is not changed.
+    pub fn rotate(&self, password: String, grace: Duration, now: Instant) -> bool {
+        let mut guard = self.0.write().unwrap();
+        if guard.current == password {
+            return false;
+        }
+        *guard = Arc::new(Passwords {
+            current: password,
+            previous: Some((guard.current.clone(), now + grace)),
+        });
+        true
+    }
+}
+
+/// Password file to watch.
+pub struct PasswordFile {
+    pub path: PathBuf,
+    pub salt: Option<String>,
+    /// How long the replaced password is still accepted.
+    pub grace: Duration,
+}
+
+impl PasswordFile {
+    /// Read the file and rotate the password if changed.
+    fn refresh(&self, handle: &PasswordsHandle, now: Instant) -> anyhow::Result<bool> {
+        let password = salted_password(read_password_file(&self.path)?, self.salt.as_deref());
+        Ok(handle.rotate(password, self.grace, now))
+    }
+}
+
+/// Spawn a thread to poll the password file.
+pub fn spawn_password_file_watcher(
+    file: PasswordFile,
+    handle: PasswordsHandle,
+) -> std::thread::JoinHandle<()> {
+    const POLL_INTERVAL: Duration = Duration::from_secs(5);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(POLL_INTERVAL);
+        match file.refresh(&handle, Instant::now()) {
+            Ok(true) => tracing::info!(
+                "password rotated from {}, the old one is valid for {}s",
+                file.path.display(),
+                file.grace.as_secs()
+            ),
+            Ok(false) => (),
+            Err(e) => tracing::warn!("password file read failed, keep the current one: {e}"),
+        }
+    })
+}
+
+/// Run the command with `sh -c` and parse its stdout as server addrs.
+fn load_tls_addrs_from_cmd(cmd: &str) -> anyhow::Result<TlsAddrs> {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .output()?;
+    if !output.status.success() {
+        bail!("handshake source command exited with {}", output.status);
+    }
+    let stdout = String::from_utf8(output.stdout)?;
+    parse_server_addrs(stdout.trim())
+}
+
+/// Refresh handle with the output of the command.
+/// If the command fails or the output is invalid, the current list is kept.
+pub fn refresh_tls_addrs(cmd: &str, handle: &TlsAddrsHandle) -> anyhow::Result<()> {
+    let tls_addr = load_tls_addrs_from_cmd(cmd)?;
+    tracing::info!("handshake server list refreshed: {tls_addr}");
+    handle.store(tls_addr);
+    Ok(())
+}
+
+/// Spawn a thread to refresh handshake server list periodically.
+pub fn spawn_tls_addrs_refresher(
+    cmd: String,
+    interval: Duration,
+    handle: TlsAddrsHandle,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || loop {
+        if let Err(e) = refresh_tls_addrs(&cmd, &handle) {
+            tracing::warn!("handshake server list refresh failed, keep the current one: {e}");
+        }
+        std::thread::sleep(interval);
+    })
+}
+
 impl<LA, TA> ShadowTlsServer<LA, TA> {
     pub fn new(
         listen_addr: LA,
         target_addr: TA,
         tls_addr: TlsAddrs,
         password: String,
-        nodelay: bool,
-        v3: bool,
+        opts: ServerOpts,
     ) -> Self {
         Self {
             listen_addr: Arc::new(listen_addr),
             target_addr: Arc::new(target_addr),
-            tls_addr: Arc::new(tls_addr),
-            password: Arc::new(password),
-            nodelay,
-            v3,
+            tls_addr: TlsAddrsHandle::new(tls_addr, opts.allowed_handshake_ports.clone()),
+            passwords: PasswordsHandle::new(password),
+            replay_cache: opts.replay_window.map(|w| Arc::new(ReplayCache::new(w))),
+            handshake_limit: opts.max_handshake_conns.map(ConnLimit::new),
+            pending_limit: opts.max_pending_handshakes.map(ConnLimit::new),
+            opts,
+        }
+    }
+
+    pub fn tls_addrs_handle(&self) -> TlsAddrsHandle {
+        self.tls_addr.clone()
+    }
+
+    pub fn passwords_handle(&self) -> PasswordsHandle {
+        self.passwords.clone()
+    }
+
+    /// Build PROXY protocol header for the connection if enabled.
+    fn proxy_header(&self, conn: &TcpStream) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.opts.proxy_protocol {
+            Some(version) => Ok(Some(encode_header(
+                version,
+                conn.peer_addr()?,
+                conn.local_addr()?,
+            ))),
+            None => Ok(None),
         }
     }
+
+    #[cfg(target_os = "linux")]
+    fn tcp_info_probe(&self, conn: &TcpStream, addr: SocketAddr) -> Option<TcpInfoProbe> {
+        self.opts
+            .tcp_info
+            .then(|| TcpInfoProbe::new(conn, format!("Data server connection for {addr}")))
+            .flatten()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn tcp_info_probe(&self, _conn: &TcpStream, _addr: SocketAddr) -> Option<()> {
+        None
+    }
+
+    /// Connect handshake server, bounded by handshake_connect_timeout.
+    async fn connect_handshake_server(&self, addr: &str) -> anyhow::Result<TcpStream> {
+        let addrs = self.opts.frozen_dns.lookup(addr)?;
+        let connect = TcpStream::connect(addrs.as_slice());
+        let mut stream = match self.opts.handshake_connect_timeout {
+            Some(timeout) => monoio::time::timeout(timeout, connect)
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!(
+                        "handshake server connect timeout: {addr} not connected in {timeout:?}"
+                    )
+                })??,
+            None => connect.await?,
+        };
+        mod_tcp_conn(
+            &mut stream,
+            true,
+            self.opts.nodelay,
+            self.opts.user_timeout,
+            self.opts.buffers,
+        );
+        tracing::debug!("handshake server connected: {addr}");
+        Ok(stream)
+    }
+
+    /// Check that at least one handshake server completes a TLS handshake,
+    /// its certificate is not verified.
+    pub async fn check_handshake_servers(&self) -> anyhow::Result<()> {
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoCertificateVerification));
+        let connector = TlsConnector::from(Arc::new(config));
+        let timeout = self
+            .opts
+            .handshake_timeout
+            .unwrap_or(HANDSHAKE_CHECK_TIMEOUT);
+        let tls_addr = self.tls_addr.load();
+        let mut errors = Vec::new();
+        for (sni, addr) in tls_addr.targets() {
+            let check = async {
+                let server_name = ServerName::try_from(sni)
+                    .map_err(|_| anyhow::anyhow!("invalid server name {sni}"))?;
+                let stream = self.connect_handshake_server(addr).await?;
+                let mut tls_stream = connector.connect(server_name, stream).await?;
+                let _ = tls_stream.shutdown().await;
+                anyhow::Ok(())
+            };
+            match monoio::time::timeout(timeout, check).await {
+                Ok(Ok(_)) => {
+                    tracing::info!("handshake server {addr} completed a TLS handshake");
+                    return Ok(());
+                }
+                Ok(Err(e)) => errors.push(format!("{addr}({e})")),
+                Err(_) => errors.push(format!("{addr}(timeout)")),
+            }
+        }
+        bail!(
+            "no handshake server completes a TLS handshake: {}",
+            errors.join(", ")
+        )
+    }
+
+    /// Connect data server, refuse addresses not in the allowlist.
+    /// The proxy header is sent first if given.
+    async fn connect_data_server(&self, proxy_header: Option<Vec<u8>>) -> anyhow::Result<TcpStream>
+    where
+        TA: ToSocketAddrs,
+    {
+        let addrs: Vec<SocketAddr> = self.target_addr.to_socket_addrs()?.collect();
+        let addrs = match &self.opts.backend_allowlist {
+            Some(allowlist) => filter_allowed(addrs, allowlist)?,
+            None => addrs,
+        };
+        let mut data_stream = TcpStream::connect(addrs.as_slice()).await?;
+        mod_tcp_conn(
+            &mut data_stream,
+            true,
+            self.opts.nodelay,
+            self.opts.user_timeout,
+            self.opts.buffers,
+        );
+        if let Some(header) = proxy_header {
+            let (res, _) = data_stream.write_all(header).await;
+            res?;
+        }
+        Ok(data_stream)
+    }
+}
+
+fn filter_allowed(
+    addrs: Vec<SocketAddr>,
+    allowlist: &AddrAllowlist,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let (allowed, refused): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| allowlist.contains(a.ip()));
+    if !refused.is_empty() {
+        tracing::warn!("data server addresses not in allowlist refused: {refused:?}");
+    }
+    if allowed.is_empty() {
+        bail!("no data server address is allowed");
+    }
+    Ok(allowed)
 }
 
 impl<LA, TA> ShadowTlsServer<LA, TA> {
-    /// Serve a raw connection.
-    pub async fn serve(self) -> anyhow::Result<()>
+    /// Bind the listen address.
+    pub fn bind(&self) -> anyhow::Result<TcpListener>
     where
-        LA: std::net::ToSocketAddrs + 'static,
-        TA: std::net::ToSocketAddrs + 'static,
+        LA: std::net::ToSocketAddrs,
     {
         let listener = TcpListener::bind(self.listen_addr.as_ref())
             .map_err(|e| anyhow::anyhow!("bind failed, check if the port is used: {e}"))?;
+        #[cfg(unix)]
+        if let Err(e) = self
+            .opts
+            .buffers
+            .apply(std::os::unix::io::AsRawFd::as_raw_fd(&listener))
+        {
+            tracing::warn!("unable to set socket buffer sizes of listener: {e}");
+        }
+        Ok(listener)
+    }
+
+    /// Serve raw connections from the listener.
+    pub async fn serve(self, listener: TcpListener) -> anyhow::Result<()>
+    where
+        LA: 'static,
+        TA: std::net::ToSocketAddrs + 'static,
+    {
         let shared = Rc::new(self);
         loop {
+            // connections wait in the backlog if too many are handshaking
+            let slot = match &shared.pending_limit {
+                Some(limit) => loop {
+                    match limit.try_acquire() {
+                        Some(slot) => break Some(slot),
+                        None => monoio::time::sleep(PENDING_DEFER_INTERVAL).await,
+                    }
+                },
+                None => None,
+            };
             match listener.accept().await {
                 Ok((mut conn, addr)) => {
                     tracing::info!("Accepted a connection from {addr}");
                     let server = shared.clone();
-                    mod_tcp_conn(&mut conn, true, shared.nodelay);
+                    let pending = PendingHandshake::new(slot);
+                    let active = METRICS.accept_conn();
+                    mod_tcp_conn(
+                        &mut conn,
+                        true,
+                        shared.opts.nodelay,
+                        shared.opts.user_timeout,
+                        shared.opts.buffers,
+                    );
                     monoio::spawn(async move {
-                        let _ = match server.v3 {
-                            false => server.relay_v2(conn).await,
-                            true => server.relay_v3(conn).await,
+                        let _active = active;
+                        let _ = match (server.opts.handshake_passthrough, server.opts.v3) {
+                            (true, _) => server.relay_passthrough(conn, addr, pending).await,
+                            (false, false) => server.relay_v2(conn, addr, pending).await,
+                            (false, true) => server.relay_v3(conn, addr, pending).await,
                         };
                         tracing::info!("Relay for {addr} finished");
                     });
@@ -172,111 +878,298 @@ impl<LA, TA> ShadowTlsServer<LA, TA> {
         }
     }
 
+    /// Forward the connection verbatim to the handshake server chosen by SNI,
+    /// nothing is authenticated or relayed to the data server.
+    async fn relay_passthrough(
+        &self,
+        mut in_stream: TcpStream,
+        addr: SocketAddr,
+        pending: PendingHandshake,
+    ) -> anyhow::Result<()> {
+        let (prefix, server_name) = extract_sni_v2(&mut in_stream).await?;
+        let server_name = server_name.and_then(|s| String::from_utf8(s).ok());
+        let tls_addr = self.tls_addr.load();
+        let handshake_addr = tls_addr.choose(server_name.as_deref(), self.opts.random_handshake);
+        let mut out_stream = self.connect_handshake_server(handshake_addr).await?;
+        drop(pending);
+        tracing::debug!("pass {addr} through to handshake server {handshake_addr}");
+        let (res, _) = out_stream.write_all(prefix).await;
+        res?;
+        copy_bidirectional(&mut in_stream, &mut out_stream).await;
+        Ok(())
+    }
+
     /// Main relay for V2 protocol.
-    async fn relay_v2(&self, in_stream: TcpStream) -> anyhow::Result<()>
+    async fn relay_v2(
+        &self,
+        in_stream: TcpStream,
+        addr: SocketAddr,
+        pending: PendingHandshake,
+    ) -> anyhow::Result<()>
     where
         TA: std::net::ToSocketAddrs,
     {
+        let accepted_at = Instant::now();
+        let proxy_header = self.proxy_header(&in_stream)?;
         // wrap in_stream with hash layer
-        let mut in_stream = HashedWriteStream::new(in_stream, self.password.as_bytes())?;
+        let passwords = self.passwords.load();
+        // clients still on the previous password are accepted in grace window
+        let accepted: Vec<_> = std::iter::once(passwords.current())
+            .chain(passwords.previous(Instant::now()))
+            .map(str::as_bytes)
+            .collect();
+        let in_stream = FirstByteStream::new(in_stream);
+        let sent_at = in_stream.sent_at();
+        let mut in_stream = HashedWriteStream::with_passwords(in_stream, &accepted)?;
         let mut hmac = in_stream.hmac_handler();
 
         // read and extract server name
-        // if there is only one fallback server, skip it
-        let (prefix, server_name) = match self.tls_addr.is_empty() {
-            true => (Vec::new(), None),
-            false => extract_sni_v2(&mut in_stream).await?,
-        };
-        let mut prefixed_io = PrefixedReadIo::new(&mut in_stream, std::io::Cursor::new(prefix));
+        let tls_addr = self.tls_addr.load();
+        let (prefix, server_name) = extract_sni_v2(&mut in_stream).await?;
+        let mut prefixed_io =
+            PrefixedReadIo::new(&mut in_stream, std::io::Cursor::new(prefix.as_slice()));
         tracing::debug!("server name extracted from SNI extention: {server_name:?}");
 
         // choose handshake server addr and connect
         let server_name = server_name.and_then(|s| String::from_utf8(s).ok());
-        let addr = self.tls_addr.find(server_name.as_ref().map(AsRef::as_ref));
-        let mut out_stream = TcpStream::connect(addr).await?;
-        mod_tcp_conn(&mut out_stream, true, self.nodelay);
-        tracing::debug!("handshake server connected: {addr}");
+        let handshake_addr = tls_addr.choose(
+            server_name.as_ref().map(AsRef::as_ref),
+            self.opts.random_handshake,
+        );
+        let mut out_stream = self.connect_handshake_server(handshake_addr).await?;
+        let deadline = HandshakeDeadline::start(self.opts.handshake_timeout);
 
         // copy stage 1
         let (mut out_r, mut out_w) = out_stream.split();
         let (mut in_r, mut in_w) = prefixed_io.split();
-        let (switch, cp) = FirstRetGroup::new(
-            copy_until_handshake_finished(&mut in_r, &mut out_w, &hmac),
-            Box::pin(copy_until_eof(&mut out_r, &mut in_w)),
-        )
-        .await?;
+        // a probe is relayed like the handshake server after handshake frames
+        let handshake_done = Cell::new(false);
+        let stage1 = deadline
+            .run_until(
+                FirstRetGroup::new(
+                    copy_until_handshake_finished(&mut in_r, &mut out_w, &hmac, &handshake_done),
+                    Box::pin(copy_until_eof(&mut out_r, &mut in_w)),
+                ),
+                &handshake_done,
+            )
+            .await;
+        if stage1.is_err() {
+            observe_first_byte(accepted_at, &sent_at, true);
+        }
+        let (switch, cp) = stage1?;
         hmac.disable();
+        drop(pending);
         tracing::debug!("handshake finished, switch: {switch:?}");
 
         // copy stage 2
         match switch {
             SwitchResult::Switch(data_left) => {
+                // connect our data server
+                let data_stream = match self.connect_data_server(proxy_header).await {
+                    Ok(data_stream) => data_stream,
+                    Err(e) if self.opts.backend_down_fallback => {
+                        tracing::warn!("data server unavailable: {e}");
+                        METRICS.record_conn(ConnClass::BackendDown, addr);
+                        let relay = async {
+                            let (res, _) = out_w.write_all(data_left).await;
+                            res?;
+                            match cp {
+                                FutureOrOutput::Future(cp) => {
+                                    ErrGroup::new(cp, copy_until_eof(in_r, out_w)).await?;
+                                }
+                                FutureOrOutput::Output(_) => {
+                                    copy_until_eof(in_r, out_w).await?;
+                                }
+                            }
+                            Ok(())
+                        };
+                        let res = relay.await;
+                        observe_first_byte(accepted_at, &sent_at, true);
+                        return res;
+                    }
+                    Err(e) => return Err(e),
+                };
+                METRICS.record_conn(ConnClass::Authed, addr);
+                observe_first_byte(accepted_at, &sent_at, false);
                 drop(cp);
-                let mut in_stream = in_stream.into_inner();
+                let op_timeout = OpTimeout::new(self.opts.read_timeout, self.opts.write_timeout);
+                let in_stream =
+                    JitterStream::new(in_stream.into_inner().into_inner(), self.opts.close_jitter);
+                let mut in_stream = op_timeout.wrap(in_stream);
                 let (mut in_r, mut in_w) = in_stream.split();
-
-                // connect our data server
                 let _ = out_stream.shutdown().await;
                 drop(out_stream);
-                let mut data_stream = TcpStream::connect(self.target_addr.as_ref()).await?;
-                mod_tcp_conn(&mut data_stream, true, self.nodelay);
+                let _tcp_info = self.tcp_info_probe(&data_stream, addr);
                 tracing::debug!("data server connected, start relay");
+                let byte_limit =
+                    ByteLimit::new(self.opts.max_bytes_per_conn, self.opts.max_bytes_mode);
+                let mut data_stream = op_timeout.wrap(byte_limit.wrap(data_stream));
                 let (mut data_r, mut data_w) = data_stream.split();
-                let (result, _) = data_w.write(data_left).await;
-                result?;
-                ErrGroup::new(
-                    copy_with_application_data::<0, _, _>(&mut data_r, &mut in_w, None),
-                    copy_without_application_data(&mut in_r, &mut data_w),
-                )
-                .await?;
-            }
-            SwitchResult::DirectProxy => match cp {
-                FutureOrOutput::Future(cp) => {
-                    ErrGroup::new(cp, copy_until_eof(in_r, out_w)).await?;
-                }
-                FutureOrOutput::Output(_) => {
-                    copy_until_eof(in_r, out_w).await?;
+                let relay = async {
+                    let (result, _) = data_w.write_all(data_left).await;
+                    result?;
+                    ErrGroup::new(
+                        copy_with_application_data::<0, _, _>(
+                            &mut data_r,
+                            &mut in_w,
+                            None,
+                            self.opts.buffers,
+                        ),
+                        copy_without_application_data(&mut in_r, &mut data_w, self.opts.buffers),
+                    )
+                    .await
+                };
+                match byte_limit.guard(op_timeout.guard(relay)).await {
+                    Some(Some(r)) => {
+                        r?;
+                    }
+                    Some(None) => bail!("data relay timeout"),
+                    None => (),
+                };
+            }
+            SwitchResult::DirectProxy => {
+                METRICS.record_conn(ConnClass::Probe, addr);
+                if verified_extract_sni(&prefix, passwords.current()).0 {
+                    tracing::warn!(
+                        "client {addr} appears to use protocol v3 but server is v2, check v3 option of both sides"
+                    );
                 }
-            },
+                let relay = async {
+                    match cp {
+                        FutureOrOutput::Future(cp) => {
+                            ErrGroup::new(cp, copy_until_eof(in_r, out_w)).await?;
+                        }
+                        FutureOrOutput::Output(_) => {
+                            copy_until_eof(in_r, out_w).await?;
+                        }
+                    }
+                    Ok::<_, std::io::Error>(())
+                };
+                let res = relay.await;
+                observe_first_byte(accepted_at, &sent_at, true);
+                res?;
+            }
         }
         Ok(())
     }
 
     /// Main relay for V3 protocol.
-    async fn relay_v3(&self, mut in_stream: TcpStream) -> anyhow::Result<()>
+    async fn relay_v3(
+        &self,
+        mut in_stream: TcpStream,
+        addr: SocketAddr,
+        pending: PendingHandshake,
+    ) -> anyhow::Result<()>
     where
         TA: std::net::ToSocketAddrs,
     {
+        let accepted_at = Instant::now();
+        let proxy_header = self.proxy_header(&in_stream)?;
         // stage 1.1: read and validate client hello
         let first_client_frame = read_exact_frame(&mut in_stream).await?;
-        let (client_hello_pass, sni) = verified_extract_sni(&first_client_frame, &self.password);
+        let passwords = self.passwords.load();
+        let mut password = passwords.current();
+        let (mut client_hello_pass, sni) = verified_extract_sni(&first_client_frame, password);
+        if !client_hello_pass {
+            if let Some(previous) = passwords.previous(Instant::now()) {
+                if verified_extract_sni(&first_client_frame, previous).0 {
+                    tracing::debug!("ClientHello verified with the previous password");
+                    (password, client_hello_pass) = (previous, true);
+                }
+            }
+        }
+        let mut replayed = false;
+        if let (true, Some(cache)) = (client_hello_pass, &self.replay_cache) {
+            let mut random = [0; TLS_RANDOM_SIZE];
+            random.copy_from_slice(
+                &first_client_frame[SERVER_RANDOM_IDX..SERVER_RANDOM_IDX + TLS_RANDOM_SIZE],
+            );
+            // treat replayed one as probe, the handshake server will answer it
+            replayed = cache.check_and_insert(random, Instant::now());
+            client_hello_pass = !replayed;
+        }
+
+        // probes are dropped if relayed probes are too many
+        let handshake_slot = match (&self.handshake_limit, client_hello_pass) {
+            (Some(_), true) | (None, _) => None,
+            (Some(limit), false) => match limit.try_acquire() {
+                Some(slot) => Some(slot),
+                None => {
+                    tracing::debug!("too many probes relayed, drop probe from {addr}");
+                    METRICS.probe_dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+            },
+        };
+
+        // delay probes only, authenticated clients are never slowed down
+        if let (false, Some(delay)) = (client_hello_pass, self.opts.probe_delay) {
+            monoio::time::sleep(delay.sample()).await;
+        }
 
         // connect handshake server
         let server_name = sni.and_then(|s| String::from_utf8(s).ok());
-        let addr = self.tls_addr.find(server_name.as_ref().map(AsRef::as_ref));
-        let mut handshake_stream = TcpStream::connect(addr).await?;
-        mod_tcp_conn(&mut handshake_stream, true, self.nodelay);
-        tracing::debug!("handshake server connected: {addr}");
+        let tls_addr = self.tls_addr.load();
+        let handshake_addr = tls_addr.choose(
+            server_name.as_ref().map(AsRef::as_ref),
+            self.opts.random_handshake,
+        );
+        let mut handshake_stream = self.connect_handshake_server(handshake_addr).await?;
+        let deadline = HandshakeDeadline::start(self.opts.handshake_timeout);
 
-        let (res, _) = handshake_stream.write_all(first_client_frame).await;
-        res?;
         if !client_hello_pass {
             // if client verify failed, bidirectional copy and return
             tracing::debug!("ClientHello verify failed, will copy bidirectional");
-            copy_bidirectional(&mut in_stream, &mut handshake_stream).await;
-            return Ok(());
+            drop(pending);
+            let mut in_stream = FirstByteStream::new(in_stream);
+            let sent_at = in_stream.sent_at();
+            let class = match replayed {
+                true => ConnClass::Replay,
+                false => ConnClass::Probe,
+            };
+            METRICS.record_conn(class, addr);
+            let res = match class {
+                ConnClass::Probe if MISMATCH_CHECK_LIMIT.check(now_secs()).is_some() => {
+                    relay_probe_v3(
+                        in_stream,
+                        handshake_stream,
+                        first_client_frame,
+                        password,
+                        addr,
+                    )
+                    .await
+                }
+                _ => {
+                    let (res, _) =
+                        write_to_handshake_server(&mut handshake_stream, first_client_frame).await;
+                    if res.is_ok() {
+                        copy_bidirectional(&mut in_stream, &mut handshake_stream).await;
+                    }
+                    res.map(|_| ()).map_err(Into::into)
+                }
+            };
+            observe_first_byte(accepted_at, &sent_at, true);
+            return res;
         }
+        let (res, _) = write_to_handshake_server(&mut handshake_stream, first_client_frame).await;
+        res?;
         tracing::debug!("ClientHello verify success");
 
         // stage 1.2: read server hello and extract server random from it
-        let first_server_frame = read_exact_frame(&mut handshake_stream).await?;
+        let first_server_frame = deadline
+            .run(read_exact_frame(&mut handshake_stream))
+            .await?;
         let (res, first_server_frame) = in_stream.write_all(first_server_frame).await;
         res?;
+        let first_byte_latency = accepted_at.elapsed();
         let server_random = match extract_server_random(&first_server_frame) {
             Some(sr) => sr,
             None => {
                 // we cannot extract server random, bidirectional copy and return
                 tracing::debug!("ServerRandom extract failed, will copy bidirectional");
+                drop(pending);
+                METRICS.record_conn(ConnClass::Fallback, addr);
+                METRICS.first_byte_latency.observe(first_byte_latency, true);
                 copy_bidirectional(&mut in_stream, &mut handshake_stream).await;
                 return Ok(());
             }
@@ -285,62 +1178,124 @@ impl<LA, TA> ShadowTlsServer<LA, TA> {
 
         if !support_tls13(&first_server_frame) {
             tracing::error!("TLS 1.3 is not supported, will copy bidirectional");
+            drop(pending);
+            METRICS.record_conn(ConnClass::Fallback, addr);
+            METRICS.first_byte_latency.observe(first_byte_latency, true);
             copy_bidirectional(&mut in_stream, &mut handshake_stream).await;
             return Ok(());
         }
 
         // stage 1.3.1: create HMAC_ServerRandomC and HMAC_ServerRandom
-        let mut hmac_sr_c = Hmac::new(&self.password, (&server_random, b"C"));
-        let hmac_sr_s = Hmac::new(&self.password, (&server_random, b"S"));
-        let mut hmac_sr = Hmac::new(&self.password, (&server_random, &[]));
+        let mut hmac_sr_c = Hmac::new(password, (&server_random, b"C"));
+        let hmac_sr_s = Hmac::new(password, (&server_random, b"S"));
+        let mut hmac_sr = Hmac::new(password, (&server_random, &[]));
 
         // stage 1.3.2: copy ShadowTLS Client -> Handshake Server until hamc matches
         // stage 1.3.3: copy and modify Handshake Server -> ShadowTLS Client until 1.3.2 stops
-        let pure_data = {
-            let (mut c_read, mut c_write) = in_stream.split();
-            let (mut h_read, mut h_write) = handshake_stream.split();
-            let (mut sender, mut recevier) = local_sync::oneshot::channel::<()>();
-            let key = kdf(&self.password, &server_random);
-            let (maybe_pure, _) = monoio::join!(
-                async {
-                    let r =
-                        copy_by_frame_until_hmac_matches(&mut c_read, &mut h_write, &mut hmac_sr_c)
-                            .await;
-                    recevier.close();
-                    if r.is_err() {
-                        let _ = h_write.shutdown().await;
-                    }
-                    r
-                },
-                async {
-                    let r = copy_by_frame_with_modification(
-                        &mut h_read,
-                        &mut c_write,
-                        &mut hmac_sr,
-                        &key,
-                        &mut sender,
-                    )
-                    .await;
-                    if r.is_err() {
-                        let _ = c_write.shutdown().await;
+        let pure_data = deadline
+            .run(async {
+                let (mut c_read, mut c_write) = in_stream.split();
+                let (mut h_read, mut h_write) = handshake_stream.split();
+                let (mut sender, mut recevier) = local_sync::oneshot::channel::<()>();
+                let key = kdf(password, &server_random);
+                let (maybe_pure, _) = monoio::join!(
+                    async {
+                        let r = copy_by_frame_until_hmac_matches(
+                            &mut c_read,
+                            &mut h_write,
+                            &mut hmac_sr_c,
+                        )
+                        .await;
+                        recevier.close();
+                        if r.is_err() {
+                            let _ = h_write.shutdown().await;
+                        }
+                        r
+                    },
+                    async {
+                        let r = copy_by_frame_with_modification(
+                            &mut h_read,
+                            &mut c_write,
+                            &mut hmac_sr,
+                            &key,
+                            self.opts.close_notify,
+                            &mut sender,
+                        )
+                        .await;
+                        if r.is_err() {
+                            let _ = c_write.shutdown().await;
+                        }
                     }
-                }
-            );
-            maybe_pure?
-        };
+                );
+                maybe_pure
+            })
+            .await?;
         tracing::debug!("handshake relay finished");
+        drop(pending);
+        drop(first_server_frame);
+
+        // stage 2.1: connect data server, if it is down the handshake server
+        // keeps answering the client like a probe
+        let mut data_stream = match self.connect_data_server(proxy_header).await {
+            Ok(data_stream) => data_stream,
+            Err(e) if self.opts.backend_down_fallback => {
+                tracing::warn!("data server unavailable: {e}");
+                // the client is relayed like a probe from now on, so it takes a probe slot
+                let _handshake_slot = match &self.handshake_limit {
+                    Some(limit) => match limit.try_acquire() {
+                        Some(slot) => Some(slot),
+                        None => {
+                            tracing::debug!("too many probes relayed, drop client from {addr}");
+                            METRICS.probe_dropped.fetch_add(1, Ordering::Relaxed);
+                            return Ok(());
+                        }
+                    },
+                    None => None,
+                };
+                METRICS.record_conn(ConnClass::BackendDown, addr);
+                METRICS.first_byte_latency.observe(first_byte_latency, true);
+                // the hmac matched frame is forwarded without its hmac, the
+                // handshake server rejects it as any undecryptable record
+                let mut frame = Vec::with_capacity(TLS_HEADER_SIZE + pure_data.len());
+                frame.extend_from_slice(&[APPLICATION_DATA, TLS_MAJOR, TLS_MINOR.0]);
+                frame.write_u16::<BigEndian>(pure_data.len() as u16)?;
+                frame.extend_from_slice(&pure_data);
+                let (res, _) = handshake_stream.write_all(frame).await;
+                res?;
+                copy_bidirectional(&mut in_stream, &mut handshake_stream).await;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        METRICS.record_conn(ConnClass::Authed, addr);
+        METRICS
+            .first_byte_latency
+            .observe(first_byte_latency, false);
 
         // early drop useless resources
         drop(handshake_stream);
-        drop(first_server_frame);
+        drop(handshake_slot);
 
         // stage 2.2: copy ShadowTLS Client -> Data Server
         // stage 2.3: copy Data Server -> ShadowTLS Client
-        let mut data_stream = TcpStream::connect(self.target_addr.as_ref()).await?;
-        mod_tcp_conn(&mut data_stream, true, self.nodelay);
+        let _tcp_info = self.tcp_info_probe(&data_stream, addr);
         let (res, _) = data_stream.write_all(pure_data).await;
         res?;
-        verified_relay(data_stream, in_stream, hmac_sr_s, hmac_sr_c).await;
+        let op_timeout = OpTimeout::new(self.opts.read_timeout, self.opts.write_timeout);
+        let byte_limit = ByteLimit::new(self.opts.max_bytes_per_conn, self.opts.max_bytes_mode);
+        let (data_stream, in_stream) = (
+            op_timeout.wrap(byte_limit.wrap(data_stream)),
+            op_timeout.wrap(JitterStream::new(in_stream, self.opts.close_jitter)),
+        );
+        byte_limit
+            .guard(op_timeout.guard(verified_relay(
+                data_stream,
+                in_stream,
+                hmac_sr_s,
+                hmac_sr_c,
+                self.opts.buffers,
+            )))
+            .await;
         Ok(())
     }
 }
@@ -362,6 +1317,36 @@ impl std::fmt::Debug for SwitchResult {
     }
 }
 
+/// Write all of buf to the handshake server.
+/// Its socket buffer may be full while a slow server reads, then a write
+/// takes only part of buf. The rest is written again and it is logged, so
+/// a stalled handshake can be told from a lost one.
+async fn write_to_handshake_server<W, T>(writer: &mut W, mut buf: T) -> monoio::BufResult<usize, T>
+where
+    W: AsyncWriteRent + ?Sized,
+    T: IoBuf,
+{
+    let len = buf.bytes_init();
+    let mut written = 0;
+    while written < len {
+        let (res, slice) = writer.write(buf.slice(written..)).await;
+        buf = slice.into_inner();
+        match res {
+            Ok(0) => return (Err(std::io::ErrorKind::WriteZero.into()), buf),
+            Ok(n) => {
+                written += n;
+                if written < len {
+                    tracing::debug!(
+                        "short write to handshake server: {written} of {len} bytes, writing the rest"
+                    );
+                }
+            }
+            Err(e) => return (Err(e), buf),
+        }
+    }
+    (Ok(written), buf)
+}
+
 /// Copy until handshake finished.
 /// We use HMAC to check if handshake finished.
 ///
@@ -370,6 +1355,7 @@ async fn copy_until_handshake_finished<R, W>(
     mut read_half: R,
     mut write_half: W,
     hmac: &HmacHandler,
+    handshake_done: &Cell<bool>,
 ) -> std::io::Result<SwitchResult>
 where
     R: AsyncReadRent,
@@ -389,7 +1375,8 @@ where
     let mut data_buf = vec![0_u8; 2048];
     let mut application_data_count: usize = 0;
 
-    let mut hashes = VecDeque::with_capacity(10);
+    // recent hashes of every password
+    let mut hashes = VecDeque::with_capacity(10 * hmac.hashes().len());
     loop {
         let header_buf_slice = SliceMut::new(header_buf, header_read_len, TLS_HEADER_SIZE);
         let (res, header_buf_slice_) = read_half.read(header_buf_slice).await;
@@ -405,7 +1392,8 @@ where
 
         // We have to relay data now no matter header is enough or not.
         let header_buf_slice_w = Slice::new(header_buf, header_write_len, header_read_len);
-        let (res, header_buf_slice_w_) = write_half.write_all(header_buf_slice_w).await;
+        let (res, header_buf_slice_w_) =
+            write_to_handshake_server(&mut write_half, header_buf_slice_w).await;
         header_buf = header_buf_slice_w_.into_inner();
         header_write_len += res?;
 
@@ -462,7 +1450,7 @@ where
                 }
 
                 let buf = buf.into_inner().slice(0..read_len);
-                let (write_res, buf) = write_half.write_all(buf).await;
+                let (write_res, buf) = write_to_handshake_server(&mut write_half, buf).await;
                 to_copy -= write_res?;
                 data_buf = buf.into_inner();
             }
@@ -474,6 +1462,9 @@ where
             continue;
         }
 
+        // Handshake frames are finished, application data follows.
+        handshake_done.set(true);
+
         // Here we need to check hmac.
         // We have to read and copy the maybe_hmac.
         // Note: Send this 8 byte to remote does not matters:
@@ -495,7 +1486,7 @@ where
             }
 
             let buf = Slice::new(buf_.into_inner(), hmac_read_len, hmac_read_len + read_len);
-            let (write_res, buf_) = write_half.write_all(buf).await;
+            let (write_res, buf_) = write_to_handshake_server(&mut write_half, buf).await;
             write_res?;
             hmac_read_len += read_len;
             data_hmac_buf = buf_.into_inner();
@@ -503,14 +1494,15 @@ where
 
         // Now hmac has been read and copied.
         // If hmac matches, we need to read current data and return.
-        let hash = hmac.hash();
         let mut hash_trim = [0; HMAC_SIZE_V2];
-        unsafe { copy_nonoverlapping(hash.as_ptr(), hash_trim.as_mut_ptr(), HMAC_SIZE_V2) };
-        tracing::debug!("hmac calculated: {hash_trim:?}");
-        if hashes.len() + 1 > hashes.capacity() {
-            hashes.pop_front();
+        for hash in hmac.hashes() {
+            unsafe { copy_nonoverlapping(hash.as_ptr(), hash_trim.as_mut_ptr(), HMAC_SIZE_V2) };
+            tracing::debug!("hmac calculated: {hash_trim:?}");
+            if hashes.len() + 1 > hashes.capacity() {
+                hashes.pop_front();
+            }
+            hashes.push_back(hash_trim);
         }
-        hashes.push_back(hash_trim);
         unsafe {
             copy_nonoverlapping(data_hmac_buf.as_ptr(), hash_trim.as_mut_ptr(), HMAC_SIZE_V2)
         };
@@ -538,7 +1530,7 @@ where
             }
 
             let buf = buf.into_inner().slice(0..read_len);
-            let (write_res, buf) = write_half.write_all(buf).await;
+            let (write_res, buf) = write_to_handshake_server(&mut write_half, buf).await;
             to_copy -= write_res?;
             data_buf = buf.into_inner();
         }
@@ -550,10 +1542,51 @@ where
     }
 }
 
+/// Relay the probe of V3 protocol like V2 does, so a V2 client, which looks
+/// like a probe to V3 server, can be recognized.
+/// The connection of a V2 client is closed after the handshake.
+async fn relay_probe_v3<S>(
+    in_stream: S,
+    mut handshake_stream: TcpStream,
+    client_hello: Vec<u8>,
+    password: &str,
+    addr: SocketAddr,
+) -> anyhow::Result<()>
+where
+    S: AsyncReadRent + AsyncWriteRent + Split,
+{
+    let mut in_stream = HashedWriteStream::new(in_stream, password.as_bytes())?;
+    let mut hmac = in_stream.hmac_handler();
+    let mut prefixed_io = PrefixedReadIo::new(&mut in_stream, std::io::Cursor::new(client_hello));
+    let (mut out_r, mut out_w) = handshake_stream.split();
+    let (mut in_r, mut in_w) = prefixed_io.split();
+    let (switch, cp) = FirstRetGroup::new(
+        copy_until_handshake_finished(&mut in_r, &mut out_w, &hmac, &Cell::new(false)),
+        Box::pin(copy_until_eof(&mut out_r, &mut in_w)),
+    )
+    .await?;
+    hmac.disable();
+    if let SwitchResult::Switch(_) = switch {
+        tracing::warn!(
+            "client {addr} appears to use protocol v2 but server is v3, check v3 option of both sides"
+        );
+        return Ok(());
+    }
+    match cp {
+        FutureOrOutput::Future(cp) => {
+            ErrGroup::new(cp, copy_until_eof(in_r, out_w)).await?;
+        }
+        FutureOrOutput::Output(_) => {
+            copy_until_eof(in_r, out_w).await?;
+        }
+    }
+    Ok(())
+}
+
 /// Read from connection and parse the frame.
 /// Return consumed data and SNI.
 ///
-/// Only used by V2 protocol.
+/// Only used by V2 protocol and handshake passthrough.
 async fn extract_sni_v2<R: AsyncReadRent>(mut r: R) -> std::io::Result<(Vec<u8>, Option<Vec<u8>>)> {
     macro_rules! read_ok {
         ($res: expr, $data: expr) => {
@@ -791,7 +1824,7 @@ async fn copy_by_frame_until_hmac_matches(
             }
         }
 
-        let (res, buffer) = write.write_all(buffer).await;
+        let (res, buffer) = write_to_handshake_server(&mut write, buffer).await;
         res?;
         g_buffer = buffer;
     }
@@ -801,6 +1834,7 @@ async fn copy_by_frame_until_hmac_matches(
 /// Modify appdata frame:
 /// 1. Cycle XOR xor data.
 /// 2. Calculate HMAC and insert before the frame data.
+/// Alert frame is handled according to CloseNotifyMode.
 ///
 /// Only used by V3 protocol.
 async fn copy_by_frame_with_modification(
@@ -808,8 +1842,12 @@ async fn copy_by_frame_with_modification(
     mut write: impl AsyncWriteRent,
     hmac: &mut Hmac,
     xor: &[u8],
+    close_notify: CloseNotifyMode,
     stop: &mut Sender<()>,
 ) -> std::io::Result<()> {
+    /// Warning level(1) close_notify(0).
+    const CLOSE_NOTIFY: [u8; 2] = [0x01, 0x00];
+
     let mut g_buffer = Vec::new();
     let stop = stop.closed();
     monoio::pin!(stop);
@@ -841,6 +1879,22 @@ async fn copy_by_frame_with_modification(
                     let data_size = data_size.wrapping_add(HMAC_SIZE as u16);
                     (&mut buffer[3..5]).write_u16::<BigEndian>(data_size).unwrap();
                 }
+                if buffer[0] == ALERT {
+                    match close_notify {
+                        CloseNotifyMode::Forward => (),
+                        CloseNotifyMode::Suppress => {
+                            tracing::debug!("alert from handshake server suppressed");
+                            g_buffer = buffer;
+                            continue;
+                        }
+                        CloseNotifyMode::Synthesize => {
+                            tracing::debug!("alert from handshake server replaced with close_notify");
+                            buffer.truncate(TLS_HEADER_SIZE);
+                            buffer.extend_from_slice(&CLOSE_NOTIFY);
+                            (&mut buffer[3..5]).write_u16::<BigEndian>(CLOSE_NOTIFY.len() as u16).unwrap();
+                        }
+                    }
+                }
 
                 // writing is not cancelable
                 let (res, buffer) = write.write_all(buffer).await;
@@ -935,6 +1989,10 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::util::{
+        parse_jitter_range,
+        test_util::{capture_logs, capture_logs_at, tcp_pair, VecWriter},
+    };
 
     fn to_map<K: Into<String>, V: Into<String>>(
         kvs: Vec<(K, V)>,
@@ -983,4 +2041,733 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn refresh_tls_addrs_from_cmd() {
+        let dir = std::env::temp_dir().join(format!("shadow-tls-refresh-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let counter = dir.join("counter");
+        // The first run emits a valid list, the later ones emit an invalid list.
+        let cmd = format!(
+            "if [ -e {0} ]; then echo ';;'; else touch {0}; echo 'feishu.cn;google.com'; fi",
+            counter.display()
+        );
+
+        let handle = TlsAddrsHandle::new(parse_server_addrs("captive.apple.com").unwrap(), None);
+        refresh_tls_addrs(&cmd, &handle).unwrap();
+        let expected = TlsAddrs {
+            dispatch: map![
+                "feishu.cn" => "feishu.cn:443",
+            ],
+            fallback: s!("google.com:443"),
+        };
+        assert_eq!(*handle.load(), expected);
+
+        assert!(refresh_tls_addrs(&cmd, &handle).is_err());
+        assert_eq!(*handle.load(), expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backend_allowlist() {
+        assert!(parse_allowlist("10.0.0.0/33").is_err());
+        assert!(parse_allowlist("example.com").is_err());
+        let allowlist = parse_allowlist("127.0.0.1, 10.0.0.0/8,fd00::/8").unwrap();
+        assert_eq!(allowlist.to_string(), "127.0.0.1/32,10.0.0.0/8,fd00::/8");
+
+        let allowed: SocketAddr = "10.1.2.3:8080".parse().unwrap();
+        let refused: SocketAddr = "192.168.1.1:8080".parse().unwrap();
+        assert!(allowlist.contains("fd12::1".parse().unwrap()));
+        assert!(!allowlist.contains("127.0.0.2".parse().unwrap()));
+        assert_eq!(
+            filter_allowed(vec![refused, allowed], &allowlist).unwrap(),
+            vec![allowed]
+        );
+        assert!(filter_allowed(vec![refused], &allowlist).is_err());
+    }
+
+    #[test]
+    fn replay_cache_window() {
+        let cache = ReplayCache::new(Duration::from_secs(10));
+        let now = Instant::now();
+        assert!(!cache.check_and_insert([1; 32], now));
+        assert!(!cache.check_and_insert([2; 32], now + Duration::from_secs(5)));
+        // replayed within the window
+        assert!(cache.check_and_insert([1; 32], now + Duration::from_secs(9)));
+        // the first one expired, and the second one is still remembered
+        assert!(!cache.check_and_insert([1; 32], now + Duration::from_secs(11)));
+        assert!(cache.check_and_insert([2; 32], now + Duration::from_secs(12)));
+    }
+
+    #[test]
+    fn rotate_password_file() {
+        let path = std::env::temp_dir().join(format!("shadow-tls-password-{}", std::process::id()));
+        let file = PasswordFile {
+            path: path.clone(),
+            salt: None,
+            grace: Duration::from_secs(60),
+        };
+        let now = Instant::now();
+        let handle = PasswordsHandle::new(s!("old"));
+
+        std::fs::write(&path, "old\n").unwrap();
+        assert!(!file.refresh(&handle, now).unwrap());
+        std::fs::write(&path, "new\n").unwrap();
+        assert!(file.refresh(&handle, now).unwrap());
+        let passwords = handle.load();
+        assert_eq!(passwords.current(), "new");
+        assert_eq!(
+            passwords.previous(now + Duration::from_secs(59)),
+            Some("old")
+        );
+        assert_eq!(passwords.previous(now + Duration::from_secs(60)), None);
+
+        // ClientHello signed with the old password passes only in grace window.
+        let frame = client_hello_signed_by("old");
+        assert!(!verified_extract_sni(&frame, passwords.current()).0);
+        assert!(verified_extract_sni(&frame, passwords.previous(now).unwrap()).0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Build a minimal ClientHello frame with session id HMAC.
+    fn client_hello_signed_by(password: &str) -> Vec<u8> {
+        const HMAC_IDX: usize = SESSION_ID_LEN_IDX + 1 + TLS_SESSION_ID_SIZE - HMAC_SIZE;
+        let mut frame = vec![
+            HANDSHAKE,
+            TLS_MAJOR,
+            TLS_MINOR.0,
+            0,
+            0,
+            CLIENT_HELLO,
+            0,
+            0,
+            0,
+        ];
+        frame.extend_from_slice(&[TLS_MAJOR, TLS_MINOR.0]);
+        frame.extend_from_slice(&[7; TLS_RANDOM_SIZE]);
+        frame.push(TLS_SESSION_ID_SIZE as u8);
+        frame.extend_from_slice(&[0; TLS_SESSION_ID_SIZE]);
+        let len = frame.len() - TLS_HEADER_SIZE;
+        frame[3..5].copy_from_slice(&(len as u16).to_be_bytes());
+        frame[7..9].copy_from_slice(&(len as u16 - 4).to_be_bytes());
+        let mut hmac = Hmac::new(password, (&[], &[]));
+        hmac.update(&frame[TLS_HEADER_SIZE..]);
+        let hash = hmac.finalize();
+        frame[HMAC_IDX..HMAC_IDX + HMAC_SIZE].copy_from_slice(&hash);
+        frame
+    }
+
+    #[monoio::test]
+    async fn v2_client_to_v3_server() {
+        use hmac::Mac;
+
+        let ((mut client, in_stream), (handshake_stream, mut handshake_server)) =
+            monoio::join!(tcp_pair(), tcp_pair());
+        let (logs, _guard) = capture_logs();
+        let client_hello = vec![HANDSHAKE, 3, 1, 0, 1, 1];
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        let relay = relay_probe_v3(in_stream, handshake_stream, client_hello, "pwd", addr);
+        // A V2 client signs its first application data with HMAC of data from server.
+        let v2_client = async {
+            let (res, _) = handshake_server.read_exact(vec![0; 6]).await;
+            res.unwrap();
+            let server_data = vec![HANDSHAKE, 3, 3, 0, 2, 2, 0];
+            let (res, _) = handshake_server.write_all(server_data.clone()).await;
+            res.unwrap();
+            let (res, _) = client.read_exact(vec![0; server_data.len()]).await;
+            res.unwrap();
+            let mut hmac = hmac::Hmac::<sha1::Sha1>::new_from_slice(b"pwd").unwrap();
+            hmac.update(&server_data);
+            let hash = hmac.finalize().into_bytes();
+            let mut frames = vec![CHANGE_CIPHER_SPEC, 3, 3, 0, 1, 1];
+            frames.extend_from_slice(&[APPLICATION_DATA, 3, 3, 0, HMAC_SIZE_V2 as u8 + 4]);
+            frames.extend_from_slice(&hash[..HMAC_SIZE_V2]);
+            frames.extend_from_slice(b"data");
+            let (res, _) = client.write_all(frames).await;
+            res.unwrap();
+        };
+        let (res, _) = monoio::join!(relay, v2_client);
+        res.unwrap();
+        assert!(logs
+            .contents()
+            .contains("client 127.0.0.1:1234 appears to use protocol v2 but server is v3"));
+    }
+
+    #[monoio::test]
+    async fn handshake_passthrough() {
+        let handshake_server = monoio::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tls_addr =
+            TlsAddrs::try_from(handshake_server.local_addr().unwrap().to_string().as_str())
+                .unwrap();
+        let server = ShadowTlsServer::new(
+            "127.0.0.1:0",
+            "127.0.0.1:1",
+            tls_addr,
+            s!("pwd"),
+            ServerOpts {
+                v3: true,
+                handshake_passthrough: true,
+                ..Default::default()
+            },
+        );
+        let (mut client, in_stream) = tcp_pair().await;
+        let addr = in_stream.peer_addr().unwrap();
+        // even an authenticated client is passed through
+        let mut sent = client_hello_signed_by("pwd");
+        sent.extend_from_slice(&[APPLICATION_DATA, TLS_MAJOR, TLS_MINOR.0, 0, 3, 1, 2, 3]);
+        let reply = b"\x16\x03\x03\x00\x02hi, not a real ServerHello".to_vec();
+
+        let handshake = async {
+            let (mut conn, _) = handshake_server.accept().await.unwrap();
+            let (res, received) = conn.read_exact(vec![0; sent.len()]).await;
+            res.unwrap();
+            let (res, _) = conn.write_all(reply.clone()).await;
+            res.unwrap();
+            conn.shutdown().await.unwrap();
+            received
+        };
+        let peer = async {
+            let (res, _) = client.write_all(sent.clone()).await;
+            res.unwrap();
+            let (res, received) = client.read_exact(vec![0; reply.len()]).await;
+            res.unwrap();
+            let (res, _) = client.read(vec![0; 16]).await;
+            assert_eq!(res.unwrap(), 0);
+            client.shutdown().await.unwrap();
+            received
+        };
+        let (res, forwarded, answered) = monoio::join!(
+            server.relay_passthrough(in_stream, addr, PendingHandshake::new(None)),
+            handshake,
+            peer
+        );
+        res.unwrap();
+        assert_eq!(forwarded, sent);
+        assert_eq!(answered, reply);
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn require_handshake_unreachable() {
+        let refused = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // accepts TCP but never speaks TLS
+        let silent = monoio::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tls_addr = TlsAddrs::try_from(
+            format!("b.com:{};{refused}", silent.local_addr().unwrap()).as_str(),
+        )
+        .unwrap();
+        let server = ShadowTlsServer::new(
+            "127.0.0.1:0",
+            "127.0.0.1:1",
+            tls_addr,
+            s!("pwd"),
+            ServerOpts {
+                handshake_timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+        );
+        let silent = async {
+            let (conn, _) = silent.accept().await.unwrap();
+            monoio::time::sleep(Duration::from_secs(1)).await;
+            drop(conn);
+        };
+        monoio::select! {
+            res = server.check_handshake_servers() => {
+                let e = res.unwrap_err().to_string();
+                assert!(e.starts_with("no handshake server completes a TLS handshake"), "{e}");
+                assert!(e.contains(&format!("{refused}(")), "{e}");
+                assert!(e.contains("(timeout)"), "{e}");
+            }
+            _ = silent => panic!("check did not time out"),
+        }
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn probe_delay() {
+        let handshake_server = monoio::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tls_addr =
+            TlsAddrs::try_from(handshake_server.local_addr().unwrap().to_string().as_str())
+                .unwrap();
+        let server = ShadowTlsServer::new(
+            "127.0.0.1:0",
+            "127.0.0.1:1",
+            tls_addr,
+            s!("pwd"),
+            ServerOpts {
+                v3: true,
+                probe_delay: Some(parse_jitter_range("100-200").unwrap()),
+                ..Default::default()
+            },
+        );
+        let (mut client, in_stream) = tcp_pair().await;
+        let addr = in_stream.peer_addr().unwrap();
+        let hello = client_hello_signed_by("wrong");
+        let start = Instant::now();
+        let (res, _) = client.write_all(hello.clone()).await;
+        res.unwrap();
+        let handshake = async {
+            let (mut conn, _) = handshake_server.accept().await.unwrap();
+            let (res, _) = conn.read_exact(vec![0; hello.len()]).await;
+            res.unwrap();
+            start.elapsed()
+        };
+        let closed = async move {
+            let (res, _) = client.read(vec![0; 16]).await;
+            assert_eq!(res.unwrap(), 0);
+        };
+        let (_, delay, _) = monoio::join!(
+            server.relay_v3(in_stream, addr, PendingHandshake::new(None)),
+            handshake,
+            closed
+        );
+        assert!(delay >= Duration::from_millis(100), "{delay:?}");
+        assert!(delay < Duration::from_millis(200 + 100), "{delay:?}");
+    }
+
+    /// Listener whose accept queue is full, so new connections never complete.
+    fn stalled_listener() -> (std::net::TcpListener, Vec<std::net::TcpStream>) {
+        use std::os::fd::FromRawFd;
+
+        let listener = unsafe {
+            let fd = libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0);
+            assert!(fd >= 0);
+            let mut sockaddr: libc::sockaddr_in = std::mem::zeroed();
+            sockaddr.sin_family = libc::AF_INET as _;
+            sockaddr.sin_addr.s_addr = u32::from(std::net::Ipv4Addr::LOCALHOST).to_be();
+            let len = std::mem::size_of::<libc::sockaddr_in>() as _;
+            assert_eq!(libc::bind(fd, &sockaddr as *const _ as *const _, len), 0);
+            assert_eq!(libc::listen(fd, 0), 0);
+            std::net::TcpListener::from_raw_fd(fd)
+        };
+        let addr = listener.local_addr().unwrap();
+        let mut queued = Vec::new();
+        while let Ok(conn) = std::net::TcpStream::connect_timeout(&addr, Duration::from_millis(100))
+        {
+            queued.push(conn);
+        }
+        (listener, queued)
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn handshake_timeouts() {
+        let new_server = |handshake_addr: SocketAddr| {
+            ShadowTlsServer::new(
+                "127.0.0.1:0",
+                "127.0.0.1:1",
+                TlsAddrs::try_from(handshake_addr.to_string().as_str()).unwrap(),
+                s!("pwd"),
+                ServerOpts {
+                    v3: true,
+                    handshake_connect_timeout: Some(Duration::from_millis(100)),
+                    handshake_timeout: Some(Duration::from_millis(100)),
+                    ..Default::default()
+                },
+            )
+        };
+        let hello = client_hello_signed_by("pwd");
+
+        // connect phase hangs
+        let (stalled, _queued) = stalled_listener();
+        let server = new_server(stalled.local_addr().unwrap());
+        let (mut client, in_stream) = tcp_pair().await;
+        let (res, _) = client.write_all(hello.clone()).await;
+        res.unwrap();
+        let addr = in_stream.peer_addr().unwrap();
+        let err = server
+            .relay_v3(in_stream, addr, PendingHandshake::new(None))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("connect timeout"), "{err}");
+
+        // handshake server accepts but never answers the ClientHello
+        let silent = monoio::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = new_server(silent.local_addr().unwrap());
+        let (mut client, in_stream) = tcp_pair().await;
+        let (res, _) = client.write_all(hello).await;
+        res.unwrap();
+        let addr = in_stream.peer_addr().unwrap();
+        let (res, _conn) = monoio::join!(
+            server.relay_v3(in_stream, addr, PendingHandshake::new(None)),
+            silent.accept()
+        );
+        let err = res.unwrap_err();
+        assert!(err.to_string().contains("handshake timeout"), "{err}");
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn handshake_timeout_spares_probe_session() {
+        let handshake = monoio::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = ShadowTlsServer::new(
+            "127.0.0.1:0",
+            "127.0.0.1:1",
+            TlsAddrs::try_from(handshake.local_addr().unwrap().to_string().as_str()).unwrap(),
+            s!("pwd"),
+            ServerOpts {
+                handshake_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        );
+        let mut frames = client_hello_signed_by("pwd");
+        frames.extend_from_slice(&[CHANGE_CIPHER_SPEC, TLS_MAJOR, TLS_MINOR.0, 0, 1, 1]);
+        frames.extend_from_slice(&[APPLICATION_DATA, TLS_MAJOR, TLS_MINOR.0, 0, 8]);
+        frames.extend_from_slice(&[0; 8]);
+        let expected = frames.len() + 4;
+
+        // the probe goes idle after handshake frames and is still relayed
+        let (mut client, in_stream) = tcp_pair().await;
+        let addr = in_stream.peer_addr().unwrap();
+        let probe = async {
+            let (res, _) = client.write_all(frames).await;
+            res.unwrap();
+            let (mut conn, _) = handshake.accept().await.unwrap();
+            monoio::time::sleep(Duration::from_millis(300)).await;
+            let (res, _) = client.write_all(b"late").await;
+            res.unwrap();
+            let receive = async {
+                let mut received = 0;
+                while received < expected {
+                    let (res, _) = conn.read(vec![0; 4096]).await;
+                    let n = res.unwrap();
+                    assert!(n > 0, "closed after {received} bytes");
+                    received += n;
+                }
+            };
+            monoio::time::timeout(Duration::from_secs(1), receive)
+                .await
+                .expect("data after idle is not relayed");
+            drop(client);
+        };
+        let (res, _) = monoio::join!(
+            server.relay_v2(in_stream, addr, PendingHandshake::new(None)),
+            probe
+        );
+        if let Err(e) = res {
+            assert!(!e.to_string().contains("handshake timeout"), "{e}");
+        }
+
+        // a handshake stalled in handshake frames still times out
+        let (mut client, in_stream) = tcp_pair().await;
+        let (res, _) = client.write_all(client_hello_signed_by("pwd")).await;
+        res.unwrap();
+        let addr = in_stream.peer_addr().unwrap();
+        let (res, _conn) = monoio::join!(
+            server.relay_v2(in_stream, addr, PendingHandshake::new(None)),
+            handshake.accept()
+        );
+        let err = res.unwrap_err();
+        assert!(err.to_string().contains("handshake timeout"), "{err}");
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn max_pending_handshakes() {
+        let server = ShadowTlsServer::new(
+            "127.0.0.1:0",
+            "127.0.0.1:1",
+            TlsAddrs::try_from("127.0.0.1:1").unwrap(),
+            s!("pwd"),
+            ServerOpts {
+                v3: true,
+                max_pending_handshakes: Some(2),
+                ..Default::default()
+            },
+        );
+        let limit = server.pending_limit.clone().unwrap();
+        let listener = server.bind().unwrap();
+        let addr = listener.local_addr().unwrap();
+        monoio::spawn(server.serve(listener));
+
+        // clients never sending ClientHello pile up in handshake phase
+        let mut clients = Vec::new();
+        for _ in 0..8 {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+            monoio::time::sleep(Duration::from_millis(5)).await;
+            assert!(limit.current() <= 2);
+        }
+        monoio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(limit.current(), 2);
+
+        // deferred ones are accepted and finished after clients leave, only
+        // the slot reserved for the next accept is left
+        drop(clients);
+        monoio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(limit.current(), 1);
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn first_byte_latency_of_probes() {
+        let fallback_count = || {
+            let rendered = METRICS.render_prometheus();
+            rendered
+                .lines()
+                .find_map(|l| {
+                    l.strip_prefix(r#"shadow_tls_first_byte_seconds_count{fallback="true"} "#)
+                })
+                .map_or(0, |n| n.parse::<u64>().unwrap())
+        };
+        let handshake = TcpListener::bind("127.0.0.1:0").unwrap();
+        let handshake_addr = handshake.local_addr().unwrap();
+        monoio::spawn(async move {
+            loop {
+                let (mut conn, _) = handshake.accept().await.unwrap();
+                monoio::spawn(async move {
+                    let (res, _) = conn.read(vec![0; 4096]).await;
+                    res.unwrap();
+                    let (res, _) = conn.write_all(b"cover site").await;
+                    res.unwrap();
+                });
+            }
+        });
+
+        for v3 in [false, true] {
+            let server = ShadowTlsServer::new(
+                "127.0.0.1:0",
+                "127.0.0.1:1",
+                TlsAddrs::try_from(handshake_addr.to_string().as_str()).unwrap(),
+                s!("pwd"),
+                ServerOpts {
+                    v3,
+                    ..Default::default()
+                },
+            );
+            let listener = server.bind().unwrap();
+            let addr = listener.local_addr().unwrap();
+            monoio::spawn(server.serve(listener));
+
+            let before = fallback_count();
+            let mut probe = TcpStream::connect(addr).await.unwrap();
+            let (res, _) = probe.write_all(client_hello_signed_by("other")).await;
+            res.unwrap();
+            let (res, buf) = probe.read(vec![0; 4096]).await;
+            assert_eq!(&buf[..res.unwrap()], b"cover site");
+            drop(probe);
+            // other tests may observe concurrently, so only growth is checked
+            let mut observed = false;
+            for _ in 0..50 {
+                monoio::time::sleep(Duration::from_millis(10)).await;
+                if fallback_count() > before {
+                    observed = true;
+                    break;
+                }
+            }
+            assert!(observed, "no first byte latency observed, v3: {v3}");
+        }
+    }
+
+    #[monoio::test]
+    async fn close_notify_modes() {
+        const HANDSHAKE_FRAME: [u8; 6] = [HANDSHAKE, TLS_MAJOR, TLS_MINOR.0, 0, 1, 0xaa];
+        // fatal(2) handshake_failure(40)
+        const ALERT_FRAME: [u8; 7] = [ALERT, TLS_MAJOR, TLS_MINOR.0, 0, 2, 2, 40];
+        const CLOSE_NOTIFY_FRAME: [u8; 7] = [ALERT, TLS_MAJOR, TLS_MINOR.0, 0, 2, 1, 0];
+        let input = [&HANDSHAKE_FRAME[..], &ALERT_FRAME[..]].concat();
+
+        for (mode, expected) in [
+            (CloseNotifyMode::Forward, input.clone()),
+            (CloseNotifyMode::Suppress, HANDSHAKE_FRAME.to_vec()),
+            (
+                CloseNotifyMode::Synthesize,
+                [&HANDSHAKE_FRAME[..], &CLOSE_NOTIFY_FRAME[..]].concat(),
+            ),
+        ] {
+            let mut writer = VecWriter::new();
+            let mut hmac = Hmac::new("password", (&[], &[]));
+            let (mut sender, _receiver) = local_sync::oneshot::channel::<()>();
+            let _ = copy_by_frame_with_modification(
+                input.as_slice(),
+                &mut writer,
+                &mut hmac,
+                b"key",
+                mode,
+                &mut sender,
+            )
+            .await;
+            assert_eq!(writer.data, expected, "mode {mode}");
+        }
+    }
+
+    #[monoio::test]
+    async fn forward_handshake_with_short_writes() {
+        let mut input = vec![HANDSHAKE, TLS_MAJOR, TLS_MINOR.0, 0, 7];
+        input.extend_from_slice(b"hello\x01\x02");
+        input.extend_from_slice(&[CHANGE_CIPHER_SPEC, TLS_MAJOR, TLS_MINOR.0, 0, 1, 1]);
+        // HMAC does not match, so it is forwarded like handshake
+        input.extend_from_slice(&[APPLICATION_DATA, TLS_MAJOR, TLS_MINOR.0, 0, 9]);
+        input.extend_from_slice(b"encrypted");
+
+        let hmac = HashedWriteStream::new(VecWriter::new(), b"pwd")
+            .unwrap()
+            .hmac_handler();
+        let (logs, _guard) = capture_logs_at(tracing::Level::DEBUG);
+        let mut writer = VecWriter::with_chunk(3);
+        let res =
+            copy_until_handshake_finished(input.as_slice(), &mut writer, &hmac, &Cell::default())
+                .await;
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(writer.data, input);
+        assert!(logs
+            .contents()
+            .contains("short write to handshake server: 3 of 7 bytes"));
+
+        let mut writer = VecWriter::with_chunk(2);
+        copy_until_eof(input.as_slice(), &mut writer).await.unwrap();
+        assert_eq!(writer.data, input);
+    }
+
+    // io_uring finishes socket writes in full, epoll writes what fits in the
+    // small send buffer.
+    #[monoio::test(driver = "legacy")]
+    async fn short_writes_to_handshake_server() {
+        use std::os::unix::io::AsRawFd;
+
+        let (mut handshake_server, mut server_conn) = tcp_pair().await;
+        // raised to the minimum by the kernel
+        let small = BufferSizes {
+            recv: None,
+            send: Some(1),
+        };
+        small.apply(server_conn.as_raw_fd()).unwrap();
+        let data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        let (logs, _guard) = capture_logs_at(tracing::Level::DEBUG);
+        let receive = async {
+            let mut received = Vec::new();
+            while received.len() < data.len() {
+                let (res, buf) = handshake_server.read(vec![0; 16 * 1024]).await;
+                let n = res.unwrap();
+                assert!(n > 0, "closed after {} bytes", received.len());
+                received.extend_from_slice(&buf[..n]);
+            }
+            received
+        };
+        let ((res, _), received) = monoio::join!(
+            write_to_handshake_server(&mut server_conn, data.clone()),
+            receive
+        );
+        assert_eq!(res.unwrap(), data.len());
+        assert!(received == data, "forwarded data differs");
+        assert!(logs.contents().contains("short write to handshake server"));
+    }
+
+    #[monoio::test]
+    async fn switch_with_previous_password() {
+        // what the server has sent to client
+        let server_data = b"server hello";
+        let handshake = |hmac: &HmacHandler| {
+            let mut input = vec![HANDSHAKE, TLS_MAJOR, TLS_MINOR.0, 0, 1, 1];
+            input.extend_from_slice(&[CHANGE_CIPHER_SPEC, TLS_MAJOR, TLS_MINOR.0, 0, 1, 1]);
+            input.extend_from_slice(&[APPLICATION_DATA, TLS_MAJOR, TLS_MINOR.0, 0, 12]);
+            input.extend_from_slice(&hmac.hash()[..HMAC_SIZE_V2]);
+            input.extend_from_slice(b"data");
+            input
+        };
+        let client_hash = |password: &[u8]| {
+            let mut stream = HashedWriteStream::new(VecWriter::new(), password).unwrap();
+            let hmac = stream.hmac_handler();
+            async move {
+                stream.write_all(server_data.to_vec()).await.0.unwrap();
+                hmac
+            }
+        };
+        let old = client_hash(b"old").await;
+        let new = client_hash(b"new").await;
+        let unknown = client_hash(b"unknown").await;
+
+        let mut stream =
+            HashedWriteStream::with_passwords(VecWriter::new(), &[b"new", b"old"]).unwrap();
+        let hmac = stream.hmac_handler();
+        stream.write_all(server_data.to_vec()).await.0.unwrap();
+        for client in [&new, &old] {
+            let input = handshake(client);
+            let res = copy_until_handshake_finished(
+                input.as_slice(),
+                VecWriter::new(),
+                &hmac,
+                &Cell::default(),
+            )
+            .await;
+            assert!(matches!(res.unwrap(), SwitchResult::Switch(data) if data == b"data"));
+        }
+        let input = handshake(&unknown);
+        let res = copy_until_handshake_finished(
+            input.as_slice(),
+            VecWriter::new(),
+            &hmac,
+            &Cell::default(),
+        )
+        .await;
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn allowed_handshake_ports() {
+        let tls_addr = parse_server_addrs(
+            "feishu.cn;cloudflare.com:1.1.1.1:22;internal.lan:127.0.0.1:6379;google.com:8443",
+        )
+        .unwrap();
+        let (logs, _guard) = capture_logs();
+        let handle = TlsAddrsHandle::new(tls_addr.clone(), Some(vec![443]));
+        let filtered = handle.load();
+        assert_eq!(filtered.choose(Some("feishu.cn"), false), "feishu.cn:443");
+        assert_eq!(
+            filtered.choose(Some("cloudflare.com"), false),
+            "google.com:8443"
+        );
+        assert_eq!(
+            filtered.choose(Some("internal.lan"), false),
+            "google.com:8443"
+        );
+        // fallback is still used, but warned
+        assert_eq!(
+            filtered.choose(Some("unknown.com"), false),
+            "google.com:8443"
+        );
+        assert!(logs.contents().contains(
+            "fallback handshake server google.com:8443 is not on an allowed port, it is still used"
+        ));
+        assert!(logs.contents().contains(
+            "handshake server 127.0.0.1:6379 is not on an allowed port, internal.lan uses google.com:8443 instead"
+        ));
+
+        // checked again when refreshed, never on connections
+        handle.store(parse_server_addrs("a.com:10.0.0.1:80;b.com").unwrap());
+        assert_eq!(handle.load().choose(Some("a.com"), false), "b.com:443");
+
+        let handle = TlsAddrsHandle::new(tls_addr.clone(), Some(vec![443, 22]));
+        assert_eq!(
+            handle.load().choose(Some("cloudflare.com"), false),
+            "1.1.1.1:22"
+        );
+        // all ports are allowed unless configured
+        assert_eq!(
+            *TlsAddrsHandle::new(tls_addr.clone(), None).load(),
+            tls_addr
+        );
+    }
+
+    #[test]
+    fn random_handshake_distribution() {
+        const ROUNDS: usize = 30000;
+        let tls_addr =
+            parse_server_addrs("feishu.cn;cloudflare.com:1.1.1.1:80;google.com").unwrap();
+        // matched SNI is never randomized
+        for _ in 0..100 {
+            assert_eq!(tls_addr.choose(Some("feishu.cn"), true), "feishu.cn:443");
+        }
+
+        let mut counter = rustc_hash::FxHashMap::<&str, usize>::default();
+        for _ in 0..ROUNDS {
+            *counter
+                .entry(tls_addr.choose(Some("unknown.com"), true))
+                .or_default() += 1;
+        }
+        assert_eq!(counter.len(), 3);
+        let expected = ROUNDS / 3;
+        for (addr, cnt) in counter {
+            assert!(
+                cnt.abs_diff(expected) < expected / 10,
+                "{addr} chosen {cnt} times, expected about {expected}"
+            );
+        }
+    }
 }
@@ -1,5 +1,5 @@
 use anyhow::{bail, Context};
-use tracing::error;
+use tracing::{debug, error, warn};
 
 use super::Args;
 use std::{collections::HashMap, env, process::exit};
@@ -36,19 +36,17 @@ pub(crate) fn get_sip003_arg() -> Option<Args> {
     });
 
     let opts = parse_sip003_options(&ss_plugin_options).unwrap();
+    let opts = merge_optsfile(opts).unwrap_or_else(|e| {
+        error!("load SIP003 optsfile failed: {e}");
+        exit(-1);
+    });
+    log_sip003_options(&opts);
     let opts: HashMap<_, _> = opts.into_iter().collect();
 
-    let threads = opts.get("threads").map(|s| s.parse::<u8>().unwrap());
-    let v3 = opts.get("v3").is_some();
     let passwd = opts
         .get("passwd")
         .expect("need passwd param(like passwd=123456)");
-
-    let args_opts = crate::Opts {
-        threads,
-        v3,
-        ..Default::default()
-    };
+    let args_opts = build_opts(&opts);
     let args = if opts.get("server").is_some() {
         let tls_addr = opts
             .get("tls")
@@ -56,12 +54,12 @@ pub(crate) fn get_sip003_arg() -> Option<Args> {
         let tls_addrs = crate::server::parse_server_addrs(tls_addr)
             .expect("tls param parse failed(like tls=xxx.com:443 or tls=yyy.com:1.2.3.4:443;zzz.com:443;xxx.com)");
         Args {
-            cmd: crate::Commands::Server {
+            cmd: Some(crate::Commands::Server {
                 listen: format!("{ss_remote_host}:{ss_remote_port}"),
                 server_addr: format!("{ss_local_host}:{ss_local_port}"),
                 tls_addr: tls_addrs,
-                password: passwd.to_owned(),
-            },
+                password: Some(passwd.to_owned()),
+            }),
             opts: args_opts,
         }
     } else {
@@ -70,19 +68,77 @@ pub(crate) fn get_sip003_arg() -> Option<Args> {
             .expect("need host param(like host=www.baidu.com)");
         let hosts = crate::client::parse_client_names(host).expect("tls names parse failed");
         Args {
-            cmd: crate::Commands::Client {
+            cmd: Some(crate::Commands::Client {
                 listen: format!("{ss_local_host}:{ss_local_port}"),
                 server_addr: format!("{ss_remote_host}:{ss_remote_port}"),
                 tls_names: hosts,
-                password: passwd.to_owned(),
+                password: Some(passwd.to_owned()),
                 alpn: Default::default(),
-            },
+            }),
             opts: args_opts,
         }
     };
     Some(args)
 }
 
+/// Keys recognized in SIP003 options.
+const KNOWN_KEYS: [&str; 7] = ["server", "tls", "host", "passwd", "threads", "v3", "salt"];
+
+fn build_opts(opts: &HashMap<String, String>) -> crate::Opts {
+    let threads = opts.get("threads").map(|s| s.parse::<u8>().unwrap());
+    let v3 = opts.get("v3").is_some();
+    let salt = opts.get("salt").cloned();
+
+    crate::Opts {
+        threads,
+        v3,
+        salt,
+        ..Default::default()
+    }
+}
+
+/// Merge options from the file given by `optsfile`, for configs too long for
+/// environment variable. The file uses the same grammar and inline options
+/// take precedence over the ones in file.
+fn merge_optsfile(opts: Vec<(String, String)>) -> anyhow::Result<Vec<(String, String)>> {
+    let (files, inline): (Vec<_>, Vec<_>) = opts.into_iter().partition(|(k, _)| k == "optsfile");
+    let mut merged = vec![];
+    for (_, path) in files {
+        let content = std::fs::read_to_string(&path).with_context(|| format!("read {path}"))?;
+        let file_opts = parse_sip003_options(content.trim_end_matches(['\r', '\n']))
+            .with_context(|| format!("parse {path}"))?;
+        if file_opts.iter().any(|(k, _)| k == "optsfile") {
+            bail!("nested optsfile in {path} is not supported");
+        }
+        merged.extend(file_opts);
+    }
+    // Latter ones win when collected into map.
+    merged.extend(inline);
+    Ok(merged)
+}
+
+/// Log received options(secrets masked) and warn about unrecognized keys.
+/// Return the unrecognized keys.
+fn log_sip003_options(opts: &[(String, String)]) -> Vec<&str> {
+    let masked: Vec<_> = opts
+        .iter()
+        .map(|(k, v)| match k.as_str() {
+            "passwd" | "salt" => (k.as_str(), "***"),
+            _ => (k.as_str(), v.as_str()),
+        })
+        .collect();
+    debug!("SIP003 options: {masked:?}");
+    let unknown: Vec<_> = opts
+        .iter()
+        .map(|(k, _)| k.as_str())
+        .filter(|k| !KNOWN_KEYS.contains(k))
+        .collect();
+    for key in unknown.iter() {
+        warn!("unrecognized SIP003 option {key} ignored");
+    }
+    unknown
+}
+
 // Parse SIP003 optinos from env
 fn parse_sip003_options(s: &str) -> Result<Vec<(String, String)>, anyhow::Error> {
     let mut opts = vec![];
@@ -103,8 +159,11 @@ fn parse_sip003_options(s: &str) -> Result<Vec<(String, String)>, anyhow::Error>
 
         // skip equals
         i += 1;
-        // read value
-        let (offset, value) = index_unescaped(&s[i..], &[b'=', b';']).context("read value")?;
+        // read value, it may be wrapped in double quotes
+        let (offset, value) = match index_quoted(&s[i..]) {
+            Some(quoted) => quoted,
+            None => index_unescaped(&s[i..], &[b'=', b';']).context("read value")?,
+        };
         i += offset;
         opts.push((key, value));
         // Skip the semicolon.
@@ -135,6 +194,34 @@ fn index_unescaped(s: &str, term: &[u8]) -> Result<(usize, String), anyhow::Erro
     Ok((i, String::from_utf8(unesc).unwrap()))
 }
 
+// Read a value wrapped in double quotes, escapes inside are still honored.
+// Return None if it is not quoted as a whole, i.e. it does not start with a
+// quote or the closing quote is missing or not followed by `;` or the end.
+fn index_quoted(s: &str) -> Option<(usize, String)> {
+    let bytes = s.as_bytes();
+    if bytes.first() != Some(&b'"') {
+        return None;
+    }
+    let mut i = 1;
+    let mut unesc = vec![];
+    while i < bytes.len() {
+        let mut b = bytes[i];
+        if b == b'"' {
+            return match bytes.get(i + 1) {
+                None | Some(b';') => Some((i + 1, String::from_utf8(unesc).unwrap())),
+                Some(_) => None,
+            };
+        }
+        if b == b'\\' {
+            i += 1;
+            b = *bytes.get(i)?;
+        }
+        unesc.push(b);
+        i += 1;
+    }
+    None
+}
+
 #[cfg(test)]
 #[test]
 fn test_parse_sip003_options() {
@@ -150,3 +237,68 @@ fn test_parse_sip003_options() {
         ]
     );
 }
+
+#[cfg(test)]
+#[test]
+fn test_parse_sip003_quoted_options() {
+    let ret = parse_sip003_options(r#"passwd="a;b=c";tls="x\"y";v3"#).unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            ("passwd".to_string(), "a;b=c".to_string()),
+            ("tls".to_string(), "x\"y".to_string()),
+            ("v3".to_string(), "1".to_string()),
+        ]
+    );
+    // values only starting or containing a quote are kept as is
+    let ret = parse_sip003_options(r#"passwd="ab"c;salt=a"b""#).unwrap();
+    assert_eq!(ret[0].1, "\"ab\"c");
+    assert_eq!(ret[1].1, "a\"b\"");
+    // unbalanced quote is not a quoted value
+    let ret = parse_sip003_options(r#"passwd="a;b=c"#).unwrap();
+    assert_eq!(
+        ret,
+        vec![
+            ("passwd".to_string(), "\"a".to_string()),
+            ("b".to_string(), "c".to_string()),
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_unknown_sip003_options() {
+    let opts = parse_sip003_options("server;pasword=123;threads=2;v3;tls=a.com").unwrap();
+    let (logs, guard) = crate::util::test_util::capture_logs();
+    assert_eq!(log_sip003_options(&opts), vec!["pasword"]);
+    drop(guard);
+    let logs = logs.contents();
+    assert!(logs
+        .lines()
+        .any(|l| l.contains("WARN") && l.contains("unrecognized SIP003 option pasword ignored")));
+    // known options are not warned about
+    assert_eq!(logs.lines().count(), 1);
+    let opts = build_opts(&opts.into_iter().collect());
+    assert_eq!(opts.threads, Some(2));
+    assert!(opts.v3);
+}
+
+#[cfg(test)]
+#[test]
+fn test_merge_sip003_optsfile() {
+    let path = env::temp_dir().join(format!("shadow-tls-optsfile-{}", std::process::id()));
+    std::fs::write(&path, "passwd=from\\;file;threads=4;salt=s\n").unwrap();
+    let opts = parse_sip003_options(&format!(
+        "server;threads=2;optsfile={};tls=a.com",
+        path.display()
+    ))
+    .unwrap();
+    let opts: HashMap<_, _> = merge_optsfile(opts).unwrap().into_iter().collect();
+    std::fs::remove_file(&path).unwrap();
+    assert!(!opts.contains_key("optsfile"));
+    assert_eq!(opts["passwd"], "from;file");
+    assert_eq!(opts["salt"], "s");
+    // inline options take precedence
+    assert_eq!(opts["threads"], "2");
+    assert_eq!(opts["tls"], "a.com");
+}
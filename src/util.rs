@@ -1,62 +1,1236 @@
-use std::{ptr::copy_nonoverlapping, time::Duration};
+use std::{
+    cell::{Cell, RefCell},
+    future::Future,
+    net::ToSocketAddrs,
+    ptr::copy_nonoverlapping,
+    rc::Rc,
+    task::{Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use byteorder::{BigEndian, WriteBytesExt};
+use local_sync::oneshot::{Receiver, Sender};
+use monoio::{
+    buf::{IoBuf, IoBufMut, IoVecBuf, IoVecBufMut},
+    io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt, Split, Splitable},
+    net::TcpStream,
+};
+
+use hmac::Mac;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use prelude::*;
+
+pub mod prelude {
+    pub const TLS_MAJOR: u8 = 0x03;
+    pub const TLS_MINOR: (u8, u8) = (0x03, 0x01);
+    pub const SNI_EXT_TYPE: u16 = 0;
+    pub const SUPPORTED_VERSIONS_TYPE: u16 = 43;
+    pub const TLS_RANDOM_SIZE: usize = 32;
+    pub const TLS_HEADER_SIZE: usize = 5;
+    pub const TLS_SESSION_ID_SIZE: usize = 32;
+    pub const TLS_13: u16 = 0x0304;
+
+    pub const CLIENT_HELLO: u8 = 0x01;
+    pub const SERVER_HELLO: u8 = 0x02;
+    pub const ALERT: u8 = 0x15;
+    pub const HANDSHAKE: u8 = 0x16;
+    pub const APPLICATION_DATA: u8 = 0x17;
+    pub const CHANGE_CIPHER_SPEC: u8 = 0x14;
+
+    pub const SERVER_RANDOM_IDX: usize = TLS_HEADER_SIZE + 1 + 3 + 2;
+    pub const SESSION_ID_LEN_IDX: usize = TLS_HEADER_SIZE + 1 + 3 + 2 + TLS_RANDOM_SIZE;
+    pub const TLS_HMAC_HEADER_SIZE: usize = TLS_HEADER_SIZE + HMAC_SIZE;
+
+    pub const COPY_BUF_SIZE: usize = 4096;
+    pub const HMAC_SIZE: usize = 4;
+}
+
+pub async fn copy_until_eof<R, W>(mut read_half: R, mut write_half: W) -> std::io::Result<()>
+where
+    R: monoio::io::AsyncReadRent,
+    W: monoio::io::AsyncWriteRent,
+{
+    let copy_result = monoio::io::copy(&mut read_half, &mut write_half).await;
+    let _ = write_half.shutdown().await;
+    copy_result?;
+    Ok(())
+}
+
+pub async fn copy_bidirectional<L, R>(l: &mut L, r: &mut R)
+where
+    L: AsyncReadRent + AsyncWriteRent + Split,
+    R: AsyncReadRent + AsyncWriteRent + Split,
+{
+    let (lr, lw) = l.split();
+    let (rr, rw) = r.split();
+    let _ = monoio::join!(copy_until_eof(lr, rw), copy_until_eof(rr, lw));
+}
+
+/// Limit of concurrent connections, shared by all worker threads.
+pub struct ConnLimit {
+    current: std::sync::atomic::AtomicUsize,
+    max: usize,
+}
+
+/// Release the slot when dropped.
+pub struct ConnLimitGuard(std::sync::Arc<ConnLimit>);
+
+impl ConnLimit {
+    pub fn new(max: usize) -> std::sync::Arc<Self> {
+        std::sync::Arc::new(Self {
+            current: Default::default(),
+            max,
+        })
+    }
+
+    /// Take a slot if not full.
+    pub fn try_acquire(self: &std::sync::Arc<Self>) -> Option<ConnLimitGuard> {
+        use std::sync::atomic::Ordering;
+        self.current
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < self.max).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| ConnLimitGuard(self.clone()))
+    }
+
+    #[cfg(test)]
+    pub fn current(&self) -> usize {
+        self.current.load(std::sync::atomic::Ordering::Acquire)
+    }
+}
+
+impl Drop for ConnLimitGuard {
+    fn drop(&mut self) {
+        self.0
+            .current
+            .fetch_sub(1, std::sync::atomic::Ordering::AcqRel);
+    }
+}
+
+/// Timeouts for every single read or write operation of a connection.
+///
+/// A stalled operation can not be cancelled alone because the buffer is owned
+/// by it, so the whole connection future should be run with `guard`, which
+/// drops it once any operation of the wrapped streams exceeds its timeout.
+pub struct OpTimeout {
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    states: RefCell<Vec<Rc<OpState>>>,
+}
+
+#[derive(Default)]
+struct OpState {
+    reading_since: Cell<Option<Instant>>,
+    writing_since: Cell<Option<Instant>>,
+}
+
+/// Mark an operation running until dropped.
+struct OpGuard<'a>(Option<&'a Cell<Option<Instant>>>);
+
+impl<'a> OpGuard<'a> {
+    fn new(cell: &'a Cell<Option<Instant>>, enabled: bool) -> Self {
+        if !enabled {
+            return Self(None);
+        }
+        cell.set(Some(Instant::now()));
+        Self(Some(cell))
+    }
+}
+
+impl Drop for OpGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(cell) = self.0 {
+            cell.set(None);
+        }
+    }
+}
+
+impl OpTimeout {
+    pub fn new(read_timeout: Option<Duration>, write_timeout: Option<Duration>) -> Self {
+        Self {
+            read_timeout,
+            write_timeout,
+            states: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Wrap the stream so its operations are watched.
+    pub fn wrap<S>(&self, raw: S) -> TimeoutStream<S> {
+        let state = Rc::new(OpState::default());
+        self.states.borrow_mut().push(state.clone());
+        TimeoutStream {
+            raw,
+            state,
+            read: self.read_timeout.is_some(),
+            write: self.write_timeout.is_some(),
+        }
+    }
+
+    /// Run the future until it finishes or any operation times out.
+    /// Return None on timeout.
+    pub async fn guard<F: Future>(&self, f: F) -> Option<F::Output> {
+        if self.read_timeout.is_none() && self.write_timeout.is_none() {
+            return Some(f.await);
+        }
+        monoio::select! {
+            r = f => Some(r),
+            op = self.expired() => {
+                tracing::warn!("{op} operation timeout, close the connection");
+                None
+            }
+        }
+    }
+
+    async fn expired(&self) -> &'static str {
+        const MIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+        let interval = [self.read_timeout, self.write_timeout]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|t| (t / 4).max(MIN_CHECK_INTERVAL))
+            .unwrap_or(MIN_CHECK_INTERVAL);
+        let exceeded = |since: Option<Instant>, timeout: Option<Duration>| match (since, timeout) {
+            (Some(since), Some(timeout)) => since.elapsed() >= timeout,
+            _ => false,
+        };
+        loop {
+            monoio::time::sleep(interval).await;
+            for state in self.states.borrow().iter() {
+                if exceeded(state.reading_since.get(), self.read_timeout) {
+                    return "read";
+                }
+                if exceeded(state.writing_since.get(), self.write_timeout) {
+                    return "write";
+                }
+            }
+        }
+    }
+}
+
+/// A stream wrapper which reports its running operations to OpTimeout.
+pub struct TimeoutStream<S> {
+    raw: S,
+    state: Rc<OpState>,
+    read: bool,
+    write: bool,
+}
+
+// # Safety
+// Read and write states are independent, so if S is Split, Self is Split.
+unsafe impl<S: Split> Split for TimeoutStream<S> {}
+
+impl<S: AsyncReadRent> AsyncReadRent for TimeoutStream<S> {
+    type ReadFuture<'a, B> = impl std::future::Future<Output = monoio::BufResult<usize, B>> +'a where
+        B: IoBufMut + 'a, S: 'a;
+    type ReadvFuture<'a, B> = impl std::future::Future<Output = monoio::BufResult<usize, B>> +'a where
+        B: IoVecBufMut + 'a, S: 'a;
+
+    fn read<T: IoBufMut>(&mut self, buf: T) -> Self::ReadFuture<'_, T> {
+        async move {
+            let _guard = OpGuard::new(&self.state.reading_since, self.read);
+            self.raw.read(buf).await
+        }
+    }
+
+    fn readv<T: IoVecBufMut>(&mut self, buf: T) -> Self::ReadvFuture<'_, T> {
+        async move {
+            let _guard = OpGuard::new(&self.state.reading_since, self.read);
+            self.raw.readv(buf).await
+        }
+    }
+}
+
+impl<S: AsyncWriteRent> AsyncWriteRent for TimeoutStream<S> {
+    type WriteFuture<'a, T> = impl std::future::Future<Output = monoio::BufResult<usize, T>> +'a where
+        T: IoBuf + 'a, S: 'a;
+    type WritevFuture<'a, T> = impl std::future::Future<Output = monoio::BufResult<usize, T>> +'a where
+        T: IoVecBuf + 'a, S: 'a;
+    type FlushFuture<'a> = S::FlushFuture<'a> where Self: 'a;
+    type ShutdownFuture<'a> = S::ShutdownFuture<'a> where Self: 'a;
+
+    fn write<T: IoBuf>(&mut self, buf: T) -> Self::WriteFuture<'_, T> {
+        async move {
+            let _guard = OpGuard::new(&self.state.writing_since, self.write);
+            self.raw.write(buf).await
+        }
+    }
+
+    fn writev<T: IoVecBuf>(&mut self, buf_vec: T) -> Self::WritevFuture<'_, T> {
+        async move {
+            let _guard = OpGuard::new(&self.state.writing_since, self.write);
+            self.raw.writev(buf_vec).await
+        }
+    }
+
+    fn flush(&mut self) -> Self::FlushFuture<'_> {
+        self.raw.flush()
+    }
+
+    fn shutdown(&mut self) -> Self::ShutdownFuture<'_> {
+        self.raw.shutdown()
+    }
+}
+
+/// How relayed bytes are counted against the limit.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ByteLimitMode {
+    /// Sum of both directions
+    #[default]
+    Total,
+    /// Each direction separately
+    Each,
+}
+
+impl std::fmt::Display for ByteLimitMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Total => write!(f, "total"),
+            Self::Each => write!(f, "each direction"),
+        }
+    }
+}
+
+/// Limit of bytes relayed by a connection, 0 means unlimited.
+///
+/// Like `OpTimeout`, the connection future should be run with `guard`, which
+/// drops it once the wrapped stream has read or written more than the limit.
+pub struct ByteLimit {
+    state: Rc<ByteState>,
+}
+
+struct ByteState {
+    max: u64,
+    mode: ByteLimitMode,
+    read: Cell<u64>,
+    written: Cell<u64>,
+    exceeded: Cell<bool>,
+    /// Waker of the guard, for streams driven outside the guarded future.
+    waker: RefCell<Option<Waker>>,
+}
+
+impl ByteState {
+    fn add(&self, counter: &Cell<u64>, n: usize) {
+        counter.set(counter.get() + n as u64);
+        let (read, written) = (self.read.get(), self.written.get());
+        let exceeded = match self.mode {
+            ByteLimitMode::Total => read + written > self.max,
+            ByteLimitMode::Each => read.max(written) > self.max,
+        };
+        if self.max != 0 && exceeded && !self.exceeded.replace(true) {
+            if let Some(waker) = self.waker.borrow_mut().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl Drop for ByteState {
+    fn drop(&mut self) {
+        // counted once per connection, so the relay never touches the
+        // shared counter
+        crate::metrics::METRICS.bytes_relayed.fetch_add(
+            self.read.get() + self.written.get(),
+            std::sync::atomic::Ordering::Relaxed,
+        );
+    }
+}
+
+impl ByteLimit {
+    pub fn new(max: u64, mode: ByteLimitMode) -> Self {
+        Self {
+            state: Rc::new(ByteState {
+                max,
+                mode,
+                read: Cell::new(0),
+                written: Cell::new(0),
+                exceeded: Cell::new(false),
+                waker: RefCell::new(None),
+            }),
+        }
+    }
+
+    /// Wrap the stream so its traffic is counted.
+    pub fn wrap<S>(&self, raw: S) -> LimitedStream<S> {
+        LimitedStream {
+            raw,
+            state: self.state.clone(),
+        }
+    }
+
+    /// Run the future until it finishes or the limit is exceeded.
+    /// Return None if exceeded.
+    pub async fn guard<F: Future>(&self, f: F) -> Option<F::Output> {
+        if self.state.max == 0 {
+            return Some(f.await);
+        }
+        let mut f = std::pin::pin!(f);
+        // The limit is mostly exceeded while polling f, whose wake to the
+        // same task may be lost(like the main future of block_on), so the
+        // state is checked right after polling instead of waiting for a wake.
+        let guarded = std::future::poll_fn(|cx| {
+            if !self.state.exceeded.get() {
+                if let Poll::Ready(r) = f.as_mut().poll(cx) {
+                    return Poll::Ready(Some(r));
+                }
+            }
+            if self.state.exceeded.get() {
+                return Poll::Ready(None);
+            }
+            *self.state.waker.borrow_mut() = Some(cx.waker().clone());
+            Poll::Pending
+        });
+        match guarded.await {
+            Some(r) => Some(r),
+            None => {
+                tracing::warn!(
+                    "relayed {} bytes(read {}, written {}), exceeding limit {}({}), close the connection",
+                    self.state.read.get() + self.state.written.get(),
+                    self.state.read.get(),
+                    self.state.written.get(),
+                    self.state.max,
+                    self.state.mode
+                );
+                None
+            }
+        }
+    }
+}
+
+pub struct LimitedStream<S> {
+    raw: S,
+    state: Rc<ByteState>,
+}
+
+// # Safety
+// Counters are only updated after operations finish, so if S is Split, Self is Split.
+unsafe impl<S: Split> Split for LimitedStream<S> {}
+
+impl<S: AsyncReadRent> AsyncReadRent for LimitedStream<S> {
+    type ReadFuture<'a, B> = impl std::future::Future<Output = monoio::BufResult<usize, B>> +'a where
+        B: IoBufMut + 'a, S: 'a;
+    type ReadvFuture<'a, B> = impl std::future::Future<Output = monoio::BufResult<usize, B>> +'a where
+        B: IoVecBufMut + 'a, S: 'a;
+
+    fn read<T: IoBufMut>(&mut self, buf: T) -> Self::ReadFuture<'_, T> {
+        async move {
+            let (res, buf) = self.raw.read(buf).await;
+            if let Ok(n) = res {
+                self.state.add(&self.state.read, n);
+            }
+            (res, buf)
+        }
+    }
+
+    fn readv<T: IoVecBufMut>(&mut self, buf: T) -> Self::ReadvFuture<'_, T> {
+        async move {
+            let (res, buf) = self.raw.readv(buf).await;
+            if let Ok(n) = res {
+                self.state.add(&self.state.read, n);
+            }
+            (res, buf)
+        }
+    }
+}
+
+impl<S: AsyncWriteRent> AsyncWriteRent for LimitedStream<S> {
+    type WriteFuture<'a, T> = impl std::future::Future<Output = monoio::BufResult<usize, T>> +'a where
+        T: IoBuf + 'a, S: 'a;
+    type WritevFuture<'a, T> = impl std::future::Future<Output = monoio::BufResult<usize, T>> +'a where
+        T: IoVecBuf + 'a, S: 'a;
+    type FlushFuture<'a> = S::FlushFuture<'a> where Self: 'a;
+    type ShutdownFuture<'a> = S::ShutdownFuture<'a> where Self: 'a;
+
+    fn write<T: IoBuf>(&mut self, buf: T) -> Self::WriteFuture<'_, T> {
+        async move {
+            let (res, buf) = self.raw.write(buf).await;
+            if let Ok(n) = res {
+                self.state.add(&self.state.written, n);
+            }
+            (res, buf)
+        }
+    }
+
+    fn writev<T: IoVecBuf>(&mut self, buf_vec: T) -> Self::WritevFuture<'_, T> {
+        async move {
+            let (res, buf_vec) = self.raw.writev(buf_vec).await;
+            if let Ok(n) = res {
+                self.state.add(&self.state.written, n);
+            }
+            (res, buf_vec)
+        }
+    }
+
+    fn flush(&mut self) -> Self::FlushFuture<'_> {
+        self.raw.flush()
+    }
+
+    fn shutdown(&mut self) -> Self::ShutdownFuture<'_> {
+        self.raw.shutdown()
+    }
+}
+
+/// Range of random delay in milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JitterRange {
+    min: u64,
+    max: u64,
+}
+
+impl JitterRange {
+    pub fn sample(&self) -> Duration {
+        Duration::from_millis(rand::thread_rng().gen_range(self.min..=self.max))
+    }
+
+    /// Format as the command line argument it is parsed from.
+    pub fn to_arg(self) -> String {
+        format!("{}-{}", self.min, self.max)
+    }
+}
+
+/// Parse "MIN-MAX" or "MAX"(from 0) in milliseconds.
+pub fn parse_jitter_range(arg: &str) -> anyhow::Result<JitterRange> {
+    let (min, max) = match arg.split_once('-') {
+        Some((min, max)) => (min.trim().parse()?, max.trim().parse()?),
+        None => (0, arg.trim().parse()?),
+    };
+    if min > max {
+        anyhow::bail!("invalid jitter range {arg}, min is larger than max");
+    }
+    Ok(JitterRange { min, max })
+}
+
+impl std::fmt::Display for JitterRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}ms", self.min, self.max)
+    }
+}
+
+/// Delay shutdown of the stream by a random jitter, reads and writes are
+/// not affected.
+pub struct JitterStream<S> {
+    raw: S,
+    jitter: Option<JitterRange>,
+}
+
+impl<S> JitterStream<S> {
+    pub fn new(raw: S, jitter: Option<JitterRange>) -> Self {
+        Self { raw, jitter }
+    }
+}
+
+// # Safety
+// Only shutdown is changed, so if S is Split, Self is Split.
+unsafe impl<S: Split> Split for JitterStream<S> {}
+
+impl<S: AsyncReadRent> AsyncReadRent for JitterStream<S> {
+    type ReadFuture<'a, B> = S::ReadFuture<'a, B> where
+        B: IoBufMut + 'a, S: 'a;
+    type ReadvFuture<'a, B> = S::ReadvFuture<'a, B> where
+        B: IoVecBufMut + 'a, S: 'a;
+
+    fn read<T: IoBufMut>(&mut self, buf: T) -> Self::ReadFuture<'_, T> {
+        self.raw.read(buf)
+    }
+
+    fn readv<T: IoVecBufMut>(&mut self, buf: T) -> Self::ReadvFuture<'_, T> {
+        self.raw.readv(buf)
+    }
+}
+
+impl<S: AsyncWriteRent> AsyncWriteRent for JitterStream<S> {
+    type WriteFuture<'a, T> = S::WriteFuture<'a, T> where
+        T: IoBuf + 'a, S: 'a;
+    type WritevFuture<'a, T> = S::WritevFuture<'a, T> where
+        T: IoVecBuf + 'a, S: 'a;
+    type FlushFuture<'a> = S::FlushFuture<'a> where Self: 'a;
+    type ShutdownFuture<'a> = impl std::future::Future<Output = std::io::Result<()>> + 'a where Self: 'a;
+
+    fn write<T: IoBuf>(&mut self, buf: T) -> Self::WriteFuture<'_, T> {
+        self.raw.write(buf)
+    }
+
+    fn writev<T: IoVecBuf>(&mut self, buf_vec: T) -> Self::WritevFuture<'_, T> {
+        self.raw.writev(buf_vec)
+    }
+
+    fn flush(&mut self) -> Self::FlushFuture<'_> {
+        self.raw.flush()
+    }
+
+    fn shutdown(&mut self) -> Self::ShutdownFuture<'_> {
+        async move {
+            if let Some(jitter) = self.jitter {
+                monoio::time::sleep(jitter.sample()).await;
+            }
+            self.raw.shutdown().await
+        }
+    }
+}
+
+/// Record when the first byte is written to the stream.
+pub struct FirstByteStream<S> {
+    raw: S,
+    sent_at: Rc<Cell<Option<Instant>>>,
+}
+
+impl<S> FirstByteStream<S> {
+    pub fn new(raw: S) -> Self {
+        Self {
+            raw,
+            sent_at: Default::default(),
+        }
+    }
+
+    /// When the first byte is written, shared with the stream.
+    pub fn sent_at(&self) -> Rc<Cell<Option<Instant>>> {
+        self.sent_at.clone()
+    }
+
+    pub fn into_inner(self) -> S {
+        self.raw
+    }
+
+    fn record(&self, res: &std::io::Result<usize>) {
+        if matches!(res, Ok(n) if *n > 0) && self.sent_at.get().is_none() {
+            self.sent_at.set(Some(Instant::now()));
+        }
+    }
+}
+
+// # Safety
+// Only writes are recorded, so if S is Split, Self is Split.
+unsafe impl<S: Split> Split for FirstByteStream<S> {}
+
+impl<S: AsyncReadRent> AsyncReadRent for FirstByteStream<S> {
+    type ReadFuture<'a, B> = S::ReadFuture<'a, B> where
+        B: IoBufMut + 'a, S: 'a;
+    type ReadvFuture<'a, B> = S::ReadvFuture<'a, B> where
+        B: IoVecBufMut + 'a, S: 'a;
+
+    fn read<T: IoBufMut>(&mut self, buf: T) -> Self::ReadFuture<'_, T> {
+        self.raw.read(buf)
+    }
+
+    fn readv<T: IoVecBufMut>(&mut self, buf: T) -> Self::ReadvFuture<'_, T> {
+        self.raw.readv(buf)
+    }
+}
+
+impl<S: AsyncWriteRent> AsyncWriteRent for FirstByteStream<S> {
+    type WriteFuture<'a, T> = impl Future<Output = monoio::BufResult<usize, T>> + 'a where
+        T: IoBuf + 'a, S: 'a;
+    type WritevFuture<'a, T> = impl Future<Output = monoio::BufResult<usize, T>> + 'a where
+        T: IoVecBuf + 'a, S: 'a;
+    type FlushFuture<'a> = S::FlushFuture<'a> where Self: 'a;
+    type ShutdownFuture<'a> = S::ShutdownFuture<'a> where Self: 'a;
+
+    fn write<T: IoBuf>(&mut self, buf: T) -> Self::WriteFuture<'_, T> {
+        async move {
+            let (res, buf) = self.raw.write(buf).await;
+            self.record(&res);
+            (res, buf)
+        }
+    }
+
+    fn writev<T: IoVecBuf>(&mut self, buf_vec: T) -> Self::WritevFuture<'_, T> {
+        async move {
+            let (res, buf_vec) = self.raw.writev(buf_vec).await;
+            self.record(&res);
+            (res, buf_vec)
+        }
+    }
+
+    fn flush(&mut self) -> Self::FlushFuture<'_> {
+        self.raw.flush()
+    }
+
+    fn shutdown(&mut self) -> Self::ShutdownFuture<'_> {
+        self.raw.shutdown()
+    }
+}
+
+const RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const RETRY_MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Run f and retry it with exponential backoff if it fails.
+pub async fn retry_with_backoff<F, Fut, T>(retries: u8, mut f: F) -> std::io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::io::Result<T>>,
+{
+    let mut backoff = RETRY_INITIAL_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Ok(r) => return Ok(r),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                tracing::debug!("attempt {attempt} failed: {e}, retry after {backoff:?}");
+                monoio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(RETRY_MAX_BACKOFF);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Which address family to try first when a name resolves to both.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResolvePreference {
+    /// Keep the order returned by the system resolver
+    #[default]
+    System,
+    /// Prefer IPv4 addresses
+    Ipv4,
+    /// Prefer IPv6 addresses
+    Ipv6,
+}
+
+impl std::fmt::Display for ResolvePreference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::System => write!(f, "system"),
+            Self::Ipv4 => write!(f, "ipv4"),
+            Self::Ipv6 => write!(f, "ipv6"),
+        }
+    }
+}
+
+/// Address resolved and reordered by preference on every connect attempt,
+/// the order within one family is kept.
+pub struct PreferredAddr<A>(pub A, pub ResolvePreference);
+
+impl<A: ToSocketAddrs> ToSocketAddrs for PreferredAddr<A> {
+    type Iter = std::vec::IntoIter<std::net::SocketAddr>;
+
+    fn to_socket_addrs(&self) -> std::io::Result<Self::Iter> {
+        let mut addrs: Vec<_> = self.0.to_socket_addrs()?.collect();
+        sort_by_preference(&mut addrs, self.1);
+        Ok(addrs.into_iter())
+    }
+}
+
+fn sort_by_preference(addrs: &mut [std::net::SocketAddr], preference: ResolvePreference) {
+    match preference {
+        ResolvePreference::System => (),
+        ResolvePreference::Ipv4 => addrs.sort_by_key(|a| !a.is_ipv4()),
+        ResolvePreference::Ipv6 => addrs.sort_by_key(|a| !a.is_ipv6()),
+    }
+}
+
+/// Socket addresses of names("host:port") resolved once at startup, DNS is
+/// never queried again for them.
+#[derive(Debug, Default)]
+pub struct FrozenDns(rustc_hash::FxHashMap<String, Vec<std::net::SocketAddr>>);
+
+impl FrozenDns {
+    /// Resolve all names. If lenient, names failed to resolve are warned
+    /// and left to be resolved on every connect, or it fails on the first one.
+    pub fn resolve<'a>(
+        names: impl IntoIterator<Item = &'a str>,
+        lenient: bool,
+    ) -> anyhow::Result<Self> {
+        let mut resolved = rustc_hash::FxHashMap::default();
+        for name in names {
+            let addrs = name
+                .to_socket_addrs()
+                .map(Iterator::collect::<Vec<_>>)
+                .map_err(|e| anyhow::anyhow!("unable to resolve {name}: {e}"))
+                .and_then(|addrs| match addrs.is_empty() {
+                    true => Err(anyhow::anyhow!("unable to resolve {name}: no address")),
+                    false => Ok(addrs),
+                });
+            match addrs {
+                Ok(addrs) => {
+                    resolved.insert(name.to_string(), addrs);
+                }
+                Err(e) if lenient => tracing::warn!("{e}, it will be resolved on every connect"),
+                Err(e) => return Err(e),
+            }
+        }
+        tracing::info!("DNS frozen for {} names", resolved.len());
+        Ok(Self(resolved))
+    }
+
+    /// Frozen addresses of the name, or resolve it now if not frozen.
+    pub fn lookup(&self, name: &str) -> std::io::Result<Vec<std::net::SocketAddr>> {
+        match self.0.get(name) {
+            Some(addrs) => Ok(addrs.clone()),
+            None => name.to_socket_addrs().map(Iterator::collect),
+        }
+    }
+}
+
+/// Address taken from `FrozenDns` if frozen there, otherwise resolved on
+/// every connect.
+#[derive(Clone, Debug)]
+pub struct FrozenAddr {
+    name: String,
+    frozen: Option<Vec<std::net::SocketAddr>>,
+}
+
+impl FrozenAddr {
+    pub fn new(name: String, dns: &FrozenDns) -> Self {
+        let frozen = dns.0.get(&name).cloned();
+        Self { name, frozen }
+    }
+}
+
+impl ToSocketAddrs for FrozenAddr {
+    type Iter = std::vec::IntoIter<std::net::SocketAddr>;
+
+    fn to_socket_addrs(&self) -> std::io::Result<Self::Iter> {
+        match &self.frozen {
+            Some(addrs) => Ok(addrs.clone().into_iter()),
+            None => Ok(self.name.to_socket_addrs()?.collect::<Vec<_>>().into_iter()),
+        }
+    }
+}
+
+impl std::fmt::Display for FrozenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Connect to addr with retries.
+/// If timeout is given, the whole process(including retries) is bounded by it.
+pub async fn connect_with_retry<A: ToSocketAddrs>(
+    addr: A,
+    retries: u8,
+    timeout: Option<Duration>,
+) -> std::io::Result<TcpStream> {
+    let connect = retry_with_backoff(retries, || TcpStream::connect(&addr));
+    match timeout {
+        Some(timeout) => monoio::time::timeout(timeout, connect)
+            .await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timeout"))?,
+        None => connect.await,
+    }
+}
+
+/// Interval of checking whether the peer has closed during relay setup.
+const CLOSE_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Abandon relay setup once the local connection is closed before sending
+/// anything.
+///
+/// Data is only peeked, so nothing is consumed from the local connection.
+/// Once data is available, the setup always runs to the end.
+pub struct CloseWatch<'a> {
+    local: &'a TcpStream,
+    /// Shut down when abandoned, so its pending operations finish and the
+    /// connection is released once dropped.
+    upstream: Cell<Option<i32>>,
+}
+
+impl<'a> CloseWatch<'a> {
+    pub fn new(local: &'a TcpStream) -> Self {
+        Self {
+            local,
+            upstream: Cell::new(None),
+        }
+    }
+
+    /// Release the upstream connection too if abandoned.
+    pub fn watch(&self, upstream: &TcpStream) {
+        #[cfg(unix)]
+        self.upstream
+            .set(Some(std::os::unix::io::AsRawFd::as_raw_fd(upstream)));
+        #[cfg(not(unix))]
+        let _ = upstream;
+    }
+
+    /// Run the future until it finishes or the local connection is closed.
+    /// Return None if closed, then the future is dropped.
+    pub async fn run<F: Future>(&self, f: F) -> Option<F::Output> {
+        let closed = async {
+            peer_closed(self.local).await;
+            #[cfg(unix)]
+            if let Some(fd) = self.upstream.get() {
+                // the future still owns it, so the fd is valid here
+                unsafe { libc::shutdown(fd, libc::SHUT_RDWR) };
+            }
+        };
+        monoio::select! {
+            r = f => Some(r),
+            _ = closed => None,
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn peer_closed(conn: &TcpStream) {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = conn.as_raw_fd();
+    loop {
+        monoio::time::sleep(CLOSE_CHECK_INTERVAL).await;
+        let mut buf = [0u8; 1];
+        let ret = unsafe {
+            libc::recv(
+                fd,
+                buf.as_mut_ptr() as *mut _,
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        match ret {
+            0 => return,
+            n if n > 0 => return std::future::pending().await,
+            _ => match std::io::Error::last_os_error().kind() {
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted => (),
+                _ => return,
+            },
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn peer_closed(_conn: &TcpStream) {
+    std::future::pending().await
+}
+
+/// CPUs the current thread is allowed to run on(respecting cgroup cpuset).
+#[cfg(target_os = "linux")]
+pub fn allowed_cpus() -> std::io::Result<Vec<usize>> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let ret =
+        unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((0..libc::CPU_SETSIZE as usize)
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+        .collect())
+}
+
+/// Pin the current thread to the CPU.
+#[cfg(target_os = "linux")]
+pub fn pin_to_cpu(cpu: usize) -> std::io::Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+    let ret = unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn allowed_cpus() -> std::io::Result<Vec<usize>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "CPU affinity is only supported on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_to_cpu(_cpu: usize) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "CPU affinity is only supported on Linux",
+    ))
+}
+
+/// Move the current thread, and threads spawned by it later, into the network
+/// namespace at path(like "/var/run/netns/proxy").
+/// It should be called before any socket is created or thread is spawned.
+#[cfg(target_os = "linux")]
+pub fn enter_netns(path: &std::path::Path) -> anyhow::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::File::open(path)
+        .map_err(|e| anyhow::anyhow!("unable to open network namespace {}: {e}", path.display()))?;
+    if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+        let e = std::io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EPERM) => anyhow::bail!(
+                "entering network namespace {} failed, CAP_SYS_ADMIN is required: {e}",
+                path.display()
+            ),
+            Some(libc::EINVAL) => {
+                anyhow::bail!("{} is not a network namespace: {e}", path.display())
+            }
+            _ => anyhow::bail!("entering network namespace {} failed: {e}", path.display()),
+        }
+    }
+    Ok(())
+}
 
-use byteorder::{BigEndian, WriteBytesExt};
-use local_sync::oneshot::{Receiver, Sender};
-use monoio::{
-    buf::IoBufMut,
-    io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt, Splitable},
-    net::TcpStream,
-};
+#[cfg(not(target_os = "linux"))]
+pub fn enter_netns(_path: &std::path::Path) -> anyhow::Result<()> {
+    anyhow::bail!("network namespace is only supported on Linux")
+}
 
-use hmac::Mac;
-use rand::Rng;
-use sha2::{Digest, Sha256};
+/// Switch to the user and group, supplementary groups are reset to the
+/// user's ones(or only the group if user is not given).
+#[cfg(unix)]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> anyhow::Result<()> {
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+    let user = user.map(resolve_user).transpose()?;
+    let gid = match (group, &user) {
+        (Some(group), _) => resolve_group(group)?,
+        (None, Some((_, _, gid))) => *gid,
+        (None, None) => unreachable!(),
+    };
+    let not_permitted = |what: &str| {
+        let e = std::io::Error::last_os_error();
+        match e.kind() {
+            std::io::ErrorKind::PermissionDenied => {
+                anyhow::anyhow!("{what} failed, root privilege is required: {e}")
+            }
+            _ => anyhow::anyhow!("{what} failed: {e}"),
+        }
+    };
+    let ret = match &user {
+        Some((name, _, _)) => unsafe { libc::initgroups(name.as_ptr(), gid as _) },
+        None => unsafe { libc::setgroups(1, &gid) },
+    };
+    if ret != 0 {
+        return Err(not_permitted("setting supplementary groups"));
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(not_permitted("setgid"));
+    }
+    if let Some((_, uid, _)) = user {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(not_permitted("setuid"));
+        }
+    }
+    tracing::info!("privileges dropped to uid {}, gid {gid}", unsafe {
+        libc::getuid()
+    });
+    Ok(())
+}
 
-use prelude::*;
+#[cfg(not(unix))]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> anyhow::Result<()> {
+    if user.is_some() || group.is_some() {
+        anyhow::bail!("switching user or group is only supported on Unix");
+    }
+    Ok(())
+}
 
-pub mod prelude {
-    pub const TLS_MAJOR: u8 = 0x03;
-    pub const TLS_MINOR: (u8, u8) = (0x03, 0x01);
-    pub const SNI_EXT_TYPE: u16 = 0;
-    pub const SUPPORTED_VERSIONS_TYPE: u16 = 43;
-    pub const TLS_RANDOM_SIZE: usize = 32;
-    pub const TLS_HEADER_SIZE: usize = 5;
-    pub const TLS_SESSION_ID_SIZE: usize = 32;
-    pub const TLS_13: u16 = 0x0304;
+/// Resolve user name or uid to (name, uid, primary gid).
+#[cfg(unix)]
+fn resolve_user(user: &str) -> anyhow::Result<(std::ffi::CString, libc::uid_t, libc::gid_t)> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let ret = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        },
+        Err(_) => {
+            let name = std::ffi::CString::new(user)?;
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            }
+        }
+    };
+    if ret != 0 || result.is_null() {
+        anyhow::bail!("user {user} not found");
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(pwd.pw_name) }.to_owned();
+    Ok((name, pwd.pw_uid, pwd.pw_gid))
+}
 
-    pub const CLIENT_HELLO: u8 = 0x01;
-    pub const SERVER_HELLO: u8 = 0x02;
-    pub const ALERT: u8 = 0x15;
-    pub const HANDSHAKE: u8 = 0x16;
-    pub const APPLICATION_DATA: u8 = 0x17;
-    pub const CHANGE_CIPHER_SPEC: u8 = 0x14;
+/// Resolve group name or gid to gid.
+#[cfg(unix)]
+fn resolve_group(group: &str) -> anyhow::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let name = std::ffi::CString::new(group)?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let ret = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        anyhow::bail!("group {group} not found");
+    }
+    Ok(grp.gr_gid)
+}
 
-    pub const SERVER_RANDOM_IDX: usize = TLS_HEADER_SIZE + 1 + 3 + 2;
-    pub const SESSION_ID_LEN_IDX: usize = TLS_HEADER_SIZE + 1 + 3 + 2 + TLS_RANDOM_SIZE;
-    pub const TLS_HMAC_HEADER_SIZE: usize = TLS_HEADER_SIZE + HMAC_SIZE;
+#[cfg(unix)]
+fn sighup_set() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        set
+    }
+}
 
-    pub const COPY_BUF_SIZE: usize = 4096;
-    pub const HMAC_SIZE: usize = 4;
+/// Block SIGHUP in the current thread and threads spawned by it later, so
+/// it is only received by `spawn_sighup_handler`.
+/// It should be called before any thread is spawned.
+#[cfg(unix)]
+pub fn block_sighup() -> std::io::Result<()> {
+    let set = sighup_set();
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        e => Err(std::io::Error::from_raw_os_error(e)),
+    }
 }
 
-pub async fn copy_until_eof<R, W>(mut read_half: R, mut write_half: W) -> std::io::Result<()>
-where
-    R: monoio::io::AsyncReadRent,
-    W: monoio::io::AsyncWriteRent,
-{
-    let copy_result = monoio::io::copy(&mut read_half, &mut write_half).await;
-    let _ = write_half.shutdown().await;
-    copy_result?;
-    Ok(())
+/// Run f on a separate thread every time SIGHUP is received.
+#[cfg(unix)]
+pub fn spawn_sighup_handler(f: impl Fn() + Send + 'static) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let set = sighup_set();
+        let mut sig = 0;
+        loop {
+            if unsafe { libc::sigwait(&set, &mut sig) } == 0 {
+                f();
+            }
+        }
+    })
 }
 
-pub async fn copy_bidirectional(l: &mut TcpStream, r: &mut TcpStream) {
-    let (lr, lw) = l.split();
-    let (rr, rw) = r.split();
-    let _ = monoio::join!(copy_until_eof(lr, rw), copy_until_eof(rr, lw));
+/// Log TCP_INFO of the connection when dropped.
+///
+/// Holds a duplicated fd, so the socket is still readable even if the stream
+/// is dropped first.
+#[cfg(target_os = "linux")]
+pub struct TcpInfoProbe {
+    fd: std::os::unix::io::RawFd,
+    label: String,
+}
+
+#[cfg(target_os = "linux")]
+impl TcpInfoProbe {
+    pub fn new(conn: &TcpStream, label: String) -> Option<Self> {
+        use std::os::unix::io::AsRawFd;
+        let fd = unsafe { libc::dup(conn.as_raw_fd()) };
+        if fd < 0 {
+            tracing::debug!(
+                "unable to dup fd for TCP_INFO: {}",
+                std::io::Error::last_os_error()
+            );
+            return None;
+        }
+        Some(Self { fd, label })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for TcpInfoProbe {
+    fn drop(&mut self) {
+        match read_tcp_info(self.fd) {
+            Ok(info) => tracing::info!("{} {info}", self.label),
+            Err(e) => tracing::debug!("{} TCP_INFO unavailable: {e}", self.label),
+        }
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Prefix of struct tcp_info in linux/tcp.h.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct RawTcpInfo {
+    _u8_fields: [u8; 8],
+    // rto, ato, snd_mss, rcv_mss, unacked, sacked, lost, retrans, fackets,
+    // last_data_sent, last_ack_sent, last_data_recv, last_ack_recv,
+    // pmtu, rcv_ssthresh
+    _u32_fields1: [u32; 15],
+    rtt: u32,
+    rttvar: u32,
+    // snd_ssthresh, snd_cwnd, advmss, reordering, rcv_rtt, rcv_space
+    _u32_fields2: [u32; 6],
+    total_retrans: u32,
+    // pacing_rate, max_pacing_rate, bytes_acked, bytes_received
+    _u64_fields: [u64; 4],
+    // segs_out, segs_in, notsent_bytes
+    _u32_fields3: [u32; 3],
+    min_rtt: u32,
+    // data_segs_in, data_segs_out
+    _u32_fields4: [u32; 2],
+    delivery_rate: u64,
 }
 
-pub fn mod_tcp_conn(conn: &mut TcpStream, keepalive: bool, nodelay: bool) {
+/// Selected TCP_INFO fields.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpInfo {
+    pub rtt: Duration,
+    pub rttvar: Duration,
+    pub min_rtt: Duration,
+    pub total_retrans: u32,
+    /// Bytes per second, None if the kernel is too old to report it.
+    pub delivery_rate: Option<u64>,
+}
+
+#[cfg(target_os = "linux")]
+impl std::fmt::Display for TcpInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "tcp_info: rtt={:?} rttvar={:?} min_rtt={:?} retrans={}",
+            self.rtt, self.rttvar, self.min_rtt, self.total_retrans
+        )?;
+        if let Some(rate) = self.delivery_rate {
+            write!(f, " delivery_rate={rate}B/s")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_tcp_info(fd: std::os::unix::io::RawFd) -> std::io::Result<TcpInfo> {
+    let mut raw = RawTcpInfo::default();
+    let mut len = std::mem::size_of::<RawTcpInfo>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut raw as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(TcpInfo {
+        rtt: Duration::from_micros(raw.rtt as u64),
+        rttvar: Duration::from_micros(raw.rttvar as u64),
+        min_rtt: Duration::from_micros(raw.min_rtt as u64),
+        total_retrans: raw.total_retrans,
+        delivery_rate: (len as usize >= std::mem::size_of::<RawTcpInfo>())
+            .then_some(raw.delivery_rate),
+    })
+}
+
+pub fn mod_tcp_conn(
+    conn: &mut TcpStream,
+    keepalive: bool,
+    nodelay: bool,
+    user_timeout: Option<Duration>,
+    buffers: BufferSizes,
+) {
     if keepalive {
         let _ = conn.set_tcp_keepalive(
             Some(Duration::from_secs(90)),
@@ -65,6 +1239,116 @@ pub fn mod_tcp_conn(conn: &mut TcpStream, keepalive: bool, nodelay: bool) {
         );
     }
     let _ = conn.set_nodelay(nodelay);
+    if let Some(timeout) = user_timeout {
+        if let Err(e) = set_user_timeout(conn, timeout) {
+            tracing::debug!("unable to set TCP_USER_TIMEOUT: {e}");
+        }
+    }
+    #[cfg(unix)]
+    if let Err(e) = buffers.apply(std::os::unix::io::AsRawFd::as_raw_fd(conn)) {
+        tracing::debug!("unable to set socket buffer sizes: {e}");
+    }
+}
+
+/// Valid range of buffer sizes in bytes.
+const BUFFER_SIZE_RANGE: std::ops::RangeInclusive<usize> = 1024..=64 * 1024 * 1024;
+/// Userspace relay buffers are capped so data frames stay in the size of a
+/// TLS record.
+const MAX_RELAY_BUF_SIZE: usize = 16 * 1024;
+
+/// Parse buffer size in bytes.
+pub fn parse_buffer_size(arg: &str) -> anyhow::Result<usize> {
+    let size: usize = arg.parse()?;
+    if !BUFFER_SIZE_RANGE.contains(&size) {
+        anyhow::bail!(
+            "buffer size must be in {}..={}",
+            BUFFER_SIZE_RANGE.start(),
+            BUFFER_SIZE_RANGE.end()
+        );
+    }
+    Ok(size)
+}
+
+/// Receive and send buffer sizes, of both sockets(SO_RCVBUF and SO_SNDBUF)
+/// and userspace relay of data from and to the TLS peer. None keeps the
+/// default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferSizes {
+    pub recv: Option<usize>,
+    pub send: Option<usize>,
+}
+
+impl BufferSizes {
+    /// Bytes the relay reads at a time of data received from the TLS peer.
+    pub fn relay_recv(&self) -> usize {
+        self.recv
+            .map_or(COPY_BUF_SIZE, |size| size.min(MAX_RELAY_BUF_SIZE))
+    }
+
+    /// Initial buffer of the v3 frame decoder, the frames are read into. It
+    /// grows for larger frames, so it is kept small unless recv is set.
+    pub fn relay_decoder(&self) -> usize {
+        const INIT_BUFFER_SIZE: usize = 2048;
+        self.recv.map_or(INIT_BUFFER_SIZE, |_| self.relay_recv())
+    }
+
+    /// Bytes the relay reads at a time of data sent to the TLS peer.
+    pub fn relay_send(&self) -> usize {
+        self.send
+            .map_or(COPY_BUF_SIZE, |size| size.min(MAX_RELAY_BUF_SIZE))
+    }
+
+    /// Set socket buffer sizes, listeners pass them to accepted sockets.
+    #[cfg(unix)]
+    pub fn apply(&self, fd: std::os::unix::io::RawFd) -> std::io::Result<()> {
+        for (opt, size) in [(libc::SO_RCVBUF, self.recv), (libc::SO_SNDBUF, self.send)] {
+            let Some(size) = size else {
+                continue;
+            };
+            let size = size as libc::c_int;
+            let ret = unsafe {
+                libc::setsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    opt,
+                    &size as *const _ as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            if ret != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set TCP_USER_TIMEOUT, how long sent data may stay unacknowledged before
+/// the connection is dropped. Once keepalive probes start, it also replaces
+/// the probe count as the limit, so keep it above the keepalive interval or
+/// idle connections can be dropped by the first lost probe. Linux only.
+#[cfg(target_os = "linux")]
+fn set_user_timeout(conn: &TcpStream, timeout: Duration) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let ms = timeout.as_millis().min(libc::c_uint::MAX as u128) as libc::c_uint;
+    let ret = unsafe {
+        libc::setsockopt(
+            conn.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_USER_TIMEOUT,
+            &ms as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_uint>() as libc::socklen_t,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_user_timeout(_conn: &TcpStream, _timeout: Duration) -> std::io::Result<()> {
+    Ok(())
 }
 
 pub struct Hmac(hmac::Hmac<sha1::Sha1>);
@@ -116,12 +1400,61 @@ pub fn kdf(password: &str, server_random: &[u8]) -> Vec<u8> {
     hash.to_vec()
 }
 
-pub async fn verified_relay(
-    mut raw: TcpStream,
-    mut tls: TcpStream,
+/// Read password from the file, surrounding whitespaces are trimmed.
+pub fn read_password_file(path: &std::path::Path) -> anyhow::Result<String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("unable to read password file {}: {e}", path.display()))?;
+    let password = content.trim();
+    if password.is_empty() {
+        anyhow::bail!("password file {} is empty", path.display());
+    }
+    Ok(password.to_string())
+}
+
+/// Valid range of io_uring entries, the kernel limit is 32768.
+const URING_ENTRIES_RANGE: std::ops::RangeInclusive<u32> = 256..=32768;
+
+/// Parse io_uring entries, which must be a power of two in range.
+pub fn parse_uring_entries(arg: &str) -> anyhow::Result<u32> {
+    let entries: u32 = arg.parse()?;
+    if !entries.is_power_of_two() || !URING_ENTRIES_RANGE.contains(&entries) {
+        anyhow::bail!(
+            "io_uring entries must be a power of two in {}..={}",
+            URING_ENTRIES_RANGE.start(),
+            URING_ENTRIES_RANGE.end()
+        );
+    }
+    Ok(entries)
+}
+
+/// Mix salt into the password, all keys are derived from the result.
+/// Empty salt keeps the password unchanged for compatibility.
+pub fn salted_password(password: String, salt: Option<&str>) -> String {
+    match salt {
+        Some(salt) if !salt.is_empty() => {
+            let mut hmac: hmac::Hmac<Sha256> =
+                hmac::Hmac::new_from_slice(salt.as_bytes()).expect("unable to build hmac instance");
+            hmac.update(password.as_bytes());
+            hmac.finalize()
+                .into_bytes()
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect()
+        }
+        _ => password,
+    }
+}
+
+pub async fn verified_relay<R, T>(
+    mut raw: R,
+    mut tls: T,
     mut hmac_add: Hmac,
     mut hmac_verify: Hmac,
-) {
+    buffers: BufferSizes,
+) where
+    R: AsyncReadRent + AsyncWriteRent + Split,
+    T: AsyncReadRent + AsyncWriteRent + Split,
+{
     tracing::debug!("verified relay started");
     let (mut tls_read, mut tls_write) = tls.split();
     let (mut raw_read, mut raw_write) = raw.split();
@@ -133,12 +1466,20 @@ pub async fn verified_relay(
                 &mut raw_write,
                 &mut hmac_verify,
                 &mut notifier,
+                buffers,
             )
             .await;
             let _ = raw_write.shutdown().await;
         },
         async {
-            copy_add_appdata(&mut raw_read, &mut tls_write, &mut hmac_add, &mut notfied).await;
+            copy_add_appdata(
+                &mut raw_read,
+                &mut tls_write,
+                &mut hmac_add,
+                &mut notfied,
+                buffers,
+            )
+            .await;
             let _ = tls_write.shutdown().await;
         }
     );
@@ -151,10 +1492,9 @@ async fn copy_remove_appdata_and_verify(
     mut write: impl AsyncWriteRent,
     hmac: &mut Hmac,
     alert_notifier: &mut Receiver<()>,
+    buffers: BufferSizes,
 ) {
-    const INIT_BUFFER_SIZE: usize = 2048;
-
-    let mut decoder = BufferFrameDecoder::new(read, INIT_BUFFER_SIZE);
+    let mut decoder = BufferFrameDecoder::new(read, buffers.relay_decoder());
     loop {
         let maybe_frame = match decoder.next().await {
             Ok(f) => f,
@@ -209,11 +1549,12 @@ async fn copy_add_appdata(
     mut write: impl AsyncWriteRent,
     hmac: &mut Hmac,
     alert_notified: &mut Sender<()>,
+    buffers: BufferSizes,
 ) {
     const DEFAULT_DATA: [u8; TLS_HMAC_HEADER_SIZE] =
         [APPLICATION_DATA, TLS_MAJOR, TLS_MINOR.0, 0, 0, 0, 0, 0, 0];
 
-    let mut buffer = Vec::with_capacity(COPY_BUF_SIZE);
+    let mut buffer = Vec::with_capacity(buffers.relay_send());
     buffer.extend_from_slice(&DEFAULT_DATA);
 
     let alert_notified = alert_notified.closed();
@@ -400,3 +1741,606 @@ impl<T: AsyncReadRent> BufferFrameDecoder<T> {
         self.read_pos = 0;
     }
 }
+
+#[cfg(test)]
+pub mod test_util {
+    use std::{
+        future::{ready, Ready},
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    use monoio::{
+        buf::{IoBuf, IoVecBuf},
+        io::AsyncWriteRent,
+        net::TcpStream,
+        BufResult,
+    };
+
+    /// Connected TCP streams on loopback.
+    pub async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = monoio::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (conn, accepted) = monoio::join!(TcpStream::connect(addr), listener.accept());
+        (conn.unwrap(), accepted.unwrap().0)
+    }
+
+    /// Collect logs of the current thread until the guard is dropped.
+    pub fn capture_logs() -> (LogBuf, tracing::subscriber::DefaultGuard) {
+        capture_logs_at(tracing::Level::INFO)
+    }
+
+    /// Like capture_logs, also collect logs down to level.
+    pub fn capture_logs_at(level: tracing::Level) -> (LogBuf, tracing::subscriber::DefaultGuard) {
+        let logs = LogBuf::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .with_max_level(level)
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    #[derive(Clone, Default)]
+    pub struct LogBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl LogBuf {
+        pub fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    impl Write for LogBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A writer which collects all written data.
+    /// It accepts at most `chunk` bytes per write to simulate short writes.
+    pub struct VecWriter {
+        pub data: Vec<u8>,
+        /// Size of every write accepted.
+        pub writes: Vec<usize>,
+        chunk: usize,
+    }
+
+    impl VecWriter {
+        pub fn new() -> Self {
+            Self::with_chunk(usize::MAX)
+        }
+
+        pub fn with_chunk(chunk: usize) -> Self {
+            Self {
+                data: Vec::new(),
+                writes: Vec::new(),
+                chunk,
+            }
+        }
+    }
+
+    impl AsyncWriteRent for VecWriter {
+        type WriteFuture<'a, T> = Ready<BufResult<usize, T>> where
+            T: IoBuf + 'a;
+        type WritevFuture<'a, T> = Ready<BufResult<usize, T>> where
+            T: IoVecBuf + 'a;
+        type FlushFuture<'a> = Ready<std::io::Result<()>>;
+        type ShutdownFuture<'a> = Ready<std::io::Result<()>>;
+
+        fn write<T: IoBuf>(&mut self, buf: T) -> Self::WriteFuture<'_, T> {
+            let n = buf.bytes_init().min(self.chunk);
+            // Safety: the ptr is valid for bytes_init bytes.
+            self.data
+                .extend_from_slice(unsafe { std::slice::from_raw_parts(buf.read_ptr(), n) });
+            self.writes.push(n);
+            ready((Ok(n), buf))
+        }
+
+        fn writev<T: IoVecBuf>(&mut self, buf_vec: T) -> Self::WritevFuture<'_, T> {
+            ready((Err(std::io::ErrorKind::Unsupported.into()), buf_vec))
+        }
+
+        fn flush(&mut self) -> Self::FlushFuture<'_> {
+            ready(Ok(()))
+        }
+
+        fn shutdown(&mut self) -> Self::ShutdownFuture<'_> {
+            ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{test_util::tcp_pair, *};
+
+    #[cfg(target_os = "linux")]
+    #[monoio::test]
+    async fn tcp_user_timeout() {
+        use std::os::unix::io::AsRawFd;
+        let read = |conn: &TcpStream| {
+            let mut ms: libc::c_uint = 0;
+            let mut len = std::mem::size_of::<libc::c_uint>() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(
+                    conn.as_raw_fd(),
+                    libc::IPPROTO_TCP,
+                    libc::TCP_USER_TIMEOUT,
+                    &mut ms as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            assert_eq!(ret, 0);
+            ms
+        };
+        let (mut a, mut b) = tcp_pair().await;
+        let buffers = BufferSizes::default();
+        mod_tcp_conn(
+            &mut a,
+            true,
+            true,
+            Some(Duration::from_millis(1500)),
+            buffers,
+        );
+        mod_tcp_conn(&mut b, true, true, None, buffers);
+        assert_eq!(read(&a), 1500);
+        assert_eq!(read(&b), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[monoio::test]
+    async fn socket_buffer_sizes() {
+        use std::os::unix::io::AsRawFd;
+        let read = |conn: &TcpStream, opt| {
+            let mut size: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(
+                    conn.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    opt,
+                    &mut size as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            assert_eq!(ret, 0);
+            size as usize
+        };
+        let (mut a, _b) = tcp_pair().await;
+        let buffers = BufferSizes {
+            recv: Some(32 * 1024),
+            send: Some(8 * 1024),
+        };
+        mod_tcp_conn(&mut a, true, true, None, buffers);
+        // the kernel doubles the value for bookkeeping overhead
+        assert_eq!(read(&a, libc::SO_RCVBUF), 2 * 32 * 1024);
+        assert_eq!(read(&a, libc::SO_SNDBUF), 2 * 8 * 1024);
+    }
+
+    #[monoio::test]
+    async fn relay_buffer_sizes() {
+        use crate::helper_v2::{copy_with_application_data, copy_without_application_data};
+        use test_util::VecWriter;
+
+        let data: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let buffers = BufferSizes {
+            recv: Some(1024),
+            send: Some(2048),
+        };
+        assert_eq!(parse_buffer_size("2048").unwrap(), 2048);
+        assert!(parse_buffer_size("512").is_err());
+
+        // data sent to the peer is read in send bytes including the header
+        let mut framed = VecWriter::new();
+        copy_with_application_data::<0, _, _>(&mut data.as_slice(), &mut framed, None, buffers)
+            .await
+            .unwrap();
+        assert!(framed.writes.iter().all(|&n| n <= 2048));
+        assert_eq!(framed.data.len(), data.len() + 10 * TLS_HEADER_SIZE);
+        assert_eq!(&framed.data[3..5], &2043u16.to_be_bytes());
+
+        // data received from the peer is read in recv bytes
+        let mut plain = VecWriter::new();
+        copy_without_application_data(&mut framed.data.as_slice(), &mut plain, buffers)
+            .await
+            .unwrap();
+        assert_eq!(plain.data, data);
+        assert!(plain.writes.iter().all(|&n| n <= 1024));
+
+        // relay reads are capped at the size of a TLS record
+        let buffers = BufferSizes {
+            recv: Some(1 << 20),
+            send: Some(1 << 20),
+        };
+        assert_eq!(buffers.relay_recv(), 16 * 1024);
+        assert_eq!(buffers.relay_send(), 16 * 1024);
+        assert_eq!(buffers.relay_decoder(), 16 * 1024);
+        assert_eq!(BufferSizes::default().relay_decoder(), 2048);
+        let mut framed = VecWriter::new();
+        copy_with_application_data::<0, _, _>(&mut data.as_slice(), &mut framed, None, buffers)
+            .await
+            .unwrap();
+        assert_eq!(framed.writes[0], 16 * 1024);
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn connect_retry_until_server_up() {
+        // Find a free port, the server will listen on it later.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        // No server, no retry.
+        assert!(connect_with_retry(addr, 0, None).await.is_err());
+
+        let server = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            let listener = std::net::TcpListener::bind(addr).unwrap();
+            let _ = listener.accept().unwrap();
+        });
+        // Retry after 100ms, 200ms, 400ms...
+        let conn = connect_with_retry(addr, 5, Some(Duration::from_secs(10))).await;
+        assert!(conn.is_ok());
+        server.join().unwrap();
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn retry_count_and_timeout() {
+        let attempts = std::cell::Cell::new(0);
+        let res: std::io::Result<()> = retry_with_backoff(2, || async {
+            attempts.set(attempts.get() + 1);
+            Err(std::io::ErrorKind::ConnectionRefused.into())
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.get(), 3);
+
+        // Retrying takes much more time than the timeout.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let begin = std::time::Instant::now();
+        let res = connect_with_retry(addr, 10, Some(Duration::from_millis(200))).await;
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+        assert!(begin.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn uring_entries_range() {
+        assert_eq!(parse_uring_entries("256").unwrap(), 256);
+        assert_eq!(parse_uring_entries("32768").unwrap(), 32768);
+        for arg in ["128", "1000", "65536", "-1", "x"] {
+            assert!(parse_uring_entries(arg).is_err(), "{arg}");
+        }
+    }
+
+    #[test]
+    fn salt_separates_keys() {
+        let password = || "password".to_string();
+        assert_eq!(salted_password(password(), None), password());
+        assert_eq!(salted_password(password(), Some("")), password());
+
+        let auth = |salt| {
+            let key = salted_password(password(), salt);
+            Hmac::new(&key, (b"server random", b"C")).finalize()
+        };
+        assert_eq!(auth(Some("a")), auth(Some("a")));
+        assert_ne!(auth(Some("a")), auth(Some("b")));
+        assert_ne!(auth(Some("a")), auth(None));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pin_workers_to_distinct_cpus() {
+        let cpus = allowed_cpus().unwrap();
+        assert!(!cpus.is_empty());
+        let workers: Vec<_> = cpus
+            .iter()
+            .map(|&cpu| {
+                std::thread::spawn(move || {
+                    pin_to_cpu(cpu).unwrap();
+                    allowed_cpus().unwrap()
+                })
+            })
+            .collect();
+        let pinned: Vec<_> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+        assert_eq!(
+            pinned,
+            cpus.iter().map(|&cpu| vec![cpu]).collect::<Vec<_>>()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_user_and_group() {
+        let (name, uid, gid) = resolve_user("root").unwrap();
+        assert_eq!((name.to_str().unwrap(), uid, gid), ("root", 0, 0));
+        assert_eq!(resolve_user("0").unwrap().0.to_str().unwrap(), "root");
+        assert!(resolve_user("no-such-user-shadow-tls").is_err());
+        assert_eq!(resolve_group("root").unwrap(), 0);
+        assert_eq!(resolve_group("1234").unwrap(), 1234);
+        assert!(resolve_group("no-such-group-shadow-tls").is_err());
+        assert!(drop_privileges(None, None).is_ok());
+    }
+
+    #[test]
+    fn resolve_preference_order() {
+        let resolved: Vec<std::net::SocketAddr> = [
+            "1.1.1.1:443",
+            "[2606::1]:443",
+            "1.0.0.1:443",
+            "[2606::2]:443",
+        ]
+        .iter()
+        .map(|a| a.parse().unwrap())
+        .collect();
+        let sorted = |preference| {
+            let mut addrs = resolved.clone();
+            sort_by_preference(&mut addrs, preference);
+            addrs.iter().map(ToString::to_string).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            sorted(ResolvePreference::System),
+            [
+                "1.1.1.1:443",
+                "[2606::1]:443",
+                "1.0.0.1:443",
+                "[2606::2]:443"
+            ]
+        );
+        assert_eq!(
+            sorted(ResolvePreference::Ipv4),
+            [
+                "1.1.1.1:443",
+                "1.0.0.1:443",
+                "[2606::1]:443",
+                "[2606::2]:443"
+            ]
+        );
+        assert_eq!(
+            sorted(ResolvePreference::Ipv6),
+            [
+                "[2606::1]:443",
+                "[2606::2]:443",
+                "1.1.1.1:443",
+                "1.0.0.1:443"
+            ]
+        );
+        let preferred = PreferredAddr(&resolved[..], ResolvePreference::Ipv6);
+        assert_eq!(
+            preferred.to_socket_addrs().unwrap().next(),
+            Some(resolved[1])
+        );
+    }
+
+    #[test]
+    fn frozen_dns() {
+        let err = FrozenDns::resolve(["127.0.0.1:443", "nothing.invalid:443"], false)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("unable to resolve nothing.invalid:443"),
+            "{err}"
+        );
+        let dns = FrozenDns::resolve(["127.0.0.1:443", "nothing.invalid:443"], true).unwrap();
+        assert_eq!(dns.0.len(), 1);
+
+        // frozen names are never resolved again, so resolving them would fail
+        let frozen: std::net::SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let mut dns = FrozenDns::default();
+        dns.0.insert("frozen.invalid:443".to_string(), vec![frozen]);
+        assert_eq!(dns.lookup("frozen.invalid:443").unwrap(), vec![frozen]);
+        assert!(dns.lookup("other.invalid:443").is_err());
+        let addr = FrozenAddr::new("frozen.invalid:443".to_string(), &dns);
+        assert_eq!(
+            addr.to_socket_addrs().unwrap().collect::<Vec<_>>(),
+            vec![frozen]
+        );
+        assert_eq!(addr.to_string(), "frozen.invalid:443");
+        let addr = FrozenAddr::new("other.invalid:443".to_string(), &dns);
+        assert!(addr.to_socket_addrs().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn netns_path() {
+        let err = enter_netns(std::path::Path::new("/nonexistent/netns"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unable to open network namespace"), "{err}");
+        let err = enter_netns(std::path::Path::new("/dev/null"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("is not a network namespace"), "{err}");
+        // entering the current namespace changes nothing, if permitted
+        let current = std::thread::spawn(|| enter_netns("/proc/self/ns/net".as_ref()))
+            .join()
+            .unwrap();
+        if let Err(e) = current {
+            assert!(e.to_string().contains("CAP_SYS_ADMIN"), "{e}");
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[monoio::test]
+    async fn tcp_info_fields() {
+        use std::os::unix::io::AsRawFd;
+        let (mut conn, mut peer) = tcp_pair().await;
+        let (res, _) = conn.write_all(b"ping").await;
+        res.unwrap();
+        let (res, _) = peer.read(vec![0; 4]).await;
+        res.unwrap();
+        let info = read_tcp_info(conn.as_raw_fd()).unwrap();
+        let line = info.to_string();
+        for field in ["rtt=", "rttvar=", "min_rtt=", "retrans="] {
+            assert!(line.contains(field), "{line}");
+        }
+        // The probe keeps the socket readable after the stream is dropped.
+        let probe = TcpInfoProbe::new(&conn, "test".to_string()).unwrap();
+        drop(conn);
+        assert!(read_tcp_info(probe.fd).is_ok());
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn conn_limit_under_load() {
+        let limit = ConnLimit::new(4);
+        let peak = Rc::new(Cell::new(0));
+        let dropped = Rc::new(Cell::new(0));
+        let tasks: Vec<_> = (0..64)
+            .map(|i| {
+                let (limit, peak, dropped) = (limit.clone(), peak.clone(), dropped.clone());
+                monoio::spawn(async move {
+                    monoio::time::sleep(Duration::from_millis(i % 8)).await;
+                    let Some(_guard) = limit.try_acquire() else {
+                        dropped.set(dropped.get() + 1);
+                        return;
+                    };
+                    peak.set(peak.get().max(limit.current()));
+                    monoio::time::sleep(Duration::from_millis(5)).await;
+                })
+            })
+            .collect();
+        for t in tasks {
+            t.await;
+        }
+        assert_eq!(peak.get(), 4);
+        assert!(dropped.get() > 0);
+        assert_eq!(limit.current(), 0);
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn stalled_read_timeout() {
+        let (conn, _peer) = tcp_pair().await;
+        let op_timeout = OpTimeout::new(Some(Duration::from_millis(100)), None);
+        let mut conn = op_timeout.wrap(conn);
+        let begin = std::time::Instant::now();
+        let res = op_timeout.guard(conn.read(vec![0; 1024])).await;
+        assert!(res.is_none());
+        assert!(begin.elapsed() < Duration::from_secs(2));
+
+        // Only writes are limited, reading is allowed to wait.
+        let op_timeout = OpTimeout::new(None, Some(Duration::from_millis(100)));
+        let (conn, mut peer) = tcp_pair().await;
+        let mut conn = op_timeout.wrap(conn);
+        let read = op_timeout.guard(conn.read(vec![0; 1024]));
+        let write = async {
+            monoio::time::sleep(Duration::from_millis(300)).await;
+            peer.write_all(b"hello").await
+        };
+        let (res, _) = monoio::join!(read, write);
+        assert_eq!(res.unwrap().0.unwrap(), 5);
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn byte_limit_exceeded() {
+        let (conn, mut peer) = tcp_pair().await;
+        let limit = ByteLimit::new(10, ByteLimitMode::Total);
+        let mut conn = limit.wrap(conn);
+        let relay = async {
+            let (res, _) = conn.write_all(b"12345").await;
+            res?;
+            loop {
+                let (res, _) = conn.read(vec![0; 1024]).await;
+                if res? == 0 {
+                    return std::io::Result::Ok(());
+                }
+            }
+        };
+        let push = async {
+            // 5 bytes written and 5 bytes read, exactly the limit
+            let (res, _) = peer.write_all(b"12345").await;
+            res.unwrap();
+            monoio::time::sleep(Duration::from_millis(50)).await;
+            let (res, _) = peer.write_all(b"6").await;
+            res.unwrap();
+        };
+        let (res, _) = monoio::join!(limit.guard(relay), push);
+        assert!(res.is_none());
+
+        // Within the limit of each direction.
+        let (conn, mut peer) = tcp_pair().await;
+        let limit = ByteLimit::new(5, ByteLimitMode::Each);
+        let mut conn = limit.wrap(conn);
+        let relay = async {
+            let (res, _) = conn.write_all(b"12345").await;
+            res?;
+            let (res, _) = monoio::io::AsyncReadRentExt::read_exact(&mut conn, vec![0; 5]).await;
+            res
+        };
+        let (res, _) = monoio::join!(limit.guard(relay), peer.write_all(b"12345"));
+        assert_eq!(res.unwrap().unwrap(), 5);
+
+        // added to the metrics once closed, other tests may add too
+        let relayed = || {
+            crate::metrics::METRICS
+                .bytes_relayed
+                .load(std::sync::atomic::Ordering::Relaxed)
+        };
+        let before = relayed();
+        drop((conn, limit));
+        assert!(relayed() - before >= 10);
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn close_jitter_in_range() {
+        assert!(parse_jitter_range("50-10").is_err());
+        assert_eq!(
+            parse_jitter_range("80").unwrap(),
+            JitterRange { min: 0, max: 80 }
+        );
+        let jitter = parse_jitter_range("30-80").unwrap();
+        let payload: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        for _ in 0..5 {
+            let (conn, mut peer) = tcp_pair().await;
+            let mut conn = JitterStream::new(conn, Some(jitter));
+            let sender = async {
+                let (res, _) = conn.write_all(payload.clone()).await;
+                res.unwrap();
+                let begin = Instant::now();
+                conn.shutdown().await.unwrap();
+                begin.elapsed()
+            };
+            let receiver = async {
+                let mut received = Vec::new();
+                loop {
+                    let (res, buf) = peer.read(vec![0; 4096]).await;
+                    match res.unwrap() {
+                        0 => return received,
+                        n => received.extend_from_slice(&buf[..n]),
+                    }
+                }
+            };
+            let (delay, received) = monoio::join!(sender, receiver);
+            assert!(delay >= Duration::from_millis(30), "{delay:?}");
+            assert!(delay < Duration::from_millis(80 + 50), "{delay:?}");
+            assert!(received == payload);
+        }
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn stalled_write_timeout() {
+        let (conn, _peer) = tcp_pair().await;
+        let op_timeout = OpTimeout::new(None, Some(Duration::from_millis(100)));
+        let mut conn = op_timeout.wrap(conn);
+        // The peer never reads, so writing blocks once socket buffers are full.
+        let write_forever = async {
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let (res, b) = conn.write_all(buf).await;
+                res?;
+                buf = b;
+            }
+        };
+        let begin = std::time::Instant::now();
+        let res: Option<std::io::Result<()>> = op_timeout.guard(write_forever).await;
+        assert!(res.is_none());
+        assert!(begin.elapsed() < Duration::from_secs(5));
+    }
+}